@@ -0,0 +1,156 @@
+#ifndef BAM_UTIL_H
+#define BAM_UTIL_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Result of the C API functions.
+ */
+typedef enum BamUtilStatus {
+  BAM_UTIL_STATUS_OK = 0,
+  /**
+   * No more records to read.
+   */
+  BAM_UTIL_STATUS_END = 1,
+  /**
+   * A NULL pointer, a string which is not UTF-8 or an invalid value.
+   */
+  BAM_UTIL_STATUS_INVALID_ARGUMENT = 2,
+  BAM_UTIL_STATUS_IO_ERROR = 3,
+  /**
+   * An invalid cigar expression or cigar string.
+   */
+  BAM_UTIL_STATUS_PARSE_ERROR = 4,
+  /**
+   * An unexpected internal error, the library should not be used anymore.
+   */
+  BAM_UTIL_STATUS_PANIC = 5,
+} BamUtilStatus;
+
+/**
+ * A compiled cigar expression, see `bam::cigar::Expression`.
+ */
+typedef struct BamUtilExpression BamUtilExpression;
+
+/**
+ * An open BAM/SAM/CRAM file and its current record.
+ */
+typedef struct BamUtilReader BamUtilReader;
+
+/**
+ * Fields of the current record of a reader.
+ *
+ * `qname` is owned by the reader and valid until the next call of `bam_util_reader_next`.
+ */
+typedef struct BamUtilRecordInfo {
+  int32_t tid;
+  /**
+   * 0-based leftmost position.
+   */
+  int64_t pos;
+  /**
+   * 0-based position after the last aligned base.
+   */
+  int64_t end_pos;
+  uint8_t mapq;
+  uint16_t flags;
+  const char *qname;
+} BamUtilRecordInfo;
+
+/**
+ * Message of the last error of the calling thread, NULL if none.
+ *
+ * The string is owned by the library and valid until the next failing call on the same thread.
+ */
+const char *bam_util_last_error(void);
+
+/**
+ * Open a BAM/SAM/CRAM file, `reference` is required for CRAM and may be NULL otherwise.
+ *
+ * # Safety
+ *
+ * `path` and `reference` must be NULL or valid C strings, `out` must be a valid pointer.
+ */
+BamUtilStatus bam_util_reader_open(const char *path,
+                                   const char *reference,
+                                   int n_threads,
+                                   BamUtilReader **out);
+
+/**
+ * Read the next record, `BAM_UTIL_STATUS_END` at the end of the file.
+ *
+ * # Safety
+ *
+ * `reader` must be a handle returned by `bam_util_reader_open`.
+ */
+BamUtilStatus bam_util_reader_next(BamUtilReader *reader);
+
+/**
+ * Fields of the current record, after a successful `bam_util_reader_next`.
+ *
+ * # Safety
+ *
+ * `reader` must be a handle returned by `bam_util_reader_open`, `out` must be a valid pointer.
+ */
+BamUtilStatus bam_util_reader_record(const BamUtilReader *reader, BamUtilRecordInfo *out);
+
+/**
+ * Close a reader, NULL is ignored.
+ *
+ * # Safety
+ *
+ * `reader` must be NULL or a handle returned by `bam_util_reader_open`, not closed yet.
+ */
+void bam_util_reader_close(BamUtilReader *reader);
+
+/**
+ * Compile a cigar expression, e.g. `sum(S) > 20 && ref_len >= 50`.
+ *
+ * # Safety
+ *
+ * `expression` must be a valid C string, `out` must be a valid pointer.
+ */
+BamUtilStatus bam_util_expression_compile(const char *expression, BamUtilExpression **out);
+
+/**
+ * Evaluate an expression on a cigar string (e.g. `10S90M`) aligned at `pos`, `result` is set to 1
+ * if the expression holds, 0 otherwise.
+ *
+ * # Safety
+ *
+ * `expression` must be a handle returned by `bam_util_expression_compile`, `cigar` a valid C
+ * string and `result` a valid pointer.
+ */
+BamUtilStatus bam_util_expression_eval_cigar(const BamUtilExpression *expression,
+                                             const char *cigar,
+                                             int64_t pos,
+                                             int *result);
+
+/**
+ * Evaluate an expression on the current record of a reader, `result` is set to 1 if the
+ * expression holds, 0 otherwise.
+ *
+ * # Safety
+ *
+ * `expression` and `reader` must be handles returned by `bam_util_expression_compile` and
+ * `bam_util_reader_open`, `result` a valid pointer.
+ */
+BamUtilStatus bam_util_expression_eval_record(const BamUtilExpression *expression,
+                                              const BamUtilReader *reader,
+                                              int *result);
+
+/**
+ * Release an expression, NULL is ignored.
+ *
+ * # Safety
+ *
+ * `expression` must be NULL or a handle returned by `bam_util_expression_compile`, not freed yet.
+ */
+void bam_util_expression_free(BamUtilExpression *expression);
+
+#endif /* BAM_UTIL_H */
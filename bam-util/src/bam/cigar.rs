@@ -1,11 +1,9 @@
 //! `Cigar` provides several functions and boolean operations for filtering bam file.
-use exitcode;
-use log::*;
-use regex::Regex;
-use rust_htslib::bam::record::CigarStringView;
+use regex::{Captures, Regex};
+use rust_htslib::bam::record::{Cigar, CigarString, CigarStringView};
 
 fn syntax_rule() -> String {
-  let func: &str = r"(?P<func>each|sum|sum_ratio)\((?P<variant_type>[MIDNSHP])\)";
+  let func: &str = r"(?:(?P<func>each|sum|sum_ratio|max|min|count|any|leading|trailing)\((?P<variant_type>[MIDNSHP=X])\)|(?P<len_func>query_len|ref_len))";
   let operation: &str = r"(?P<operator>>|<|>=|<=)";
   let bool_operation: &str = r"(?P<bool_operator>&&|\|\|)";
   let number: &str = r"(?P<number>[1-9]\d*|0)"; // Only support integer
@@ -19,6 +17,8 @@ fn syntax_rule() -> String {
 /// # Examples
 ///
 /// ```
+/// use bam_util::bam::cigar::remove_whitespace;
+///
 /// let removed = remove_whitespace("  each(S) && all(M)");
 /// assert_eq!("each(S)&&all(M)", removed);
 /// ```
@@ -26,143 +26,202 @@ pub fn remove_whitespace(s: &str) -> String {
   s.chars().filter(|c| !c.is_whitespace()).collect()
 }
 
-pub fn check_expr(expression: &str) -> bool {
-  let expression = &remove_whitespace(expression)[..];
+/// A single condition of an expression. e.g. sum(S) > 100 / each(S) > 20 / sum_ratio(S) > 50
+#[derive(Debug, Clone, PartialEq)]
+struct Condition {
+  func: String,
+  // Unused by query_len and ref_len.
+  variant_type: char,
+  operator: String,
+  number: u32,
+}
+
+impl Condition {
+  /// None if the number does not fit in a u32.
+  fn from_captures(caps: &Captures) -> Option<Self> {
+    let func = match caps.name("len_func") {
+      Some(len_func) => len_func.as_str(),
+      None => caps.name("func")?.as_str(),
+    };
+    let variant_type = caps
+      .name("variant_type")
+      .map_or(Some(' '), |variant_type| {
+        variant_type.as_str().chars().next()
+      })?;
+    // Only support integer
+    let number = caps.name("number")?.as_str().parse::<u32>().ok()?;
 
-  lazy_static! {
-    static ref CHECK_EXPR_REGEX: Regex = Regex::new(&syntax_rule()[..]).unwrap();
+    Some(Condition {
+      func: String::from(func),
+      variant_type,
+      operator: String::from(caps.name("operator")?.as_str()),
+      number,
+    })
   }
 
-  match CHECK_EXPR_REGEX.captures(expression) {
-    Some(caps) => match caps.name("bool_operator") {
-      Some(_bool_operator) => {
-        let bool_operator = _bool_operator.as_str();
-        let rest = caps.name("rest").unwrap().as_str();
+  fn eval(&self, cigar: &CigarStringView) -> bool {
+    let func = &self.func[..];
+    let variant_type = self.variant_type;
+    let operation = &self.operator[..];
+    let number = self.number;
 
-        match bool_operator {
-          "&&" => return check_expr(rest),
-          "||" => return check_expr(rest),
-          _ => {
-            eprintln!("Not valid expression: {:?}", rest);
-            std::process::exit(exitcode::DATAERR);
-          }
-        }
+    if func == "query_len" {
+      return compare(query_len(cigar), operation, number);
+    } else if func == "ref_len" {
+      return compare(ref_len(cigar), operation, number);
+    } else if func == "each" {
+      let value = len_vector(cigar, variant_type);
+      // Not vacuously true: a cigar without OP does not satisfy `each(OP)`.
+      if value.is_empty() {
+        return false;
       }
-      None => {
-        if caps.name("rest").unwrap().as_str() != "" {
-          eprintln!("Not valid expression: {:?}", expression);
-          std::process::exit(1);
-        } else {
-          return true;
-        }
+      match operation {
+        "<" => return value.iter().all(|&x| x < number),
+        ">" => return value.iter().all(|&x| x > number),
+        ">=" => return value.iter().all(|&x| x >= number),
+        "<=" => return value.iter().all(|&x| x <= number),
+        _ => return false,
       }
-    },
-    None => {
-      eprintln!("Not valid expression: {:?}", expression);
-      std::process::exit(exitcode::DATAERR);
+    } else if func == "any" {
+      let value = len_vector(cigar, variant_type);
+      return value.iter().any(|&x| compare(x, operation, number));
+    } else if func == "sum" {
+      let value = dispatch(cigar, variant_type);
+      // debug!("Operation {}, Sum: {}, Number: {}", operation, value, number);
+
+      return compare(value, operation, number);
+    } else if func == "max" {
+      let value = len_vector(cigar, variant_type);
+      return compare(value.into_iter().max().unwrap_or(0), operation, number);
+    } else if func == "min" {
+      let value = len_vector(cigar, variant_type);
+      return compare(value.into_iter().min().unwrap_or(0), operation, number);
+    } else if func == "count" {
+      let value = len_vector(cigar, variant_type);
+      return compare(value.len() as u32, operation, number);
+    } else if func == "leading" {
+      let value = leading(cigar, variant_type);
+      return compare(value, operation, number);
+    } else if func == "trailing" {
+      let value = trailing(cigar, variant_type);
+      return compare(value, operation, number);
+    } else if func == "sum_ratio" {
+      let value = sum_ratio(
+        cigar,
+        variant_type,
+        cigar.iter().map(|cigar| cigar.len()).sum(),
+      );
+
+      // debug!("Operation: {}, Sum Ratio: {}, Number: {}", operation, value, number);
+
+      match operation {
+        "<" => return value < (number as f64 / 100.0),
+        ">" => return value > (number as f64 / 100.0),
+        ">=" => return (value - (number as f64 / 100.0)) >= 0.0,
+        "<=" => return (value - (number as f64 / 100.0)) <= 0.0,
+        _ => return true,
+      }
+    } else {
+      return false;
     }
   }
 }
 
-/// Exec expression for filtering bam file with cigar field.
-pub fn exec(cigar: &CigarStringView, expression: &str) -> bool {
-  let expression = &remove_whitespace(expression)[..];
-  lazy_static! {
-    static ref EXEC_REGEX: Regex = Regex::new(&syntax_rule()[..]).unwrap();
-  }
+/// A compiled expression, parsed once and evaluated on many records.
+///
+/// Conditions are grouped from the right: `a && b || c` is `a && (b || c)`.
+///
+/// Supported functions:
+///
+/// - `each(OP)`: every OP satisfies the condition, false when the cigar has no OP at all.
+/// - `any(OP)`: at least one OP satisfies the condition.
+/// - `sum(OP)` / `sum_ratio(OP)`: total length of OP, or its percentage of the whole cigar.
+/// - `max(OP)` / `min(OP)`: longest / shortest OP, 0 when the cigar has no OP.
+/// - `count(OP)`: number of OP operations.
+/// - `leading(OP)` / `trailing(OP)`: length of OP at the start / end of the alignment, 0 if absent.
+/// - `query_len` / `ref_len`: number of bases consumed on the read / the reference.
+///
+/// # Examples
+///
+/// ```
+/// use bam_util::bam::cigar::Expression;
+///
+/// let expression = Expression::compile("each(S) > 100 || any(I) > 5").unwrap();
+/// assert!(Expression::compile("each(Q) > 100").is_none());
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+  conditions: Vec<Condition>,
+  bool_operators: Vec<String>,
+}
+
+impl Expression {
+  /// Parse an expression, None if it is not valid, e.g. a number which does not fit in a u32.
+  pub fn compile(expression: &str) -> Option<Expression> {
+    lazy_static! {
+      static ref COMPILE_REGEX: Regex = Regex::new(&syntax_rule()[..]).unwrap();
+    }
+
+    let expression = remove_whitespace(expression);
+    let mut rest = &expression[..];
+    let mut conditions = vec![];
+    let mut bool_operators = vec![];
 
-  match EXEC_REGEX.captures(expression) {
-    Some(caps) => {
-      let first = caps.name("first").unwrap().as_str();
+    loop {
+      let caps = COMPILE_REGEX.captures(rest)?;
+      if caps.get(0).unwrap().start() != 0 {
+        return None;
+      }
+
+      conditions.push(Condition::from_captures(&caps)?);
+      let remaining = caps.name("rest").unwrap().as_str();
       match caps.name("bool_operator") {
-        Some(_bool_operator) => {
-          let bool_operator = _bool_operator.as_str();
-          let rest = caps.name("rest").unwrap().as_str();
-
-          debug!(
-            "First {}, Bool Operator: {}, Rest: {}",
-            first, bool_operator, rest
-          );
-          match bool_operator {
-            "&&" => return exec_single(cigar, first) && exec(cigar, rest),
-            "||" => return exec_single(cigar, first) || exec(cigar, rest),
-            _ => return false,
-          }
+        Some(bool_operator) => {
+          bool_operators.push(String::from(bool_operator.as_str()));
+          rest = remaining;
         }
-        None => exec_single(cigar, first),
+        None if remaining.is_empty() => break,
+        None => return None,
       }
     }
-    None => {
-      eprintln!("Not valid expression: {:?}", expression);
-      std::process::exit(exitcode::DATAERR);
-    }
-  }
-}
 
-/// Exec a single expression. e.g. sum(S) > 100 / each(S) > 20 / sum_ratio(S) > 0.5
-pub fn exec_single(cigar: &CigarStringView, expression: &str) -> bool {
-  let expression = &remove_whitespace(expression)[..];
-  lazy_static! {
-    static ref EXEC_SINGLE_REGEX: Regex = Regex::new(&syntax_rule()[..]).unwrap();
+    Some(Expression {
+      conditions,
+      bool_operators,
+    })
   }
 
-  match EXEC_SINGLE_REGEX.captures(expression) {
-    Some(caps) => {
-      let func = caps.name("func").unwrap().as_str();
-      let variant_type = caps.name("variant_type").unwrap().as_str();
-      let operation = caps.name("operator").unwrap().as_str();
-      // Only support integer
-      let number_str = caps.name("number").unwrap().as_str();
-      let number = number_str.parse::<u32>().unwrap();
+  /// Exec the expression for a cigar.
+  pub fn eval(&self, cigar: &CigarStringView) -> bool {
+    self.eval_from(cigar, 0)
+  }
 
-      if func == "each" {
-        let value = len_vector(cigar, variant_type.chars().next().unwrap());
-        match operation {
-          "<" => return value.iter().all(|&x| x < number),
-          ">" => return value.iter().all(|&x| x > number),
-          ">=" => return value.iter().all(|&x| x >= number),
-          "<=" => return value.iter().all(|&x| x <= number),
-          _ => return false,
-        }
-      } else if func == "sum" {
-        let value = dispatch(cigar, variant_type.chars().next().unwrap());
-        // debug!("Operation {}, Sum: {}, Number: {}", operation, value, number);
-
-        match operation {
-          "<" => return value < number,
-          ">" => return value > number,
-          ">=" => return value >= number,
-          "<=" => return value <= number,
-          _ => return false,
-        }
-      } else if func == "sum_ratio" {
-        let value = sum_ratio(
-          cigar,
-          variant_type.chars().next().unwrap(),
-          cigar.iter().map(|cigar| cigar.len()).sum(),
-        );
-
-        // debug!("Operation: {}, Sum Ratio: {}, Number: {}", operation, value, number);
-
-        match operation {
-          "<" => return value < (number as f64 / 100.0),
-          ">" => return value > (number as f64 / 100.0),
-          ">=" => return (value - (number as f64 / 100.0)) >= 0.0,
-          "<=" => return (value - (number as f64 / 100.0)) <= 0.0,
-          _ => return true,
-        }
-      } else {
-        return false;
-      }
-    }
-    None => {
-      eprintln!("Not valid expression: {:?}", expression);
-      std::process::exit(exitcode::DATAERR);
+  fn eval_from(&self, cigar: &CigarStringView, idx: usize) -> bool {
+    let first = self.conditions[idx].eval(cigar);
+    match self
+      .bool_operators
+      .get(idx)
+      .map(|bool_operator| &bool_operator[..])
+    {
+      Some("&&") => first && self.eval_from(cigar, idx + 1),
+      Some("||") => first || self.eval_from(cigar, idx + 1),
+      _ => first,
     }
   }
 }
 
-fn dispatch(cigar: &CigarStringView, variant_type: char) -> u32 {
+fn compare(value: u32, operation: &str, number: u32) -> bool {
+  match operation {
+    "<" => value < number,
+    ">" => value > number,
+    ">=" => value >= number,
+    "<=" => value <= number,
+    _ => false,
+  }
+}
+
+/// Total length of an operation, clips are only counted at the ends of the alignment.
+pub fn dispatch(cigar: &CigarStringView, variant_type: char) -> u32 {
   match variant_type {
     'M' => sum_by(cigar, 'M'),
     'I' => sum_by(cigar, 'I'),
@@ -177,8 +236,8 @@ fn dispatch(cigar: &CigarStringView, variant_type: char) -> u32 {
   }
 }
 
-/// Function
-fn sum_by(cigar: &CigarStringView, variant_type: char) -> u32 {
+/// Total length of an operation.
+pub fn sum_by(cigar: &CigarStringView, variant_type: char) -> u32 {
   return cigar
     .iter()
     .filter(|cigar| cigar.char() == variant_type)
@@ -190,10 +249,221 @@ fn sum_ratio(cigar: &CigarStringView, variant_type: char, seq_len: u32) -> f64 {
   f64::from(dispatch(cigar, variant_type)) / f64::from(seq_len)
 }
 
-fn len_vector(cigar: &CigarStringView, variant_type: char) -> Vec<u32> {
+/// Lengths of each occurrence of an operation.
+pub fn len_vector(cigar: &CigarStringView, variant_type: char) -> Vec<u32> {
   return cigar
     .iter()
     .filter(|cigar| cigar.char() == variant_type)
     .map(|cigar| cigar.len())
     .collect();
 }
+
+/// Length of the `variant_type` operation at the start of the alignment, hard clips are skipped
+/// unless `variant_type` is `H` itself.
+fn leading(cigar: &CigarStringView, variant_type: char) -> u32 {
+  match variant_type {
+    'S' => cigar.leading_softclips() as u32,
+    'H' => cigar.leading_hardclips() as u32,
+    _ => cigar
+      .iter()
+      .find(|cigar| cigar.char() != 'H')
+      .filter(|cigar| cigar.char() == variant_type)
+      .map_or(0, |cigar| cigar.len()),
+  }
+}
+
+/// Length of the `variant_type` operation at the end of the alignment, hard clips are skipped
+/// unless `variant_type` is `H` itself.
+fn trailing(cigar: &CigarStringView, variant_type: char) -> u32 {
+  match variant_type {
+    'S' => cigar.trailing_softclips() as u32,
+    'H' => cigar.trailing_hardclips() as u32,
+    _ => cigar
+      .iter()
+      .rev()
+      .find(|cigar| cigar.char() != 'H')
+      .filter(|cigar| cigar.char() == variant_type)
+      .map_or(0, |cigar| cigar.len()),
+  }
+}
+
+/// Decode the raw cigar of a record (`Record::raw_cigar`), as `Record::cigar` does. Lets the cigars
+/// be decoded on another thread than the records, which are not `Send`. An error for an operation
+/// code greater than 8.
+pub fn decode_raw(raw: &[u32], pos: i64) -> Result<CigarStringView, String> {
+  let ops = raw
+    .iter()
+    .map(|&op| {
+      let len = op >> 4;
+      match op & 0xf {
+        0 => Ok(Cigar::Match(len)),
+        1 => Ok(Cigar::Ins(len)),
+        2 => Ok(Cigar::Del(len)),
+        3 => Ok(Cigar::RefSkip(len)),
+        4 => Ok(Cigar::SoftClip(len)),
+        5 => Ok(Cigar::HardClip(len)),
+        6 => Ok(Cigar::Pad(len)),
+        7 => Ok(Cigar::Equal(len)),
+        8 => Ok(Cigar::Diff(len)),
+        code => Err(format!("Unexpected cigar operation {}", code)),
+      }
+    })
+    .collect::<Result<Vec<_>, _>>()?;
+
+  Ok(CigarString(ops).into_view(pos))
+}
+
+/// Reference position the alignment would start at without its leading clips (S and H).
+pub fn unclipped_start(cigar: &CigarStringView) -> i64 {
+  let clipped: u32 = cigar
+    .iter()
+    .take_while(|cigar| matches!(cigar.char(), 'S' | 'H'))
+    .map(|cigar| cigar.len())
+    .sum();
+  cigar.pos() - clipped as i64
+}
+
+/// Reference position (exclusive) the alignment would end at without its trailing clips (S and H).
+pub fn unclipped_end(cigar: &CigarStringView) -> i64 {
+  let clipped: u32 = cigar
+    .iter()
+    .rev()
+    .take_while(|cigar| matches!(cigar.char(), 'S' | 'H'))
+    .map(|cigar| cigar.len())
+    .sum();
+  cigar.end_pos() + clipped as i64
+}
+
+/// Number of read bases consumed by the cigar (M/I/S/=/X).
+fn query_len(cigar: &CigarStringView) -> u32 {
+  return cigar
+    .iter()
+    .filter(|cigar| matches!(cigar.char(), 'M' | 'I' | 'S' | '=' | 'X'))
+    .map(|cigar| cigar.len())
+    .sum();
+}
+
+/// Number of reference bases consumed by the cigar (M/D/N/=/X).
+fn ref_len(cigar: &CigarStringView) -> u32 {
+  return cigar
+    .iter()
+    .filter(|cigar| matches!(cigar.char(), 'M' | 'D' | 'N' | '=' | 'X'))
+    .map(|cigar| cigar.len())
+    .sum();
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Cigar view at position 100, e.g. `view("5H10S80M2I8M")`.
+  fn view(cigar: &str) -> CigarStringView {
+    let mut ops = vec![];
+    let mut len = 0;
+    for c in cigar.chars() {
+      if let Some(digit) = c.to_digit(10) {
+        len = len * 10 + digit;
+        continue;
+      }
+      ops.push(match c {
+        'M' => Cigar::Match(len),
+        'I' => Cigar::Ins(len),
+        'D' => Cigar::Del(len),
+        'N' => Cigar::RefSkip(len),
+        'S' => Cigar::SoftClip(len),
+        'H' => Cigar::HardClip(len),
+        'P' => Cigar::Pad(len),
+        '=' => Cigar::Equal(len),
+        'X' => Cigar::Diff(len),
+        _ => panic!("Unknown operation {}", c),
+      });
+      len = 0;
+    }
+    CigarString(ops).into_view(100)
+  }
+
+  fn eval(cigar: &str, expression: &str) -> bool {
+    Expression::compile(expression).unwrap().eval(&view(cigar))
+  }
+
+  #[test]
+  fn each() {
+    assert!(eval("10S80M10S", "each(S) >= 10"));
+    assert!(!eval("10S80M5S", "each(S) >= 10"));
+    // No soft clip at all.
+    assert!(!eval("100M", "each(S) < 10"));
+    assert!(!eval("100M", "each(S) > 10"));
+  }
+
+  #[test]
+  fn max_min_count() {
+    assert!(eval("10M2I20M5I10M", "max(I) >= 5"));
+    assert!(!eval("10M2I20M5I10M", "max(I) > 5"));
+    assert!(eval("10M2I20M5I10M", "min(I) <= 2"));
+    assert!(eval("10M2I20M5I10M", "count(I) >= 2"));
+    assert!(!eval("10M2I20M5I10M", "count(I) > 2"));
+    assert!(eval("100M", "max(I) <= 0"));
+    assert!(eval("100M", "min(I) <= 0"));
+    assert!(eval("100M", "count(I) <= 0"));
+  }
+
+  #[test]
+  fn any() {
+    assert!(eval("10M2I20M5I10M", "any(I) > 4"));
+    assert!(!eval("10M2I20M5I10M", "any(I) > 5"));
+    assert!(!eval("100M", "any(I) >= 0"));
+  }
+
+  #[test]
+  fn leading_trailing() {
+    assert!(eval("5H10S80M3S", "leading(S) >= 10"));
+    assert!(eval("5H10S80M3S", "trailing(S) <= 3"));
+    assert!(eval("5H10S80M3S", "leading(H) >= 5"));
+    assert!(eval("5H10S80M3S", "trailing(H) <= 0"));
+    // Hard clips are skipped for the other operations.
+    assert!(eval("5H2I80M", "leading(I) >= 2"));
+    assert!(eval("80M2D5H", "trailing(D) >= 2"));
+    assert!(eval("10S80M", "leading(M) <= 0"));
+  }
+
+  #[test]
+  fn lengths() {
+    let cigar = view("5H10S50M2I3D20N30M4S");
+    assert_eq!(query_len(&cigar), 10 + 50 + 2 + 30 + 4);
+    assert_eq!(ref_len(&cigar), 50 + 3 + 20 + 30);
+    assert!(eval(
+      "5H10S50M2I3D20N30M4S",
+      "query_len >= 96 && ref_len <= 103"
+    ));
+    assert!(!eval("5H10S50M2I3D20N30M4S", "query_len > 96"));
+  }
+
+  #[test]
+  fn sum_and_unclipped() {
+    let cigar = view("5H10S80M3S");
+    assert_eq!(dispatch(&cigar, 'S'), 13);
+    assert_eq!(dispatch(&cigar, 'H'), 5);
+    assert_eq!(unclipped_start(&cigar), 85);
+    assert_eq!(unclipped_end(&cigar), 183);
+  }
+
+  #[test]
+  fn decode() {
+    let raw = [5 << 4 | 5, 10 << 4 | 4, 80 << 4, 2 << 4 | 1, 8 << 4 | 8];
+    assert_eq!(decode_raw(&raw, 100), Ok(view("5H10S80M2I8X")));
+    assert!(decode_raw(&[10 << 4 | 9], 100).is_err());
+  }
+
+  #[test]
+  fn compile() {
+    assert!(Expression::compile("each(S) > 10 && any(I) > 2").is_some());
+    assert!(Expression::compile("xeach(S) > 10").is_none());
+    assert!(Expression::compile("each(Q) > 10").is_none());
+    assert!(Expression::compile("each(S) > 10 &&").is_none());
+    assert!(Expression::compile("each(S) > 10 foo").is_none());
+    // Does not fit in a u32.
+    assert!(Expression::compile("sum(S) > 4294967295").is_some());
+    assert!(Expression::compile("sum(S) > 99999999999").is_none());
+    assert!(Expression::compile("each(S) > 10 || sum(S) > 4294967296").is_none());
+  }
+}
@@ -0,0 +1,311 @@
+//! `Clip` rewrites the ends of alignments: trimming bases into hard clips while keeping the cigar and the position consistent.
+use rust_htslib::bam::record::{Cigar, CigarString, Record};
+
+/// The same operation with another length.
+fn with_len(cigar: &Cigar, len: u32) -> Cigar {
+  match cigar {
+    Cigar::Match(_) => Cigar::Match(len),
+    Cigar::Ins(_) => Cigar::Ins(len),
+    Cigar::Del(_) => Cigar::Del(len),
+    Cigar::RefSkip(_) => Cigar::RefSkip(len),
+    Cigar::SoftClip(_) => Cigar::SoftClip(len),
+    Cigar::HardClip(_) => Cigar::HardClip(len),
+    Cigar::Pad(_) => Cigar::Pad(len),
+    Cigar::Equal(_) => Cigar::Equal(len),
+    Cigar::Diff(_) => Cigar::Diff(len),
+  }
+}
+
+/// Hard clip the first `n` read bases of an alignment starting at `pos`.
+///
+/// Returns the new cigar and position. Deletions and skips left at the start are dropped, and an
+/// insertion left at the start becomes a soft clip.
+pub fn trim_left(cigar: &[Cigar], pos: i64, n: u32) -> (Vec<Cigar>, i64) {
+  let mut hard = 0;
+  let mut remaining = n;
+  let mut pos = pos;
+  let mut ops: Vec<Cigar> = vec![];
+
+  for op in cigar {
+    let len = op.len();
+    match op.char() {
+      'H' if ops.is_empty() => hard += len,
+      'M' | '=' | 'X' if remaining > 0 => {
+        let take = len.min(remaining);
+        hard += take;
+        remaining -= take;
+        pos += take as i64;
+        if take < len {
+          ops.push(with_len(op, len - take));
+        }
+      }
+      'I' | 'S' if remaining > 0 => {
+        let take = len.min(remaining);
+        hard += take;
+        remaining -= take;
+        if take < len {
+          ops.push(with_len(op, len - take));
+        }
+      }
+      'D' | 'N' if ops.is_empty() => pos += len as i64,
+      'P' if ops.is_empty() => {}
+      _ => ops.push(*op),
+    }
+  }
+
+  if let Some(first) = ops.first_mut() {
+    if first.char() == 'I' {
+      *first = Cigar::SoftClip(first.len());
+    }
+  }
+
+  let mut trimmed = vec![];
+  if hard > 0 {
+    trimmed.push(Cigar::HardClip(hard));
+  }
+  trimmed.extend(ops);
+
+  (trimmed, pos)
+}
+
+/// Hard clip the last `n` read bases of an alignment.
+pub fn trim_right(cigar: &[Cigar], n: u32) -> Vec<Cigar> {
+  let reversed: Vec<Cigar> = cigar.iter().rev().cloned().collect();
+  let (trimmed, _) = trim_left(&reversed, 0, n);
+  trimmed.into_iter().rev().collect()
+}
+
+/// Number of read bases (soft clips included) aligned before the reference position `target`.
+pub fn bases_before(cigar: &[Cigar], pos: i64, target: i64) -> u32 {
+  let mut bases = 0;
+  let mut pos = pos;
+
+  for op in cigar {
+    if pos >= target {
+      break;
+    }
+
+    let len = op.len();
+    match op.char() {
+      'M' | '=' | 'X' => {
+        let take = (len as i64).min(target - pos);
+        bases += take as u32;
+        pos += take;
+      }
+      'D' | 'N' => pos += len as i64,
+      'I' | 'S' => bases += len,
+      _ => {}
+    }
+  }
+
+  bases
+}
+
+/// Number of read bases (soft clips included) aligned at or after the reference position
+/// `target`, for an alignment ending at `end` (exclusive).
+pub fn bases_after(cigar: &[Cigar], end: i64, target: i64) -> u32 {
+  let mut bases = 0;
+  let mut end = end;
+
+  for op in cigar.iter().rev() {
+    if end <= target {
+      break;
+    }
+
+    let len = op.len();
+    match op.char() {
+      'M' | '=' | 'X' => {
+        let take = (len as i64).min(end - target);
+        bases += take as u32;
+        end -= take;
+      }
+      'D' | 'N' => end -= len as i64,
+      'I' | 'S' => bases += len,
+      _ => {}
+    }
+  }
+
+  bases
+}
+
+/// BAI bin of an alignment covering [beg, end), as computed in the SAM specification.
+pub fn reg2bin(beg: i64, end: i64) -> u16 {
+  let end = end - 1;
+  let bin = if beg >> 14 == end >> 14 {
+    ((1 << 15) - 1) / 7 + (beg >> 14)
+  } else if beg >> 17 == end >> 17 {
+    ((1 << 12) - 1) / 7 + (beg >> 17)
+  } else if beg >> 20 == end >> 20 {
+    ((1 << 9) - 1) / 7 + (beg >> 20)
+  } else if beg >> 23 == end >> 23 {
+    ((1 << 6) - 1) / 7 + (beg >> 23)
+  } else if beg >> 26 == end >> 26 {
+    ((1 << 3) - 1) / 7 + (beg >> 26)
+  } else {
+    0
+  };
+
+  bin as u16
+}
+
+/// Number of read bases consumed by a cigar (M/I/S/=/X).
+fn query_len(cigar: &[Cigar]) -> usize {
+  cigar
+    .iter()
+    .filter(|op| matches!(op.char(), 'M' | 'I' | 'S' | '=' | 'X'))
+    .map(|op| op.len() as usize)
+    .sum()
+}
+
+/// Hard clip `left` and `right` read bases (in reference orientation) of a mapped record,
+/// updating the cigar, position, bin, sequence and qualities. The MD and NM tags are dropped as
+/// they no longer describe the alignment.
+///
+/// The record is left unchanged and false is returned if no aligned base would be left, or if the
+/// cigar does not match the length of the sequence.
+pub fn hard_clip(record: &mut Record, left: u32, right: u32) -> bool {
+  if left == 0 && right == 0 {
+    return true;
+  }
+
+  let cigar: Vec<Cigar> = record.cigar().iter().cloned().collect();
+  let (cigar, pos) = trim_left(&cigar, record.pos(), left);
+  let cigar = trim_right(&cigar, right);
+
+  let aligned = cigar.iter().any(|op| matches!(op.char(), 'M' | '=' | 'X'));
+  let seq_len = record.seq_len();
+  let (left, right) = (left as usize, right as usize);
+  if !aligned || left + right >= seq_len || query_len(&cigar) != seq_len - left - right {
+    return false;
+  }
+
+  let seq = record.seq().as_bytes();
+  let qual = record.qual().to_vec();
+  let qname = record.qname().to_vec();
+  let cigar = CigarString(cigar);
+  let end = pos
+    + cigar
+      .iter()
+      .filter(|op| matches!(op.char(), 'M' | 'D' | 'N' | '=' | 'X'))
+      .map(|op| op.len() as i64)
+      .sum::<i64>();
+
+  record.set(
+    &qname,
+    Some(&cigar),
+    &seq[left..seq_len - right],
+    &qual[left..seq_len - right],
+  );
+  record.set_pos(pos);
+  record.set_bin(reg2bin(pos, end.max(pos + 1)));
+  record.remove_aux(b"MD");
+  record.remove_aux(b"NM");
+
+  true
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rust_htslib::bam::record::Aux;
+
+  fn record(cigar: Vec<Cigar>, pos: i64, seq: &[u8]) -> Record {
+    let mut record = Record::new();
+    let qual = vec![30; seq.len()];
+    record.set(b"read", Some(&CigarString(cigar)), seq, &qual);
+    record.set_tid(0);
+    record.set_pos(pos);
+    record
+  }
+
+  #[test]
+  fn trim() {
+    let cigar = [
+      Cigar::SoftClip(2),
+      Cigar::Match(5),
+      Cigar::Del(3),
+      Cigar::Match(5),
+    ];
+    assert_eq!(
+      trim_left(&cigar, 100, 4),
+      (
+        vec![
+          Cigar::HardClip(4),
+          Cigar::Match(3),
+          Cigar::Del(3),
+          Cigar::Match(5)
+        ],
+        102
+      )
+    );
+    // The deletion left at the start is dropped.
+    assert_eq!(
+      trim_left(&cigar, 100, 7),
+      (vec![Cigar::HardClip(7), Cigar::Match(5)], 108)
+    );
+    assert_eq!(
+      trim_right(&cigar, 6),
+      vec![Cigar::SoftClip(2), Cigar::Match(4), Cigar::HardClip(6)]
+    );
+
+    let cigar = [Cigar::Match(2), Cigar::Ins(3), Cigar::Match(5)];
+    assert_eq!(
+      trim_left(&cigar, 100, 3),
+      (
+        vec![Cigar::HardClip(3), Cigar::SoftClip(2), Cigar::Match(5)],
+        102
+      )
+    );
+  }
+
+  #[test]
+  fn bases() {
+    let cigar = [
+      Cigar::SoftClip(2),
+      Cigar::Match(5),
+      Cigar::Del(3),
+      Cigar::Match(5),
+    ];
+    assert_eq!(bases_before(&cigar, 100, 103), 5);
+    assert_eq!(bases_before(&cigar, 100, 110), 9);
+    assert_eq!(bases_after(&cigar, 113, 110), 3);
+    assert_eq!(bases_after(&cigar, 113, 100), 10);
+  }
+
+  #[test]
+  fn bin() {
+    assert_eq!(reg2bin(0, 1), 4681);
+    assert_eq!(reg2bin(16383, 16385), 585);
+  }
+
+  #[test]
+  fn hard_clip_record() {
+    let mut read = record(vec![Cigar::Match(10)], 100, b"ACGTACGTAC");
+    read.push_aux(b"MD", &Aux::String(b"10"));
+    read.push_aux(b"NM", &Aux::Integer(0));
+
+    assert!(hard_clip(&mut read, 2, 3));
+    assert_eq!(read.seq().as_bytes(), b"GTACG");
+    assert_eq!(read.qual().len(), 5);
+    assert_eq!(read.pos(), 102);
+    assert_eq!(
+      read.cigar().iter().cloned().collect::<Vec<_>>(),
+      vec![Cigar::HardClip(2), Cigar::Match(5), Cigar::HardClip(3)]
+    );
+    assert!(read.aux(b"MD").is_none());
+    assert!(read.aux(b"NM").is_none());
+  }
+
+  #[test]
+  fn hard_clip_unchanged() {
+    // Nothing aligned would be left.
+    let mut read = record(vec![Cigar::Match(10)], 100, b"ACGTACGTAC");
+    assert!(!hard_clip(&mut read, 5, 5));
+    assert_eq!(read.seq_len(), 10);
+
+    // The cigar is longer than the sequence.
+    let mut read = record(vec![Cigar::Match(12)], 100, b"ACGTACGTAC");
+    assert!(!hard_clip(&mut read, 2, 0));
+    assert_eq!(read.pos(), 100);
+  }
+}
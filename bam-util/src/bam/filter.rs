@@ -0,0 +1,330 @@
+//! `Filter` keeps the reads matching a cigar expression, for the bindings of the library. The
+//! `filter` subcommand adds regions, pair handling and a file of rejected reads on top of it.
+use log::*;
+use rust_htslib::bam::record::Record;
+use rust_htslib::bam::{header, Read};
+use rust_htslib::errors::Result;
+
+// Standard
+use std::collections::{BTreeSet, HashMap};
+use std::str::FromStr;
+
+// Custom
+use super::cigar::Expression;
+use super::util as bam_io;
+
+/// Number of records between two calls of the progress callback.
+pub const PROGRESS_INTERVAL: u64 = 100_000;
+
+/// Number of passed and failed reads of a filtering.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FilterSummary {
+  pub passed: u64,
+  pub failed: u64,
+  /// Whether the progress callback stopped the filtering early, the output is then partial.
+  pub cancelled: bool,
+}
+
+/// Write the reads of `input` matching the expression to `output` (BAM), unmapped reads are
+/// kept only if the expression holds for an empty cigar.
+///
+/// `progress` is called with the number of records read every `PROGRESS_INTERVAL` records and at
+/// the end, it stops the filtering early by returning false.
+pub fn filter_by_expression<F: FnMut(u64) -> bool>(
+  input: &str,
+  output: &str,
+  expression: &Expression,
+  reference: Option<&str>,
+  n_threads: usize,
+  mut progress: F,
+) -> Result<FilterSummary> {
+  let mut reader = bam_io::open_reader(input, reference, n_threads)?;
+  let header = header::Header::from_template(reader.header());
+  let format = bam_io::parse_format(if output.ends_with(".sam") {
+    "SAM"
+  } else if output.ends_with(".cram") {
+    "CRAM"
+  } else {
+    "BAM"
+  });
+  let mut writer = bam_io::open_writer(Some(output), &header, format, reference, None, n_threads)?;
+  let mut summary = FilterSummary::default();
+
+  for record in reader.records() {
+    let record = record?;
+    if expression.eval(&record.cigar()) {
+      writer.write(&record)?;
+      summary.passed += 1;
+    } else {
+      summary.failed += 1;
+    }
+
+    let total = summary.passed + summary.failed;
+    if total % PROGRESS_INTERVAL == 0 && !progress(total) {
+      summary.cancelled = true;
+      return Ok(summary);
+    }
+  }
+
+  progress(summary.passed + summary.failed);
+  Ok(summary)
+}
+
+/// How the mates of a pair are filtered.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PairMode {
+  /// Each read alone.
+  Any,
+  /// Keep the pair if both mates pass.
+  Both,
+  /// Keep the pair if one mate passes.
+  Either,
+}
+
+impl FromStr for PairMode {
+  type Err = String;
+
+  fn from_str(mode: &str) -> std::result::Result<Self, Self::Err> {
+    match mode {
+      "any" => Ok(PairMode::Any),
+      "both" => Ok(PairMode::Both),
+      "either" => Ok(PairMode::Either),
+      _ => Err(format!("Not valid pair mode: {:?}", mode)),
+    }
+  }
+}
+
+/// Sort key of a position, unmapped reads last.
+fn position_key(tid: i32, pos: i64) -> (i64, i64) {
+  (if tid < 0 { i64::MAX } else { tid as i64 }, pos)
+}
+
+/// Decides the mates of a pair together. A record is emitted as soon as its decision is known, so
+/// only the reads whose mate has not been read yet are held, and the output is not in input order.
+///
+/// The input must be coordinate-sorted or grouped by read name, a read is decided on its own result
+/// once its mate can no longer appear.
+pub struct PairBuffer {
+  mode: PairMode,
+  coordinate_sorted: bool,
+  // Read name -> (record, own result) of the mates still waiting.
+  pending: HashMap<Vec<u8>, (Record, bool)>,
+  // (Mate position, read name) of the pending records, only for coordinate-sorted input.
+  by_mate: BTreeSet<((i64, i64), Vec<u8>)>,
+}
+
+impl PairBuffer {
+  pub fn new(mode: PairMode, coordinate_sorted: bool) -> Self {
+    PairBuffer {
+      mode,
+      coordinate_sorted,
+      pending: HashMap::new(),
+      by_mate: BTreeSet::new(),
+    }
+  }
+
+  /// Number of records waiting for their mate.
+  pub fn len(&self) -> usize {
+    self.pending.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.pending.is_empty()
+  }
+
+  /// Secondary and supplementary alignments are filtered alone.
+  fn is_pairable(&self, record: &Record) -> bool {
+    self.mode != PairMode::Any
+      && record.is_paired()
+      && !record.is_secondary()
+      && !record.is_supplementary()
+  }
+
+  fn combine(&self, passed: bool, mate_passed: bool) -> bool {
+    match self.mode {
+      PairMode::Both => passed && mate_passed,
+      _ => passed || mate_passed,
+    }
+  }
+
+  /// Add a record and its own result, `emit` is called with the records decided so far.
+  pub fn push<F: FnMut(Record, bool)>(&mut self, record: Record, passed: bool, emit: &mut F) {
+    self.resolve_orphans(Some(&record), emit);
+
+    if !self.is_pairable(&record) {
+      emit(record, passed);
+      return;
+    }
+
+    match self.pending.remove(record.qname()) {
+      Some((mate, mate_passed)) => {
+        if self.coordinate_sorted {
+          let key = position_key(mate.mtid(), mate.mpos());
+          self.by_mate.remove(&(key, mate.qname().to_vec()));
+        }
+
+        let decision = self.combine(passed, mate_passed);
+        emit(mate, decision);
+        emit(record, decision);
+      }
+      None => {
+        let qname = record.qname().to_vec();
+        if self.coordinate_sorted {
+          let key = position_key(record.mtid(), record.mpos());
+          self.by_mate.insert((key, qname.clone()));
+        }
+        self.pending.insert(qname, (record, passed));
+      }
+    }
+  }
+
+  /// Decide the waiting records whose mate can no longer appear before `next` on their own result,
+  /// all of them if `next` is None.
+  fn resolve_orphans<F: FnMut(Record, bool)>(&mut self, next: Option<&Record>, emit: &mut F) {
+    let orphans: Vec<Vec<u8>> = match next {
+      None => self.pending.keys().cloned().collect(),
+      Some(next) if self.coordinate_sorted => {
+        let next_key = position_key(next.tid(), next.pos());
+        self
+          .by_mate
+          .iter()
+          .take_while(|(key, _)| *key < next_key)
+          .map(|(_, qname)| qname.clone())
+          .collect()
+      }
+      Some(next) => self
+        .pending
+        .keys()
+        .filter(|qname| &qname[..] != next.qname())
+        .cloned()
+        .collect(),
+    };
+
+    for qname in orphans {
+      let (record, passed) = self.pending.remove(&qname).unwrap();
+      if self.coordinate_sorted {
+        let key = position_key(record.mtid(), record.mpos());
+        self.by_mate.remove(&(key, qname));
+      }
+
+      debug!(
+        "{} - Mate not found: {:?}",
+        module_path!(),
+        String::from_utf8_lossy(record.qname())
+      );
+      emit(record, passed);
+    }
+  }
+
+  /// Decide the records still waiting on their own result.
+  pub fn finish<F: FnMut(Record, bool)>(&mut self, emit: &mut F) {
+    self.resolve_orphans(None, emit);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A paired read of 10 bases at `tid:pos`, its mate at `mtid:mpos`.
+  fn read(qname: &str, tid: i32, pos: i64, mtid: i32, mpos: i64) -> Record {
+    let mut record = Record::new();
+    record.set(qname.as_bytes(), None, b"ACGTACGTAC", &[30; 10]);
+    record.set_paired();
+    record.set_tid(tid);
+    record.set_pos(pos);
+    record.set_mtid(mtid);
+    record.set_mpos(mpos);
+    record
+  }
+
+  fn run(mode: PairMode, sorted: bool, reads: Vec<(Record, bool)>) -> Vec<(String, i64, bool)> {
+    let mut buffer = PairBuffer::new(mode, sorted);
+    let mut emitted = vec![];
+    let mut emit = |record: Record, passed: bool| {
+      let qname = String::from_utf8_lossy(record.qname()).into_owned();
+      emitted.push((qname, record.pos(), passed));
+    };
+
+    for (record, passed) in reads {
+      buffer.push(record, passed, &mut emit);
+    }
+    buffer.finish(&mut emit);
+    emitted
+  }
+
+  #[test]
+  fn both_and_either() {
+    let reads = || {
+      vec![
+        (read("a", 0, 100, 0, 300), true),
+        (read("b", 0, 200, 0, 250), true),
+        (read("b", 0, 250, 0, 200), true),
+        (read("a", 0, 300, 0, 100), false),
+      ]
+    };
+
+    let both = run(PairMode::Both, true, reads());
+    assert_eq!(
+      both,
+      vec![
+        (String::from("b"), 200, true),
+        (String::from("b"), 250, true),
+        (String::from("a"), 100, false),
+        (String::from("a"), 300, false),
+      ]
+    );
+
+    let either = run(PairMode::Either, true, reads());
+    assert!(either.iter().all(|(_, _, passed)| *passed));
+  }
+
+  #[test]
+  fn cross_contig_pair() {
+    let mut buffer = PairBuffer::new(PairMode::Both, true);
+    let mut emitted = vec![];
+    let mut emit = |record: Record, passed: bool| emitted.push((record.tid(), passed));
+
+    buffer.push(read("a", 0, 100, 1, 50), true, &mut emit);
+    // The reads of chr1 are not held behind the read waiting for its mate on chr2.
+    for pos in 0..100 {
+      let mut record = read(&format!("s{}", pos), 0, 200 + pos, 0, 200 + pos);
+      record.unset_paired();
+      buffer.push(record, true, &mut emit);
+    }
+    assert_eq!(buffer.len(), 1);
+    buffer.push(read("a", 1, 50, 0, 100), false, &mut emit);
+    buffer.finish(&mut emit);
+
+    assert!(buffer.is_empty());
+    assert_eq!(emitted.len(), 102);
+    assert_eq!(&emitted[100..], &[(0, false), (1, false)]);
+  }
+
+  #[test]
+  fn missing_mate() {
+    // Sorted: the mate of `a` would be before 500.
+    let emitted = run(
+      PairMode::Both,
+      true,
+      vec![
+        (read("a", 0, 100, 0, 300), true),
+        (read("c", 0, 500, 0, 500), true),
+      ],
+    );
+    assert_eq!(emitted[0], (String::from("a"), 100, true));
+
+    // Grouped by name: the mate of `a` would be next.
+    let emitted = run(
+      PairMode::Both,
+      false,
+      vec![
+        (read("a", 0, 100, 0, 300), false),
+        (read("c", 0, 50, 0, 70), true),
+        (read("c", 0, 70, 0, 50), true),
+      ],
+    );
+    assert_eq!(emitted[0], (String::from("a"), 100, false));
+    assert_eq!(emitted.len(), 3);
+  }
+}
@@ -1,3 +1,8 @@
 //! `Bam` is a suite of programs for interacting with Bam file, e.g. filtering with some conditions, such as cigar field. 
 
-pub mod cigar;
\ No newline at end of file
+pub mod cigar;
+pub mod clip;
+pub mod filter;
+pub mod progress;
+pub mod region;
+pub mod util;
\ No newline at end of file
@@ -0,0 +1,2 @@
+//! `Progress` reports the progress of a long-running command on stderr, see `omics_progress`.
+pub use omics_progress::{Progress, LOG_INTERVAL, TICK_INTERVAL};
@@ -0,0 +1,302 @@
+//! `Region` parses genomic regions (chr1:1-1000 or BED files) and resolves them against a bam header.
+use log::*;
+use rust_htslib::bam::HeaderView;
+
+// Standard
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind};
+use std::str::FromStr;
+
+/// A genomic region with 0-based, half-open coordinates. `end` is None for the whole contig.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+  pub chrom: String,
+  pub start: u64,
+  pub end: Option<u64>,
+  /// The region as given when it has a range, itself the name of a contig if the header has it,
+  /// e.g. HLA-A*01:01:01:01. See `Region::resolve`.
+  pub whole_name: Option<String>,
+}
+
+impl Region {
+  /// The (tid, start, end) interval of the region in `header`, clipped to the contig. A region
+  /// given with a range is first looked up as a whole contig name, as samtools does. None if the
+  /// contig is not in the header.
+  pub fn resolve(&self, header: &HeaderView) -> Option<(u32, u64, u64)> {
+    let whole = self
+      .whole_name
+      .as_ref()
+      .and_then(|name| header.tid(name.as_bytes()));
+    let (tid, start, end) = match whole {
+      Some(tid) => (tid, 0, None),
+      None => (header.tid(self.chrom.as_bytes())?, self.start, self.end),
+    };
+
+    let target_len = header.target_len(tid).unwrap_or(0);
+    Some((tid, start, end.unwrap_or(target_len).min(target_len)))
+  }
+}
+
+impl FromStr for Region {
+  type Err = String;
+
+  /// Parse a samtools-style region, e.g. chr1, chr1:1000 or chr1:1,000-2,000 (1-based, inclusive).
+  /// A contig name with a colon is given in braces with a range, e.g. {HLA-A*01:01}:1-100.
+  fn from_str(region: &str) -> Result<Self, Self::Err> {
+    let invalid = || format!("Not valid region: {:?}", region);
+    let (chrom, range) = if region.starts_with('{') {
+      let idx = region.find('}').ok_or_else(invalid)?;
+      match &region[idx + 1..] {
+        "" => (&region[1..idx], None),
+        range if range.starts_with(':') => (&region[1..idx], Some(&range[1..])),
+        _ => return Err(invalid()),
+      }
+    } else {
+      match region.rfind(':') {
+        Some(idx) => (&region[..idx], Some(&region[idx + 1..])),
+        None => (region, None),
+      }
+    };
+
+    if chrom.is_empty() {
+      return Err(invalid());
+    }
+
+    let (start, end) = match range {
+      None => (0, None),
+      Some(range) => {
+        let range = range.replace(',', "");
+        let mut bounds = range.splitn(2, '-');
+        let start = bounds
+          .next()
+          .and_then(|start| start.parse::<u64>().ok())
+          .filter(|&start| start > 0)
+          .ok_or_else(invalid)?;
+        let end = match bounds.next() {
+          Some(end) => Some(end.parse::<u64>().map_err(|_| invalid())?),
+          None => None,
+        };
+
+        if end.map_or(false, |end| end < start) {
+          return Err(invalid());
+        }
+
+        (start - 1, end)
+      }
+    };
+
+    let whole_name = match range {
+      Some(_) if !region.starts_with('{') => Some(String::from(region)),
+      _ => None,
+    };
+
+    Ok(Region {
+      chrom: String::from(chrom),
+      start,
+      end,
+      whole_name,
+    })
+  }
+}
+
+/// Read regions from a BED file, track/browser/comment lines are skipped.
+pub fn read_bed(path: &str) -> std::io::Result<Vec<Region>> {
+  let reader = BufReader::new(File::open(path)?);
+  let mut regions = vec![];
+
+  for (idx, line) in reader.lines().enumerate() {
+    let line = line?;
+    if line.trim().is_empty()
+      || line.starts_with('#')
+      || line.starts_with("track")
+      || line.starts_with("browser")
+    {
+      continue;
+    }
+
+    let fields: Vec<&str> = line.split('\t').collect();
+    let invalid = || {
+      Error::new(
+        ErrorKind::InvalidData,
+        format!("{}:{} - Not valid bed line: {:?}", path, idx + 1, line),
+      )
+    };
+
+    if fields.len() < 3 {
+      return Err(invalid());
+    }
+
+    let start = fields[1].trim().parse::<u64>().map_err(|_| invalid())?;
+    let end = fields[2].trim().parse::<u64>().map_err(|_| invalid())?;
+    regions.push(Region {
+      chrom: String::from(fields[0]),
+      start,
+      end: Some(end),
+      whole_name: None,
+    });
+  }
+
+  Ok(regions)
+}
+
+/// Resolve regions into sorted, non-overlapping (tid, start, end) intervals. Adjacent regions are
+/// kept apart.
+///
+/// Regions on contigs missing from the header are skipped with a warning.
+pub fn merge_regions(header: &HeaderView, regions: &[Region]) -> Vec<(u32, u64, u64)> {
+  let mut intervals: Vec<(u32, u64, u64)> = regions
+    .iter()
+    .filter_map(|region| match region.resolve(header) {
+      Some(interval) => Some(interval),
+      None => {
+        warn!(
+          "Region {:?} not found in the header, skipped.",
+          region.chrom
+        );
+        None
+      }
+    })
+    .filter(|&(_, start, end)| start < end)
+    .collect();
+
+  intervals.sort();
+
+  let mut merged: Vec<(u32, u64, u64)> = vec![];
+  for (tid, start, end) in intervals {
+    match merged.last_mut() {
+      Some(last) if last.0 == tid && start < last.2 => last.2 = last.2.max(end),
+      _ => merged.push((tid, start, end)),
+    }
+  }
+
+  merged
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Write;
+
+  fn header() -> HeaderView {
+    HeaderView::from_bytes(
+      b"@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:500\n@SQ\tSN:HLA-A*01:01:01:01\tLN:3000\n",
+    )
+  }
+
+  fn region(chrom: &str, start: u64, end: Option<u64>) -> Region {
+    Region {
+      chrom: String::from(chrom),
+      start,
+      end,
+      whole_name: None,
+    }
+  }
+
+  #[test]
+  fn from_str() {
+    let parsed = "chr1:1,001-2,000".parse::<Region>().unwrap();
+    assert_eq!(
+      (parsed.chrom.as_str(), parsed.start, parsed.end),
+      ("chr1", 1000, Some(2000))
+    );
+    assert_eq!(parsed.whole_name.as_deref(), Some("chr1:1,001-2,000"));
+
+    assert_eq!("chr1".parse::<Region>(), Ok(region("chr1", 0, None)));
+    let parsed = "chr1:100".parse::<Region>().unwrap();
+    assert_eq!((parsed.start, parsed.end), (99, None));
+    assert_eq!(
+      "{HLA-A*01:01}:1-100".parse::<Region>(),
+      Ok(region("HLA-A*01:01", 0, Some(100)))
+    );
+    assert_eq!(
+      "{HLA-A*01:01}".parse::<Region>(),
+      Ok(region("HLA-A*01:01", 0, None))
+    );
+
+    for invalid in &[
+      ":1-100",
+      "chr1:0-100",
+      "chr1:200-100",
+      "chr1:a-100",
+      "{chr1",
+      "{chr1}x",
+    ] {
+      assert!(invalid.parse::<Region>().is_err(), "{}", invalid);
+    }
+  }
+
+  #[test]
+  fn resolve() {
+    let header = header();
+    assert_eq!(
+      region("chr1", 100, None).resolve(&header),
+      Some((0, 100, 1000))
+    );
+    assert_eq!(
+      region("chr2", 100, Some(900)).resolve(&header),
+      Some((1, 100, 500))
+    );
+    assert_eq!(region("chr3", 0, None).resolve(&header), None);
+
+    // A contig with colons, given without a range.
+    let hla = "HLA-A*01:01:01:01".parse::<Region>().unwrap();
+    assert_eq!(hla.resolve(&header), Some((2, 0, 3000)));
+    let hla = "{HLA-A*01:01:01:01}:11-20".parse::<Region>().unwrap();
+    assert_eq!(hla.resolve(&header), Some((2, 10, 20)));
+  }
+
+  #[test]
+  fn bed() {
+    let path = std::env::temp_dir().join(format!("region-{}.bed", std::process::id()));
+    let mut bed = File::create(&path).unwrap();
+    bed
+      .write_all(b"track name=test\n# comment\nchr1\t0\t100\tfirst\n\nchr2\t10\t20\n")
+      .unwrap();
+    drop(bed);
+    let regions = read_bed(path.to_str().unwrap());
+    std::fs::remove_file(&path).unwrap();
+
+    // BED is already 0-based, half-open.
+    assert_eq!(
+      regions.unwrap(),
+      vec![region("chr1", 0, Some(100)), region("chr2", 10, Some(20))]
+    );
+    assert_eq!(
+      "chr1:1-100".parse::<Region>().unwrap().resolve(&header()),
+      region("chr1", 0, Some(100)).resolve(&header())
+    );
+  }
+
+  #[test]
+  fn invalid_bed() {
+    let path = std::env::temp_dir().join(format!("region-invalid-{}.bed", std::process::id()));
+    std::fs::write(&path, b"chr1\t0\t100\nchr1\tten\t20\n").unwrap();
+    let regions = read_bed(path.to_str().unwrap());
+    std::fs::remove_file(&path).unwrap();
+
+    let err = regions.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert!(err.to_string().contains(":2 - Not valid bed line"));
+  }
+
+  #[test]
+  fn merge() {
+    let regions = vec![
+      region("chr2", 0, Some(10)),
+      region("chr1", 50, Some(150)),
+      region("chr1", 100, Some(200)),
+      // Adjacent, kept apart.
+      region("chr1", 200, Some(300)),
+      // Contained.
+      region("chr1", 60, Some(70)),
+      region("chr3", 0, Some(10)),
+      // Empty once clipped to the contig.
+      region("chr2", 600, Some(700)),
+    ];
+
+    assert_eq!(
+      merge_regions(&header(), &regions),
+      vec![(0, 50, 200), (0, 200, 300), (1, 0, 10)]
+    );
+  }
+}
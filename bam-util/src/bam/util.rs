@@ -0,0 +1,323 @@
+//! `Util` gathers the reader/writer setup shared by the bam-util subcommands.
+use rust_htslib::bam::record::{Aux, Record};
+use rust_htslib::bam::{
+  header, index, CompressionLevel, Format, HeaderView, IndexedReader, Read, Reader, Writer,
+};
+use rust_htslib::errors::Result;
+use rust_htslib::htslib;
+use rust_htslib::tpool::ThreadPool;
+
+// Standard
+use std::collections::{HashMap, HashSet};
+use std::str;
+
+/// Largest reference length a BAI index can address (2^29 - 1).
+const BAI_MAX_TARGET_LEN: u64 = (1 << 29) - 1;
+
+/// Convert a format name (BAM, SAM, CRAM) into a htslib format, BAM if unknown.
+pub fn parse_format(format: &str) -> Format {
+  match format {
+    "SAM" => Format::SAM,
+    "CRAM" => Format::CRAM,
+    _ => Format::BAM,
+  }
+}
+
+/// Open a BAM/SAM/CRAM file, the reference is required for decoding CRAM.
+pub fn open_reader(path: &str, reference: Option<&str>, n_threads: usize) -> Result<Reader> {
+  let mut reader = Reader::from_path(path)?;
+
+  if let Some(reference) = reference {
+    reader.set_reference(reference)?;
+  }
+
+  reader.set_threads(n_threads)?;
+  Ok(reader)
+}
+
+/// Open a file as `open_reader` does, decompressing with the threads of a pool shared with other
+/// readers and writers.
+pub fn open_pooled_reader(
+  path: &str,
+  reference: Option<&str>,
+  pool: &ThreadPool,
+) -> Result<Reader> {
+  let mut reader = Reader::from_path(path)?;
+
+  if let Some(reference) = reference {
+    reader.set_reference(reference)?;
+  }
+
+  reader.set_thread_pool(pool)?;
+  Ok(reader)
+}
+
+/// Open an indexed BAM/CRAM file for fetching regions, the index must sit next to the file.
+pub fn open_indexed_reader(
+  path: &str,
+  reference: Option<&str>,
+  n_threads: usize,
+) -> Result<IndexedReader> {
+  let mut reader = IndexedReader::from_path(path)?;
+
+  if let Some(reference) = reference {
+    reader.set_reference(reference)?;
+  }
+
+  reader.set_threads(n_threads)?;
+  Ok(reader)
+}
+
+/// Open a writer to `output` or stdout when `output` is None.
+///
+/// `compression_level` is between 0 (uncompressed) and 9 (best), htslib's default is used if None.
+pub fn open_writer(
+  output: Option<&str>,
+  header: &header::Header,
+  format: Format,
+  reference: Option<&str>,
+  compression_level: Option<u32>,
+  n_threads: usize,
+) -> Result<Writer> {
+  let mut writer = create_writer(output, header, format, reference, compression_level)?;
+  writer.set_threads(n_threads)?;
+  Ok(writer)
+}
+
+/// Open a writer as `open_writer` does, compressing with the threads of a pool shared with other
+/// readers and writers.
+pub fn open_pooled_writer(
+  output: Option<&str>,
+  header: &header::Header,
+  format: Format,
+  reference: Option<&str>,
+  compression_level: Option<u32>,
+  pool: &ThreadPool,
+) -> Result<Writer> {
+  let mut writer = create_writer(output, header, format, reference, compression_level)?;
+  writer.set_thread_pool(pool)?;
+  Ok(writer)
+}
+
+fn create_writer(
+  output: Option<&str>,
+  header: &header::Header,
+  format: Format,
+  reference: Option<&str>,
+  compression_level: Option<u32>,
+) -> Result<Writer> {
+  let mut writer = match output {
+    Some(output) => Writer::from_path(output, header, format)?,
+    None => Writer::from_stdout(header, format)?,
+  };
+
+  if let Some(reference) = reference {
+    writer.set_reference(reference)?;
+  }
+
+  if let Some(level) = compression_level {
+    writer.set_compression_level(CompressionLevel::Level(level))?;
+  }
+
+  Ok(writer)
+}
+
+/// Whether the header declares `SO:coordinate` in its @HD line.
+pub fn is_coordinate_sorted(header: &HeaderView) -> bool {
+  str::from_utf8(header.as_bytes())
+    .unwrap_or("")
+    .lines()
+    .filter(|line| line.starts_with("@HD"))
+    .any(|line| line.split('\t').any(|field| field == "SO:coordinate"))
+}
+
+/// Whether the header declares the records grouped by name, `SO:queryname` or `GO:query` in its
+/// @HD line.
+pub fn is_grouped_by_name(header: &HeaderView) -> bool {
+  str::from_utf8(header.as_bytes())
+    .unwrap_or("")
+    .lines()
+    .filter(|line| line.starts_with("@HD"))
+    .any(|line| {
+      line
+        .split('\t')
+        .any(|field| field == "SO:queryname" || field == "GO:query")
+    })
+}
+
+/// Build a .bai index next to `path`, or a .csi index if a reference is too long for BAI.
+/// CRAM files always get a .crai index.
+pub fn build_index(path: &str, header: &HeaderView, n_threads: usize) -> Result<()> {
+  let too_long =
+    (0..header.target_count()).any(|tid| header.target_len(tid).unwrap_or(0) > BAI_MAX_TARGET_LEN);
+  let idx_type = if too_long {
+    index::Type::Csi(14)
+  } else {
+    index::Type::Bai
+  };
+
+  index::build(path, None, idx_type, n_threads as u32)
+}
+
+/// Compressed offset of the block being read from a BGZF file (BAM, bgzipped SAM), None for
+/// other files.
+pub fn compressed_offset<R: Read>(reader: &R) -> Option<u64> {
+  unsafe {
+    let htsfile = reader.htsfile();
+    if htsfile.is_null() || (*htsfile).format.compression != htslib::htsCompression_bgzf {
+      return None;
+    }
+
+    let bgzf = (*htsfile).fp.bgzf;
+    if bgzf.is_null() {
+      None
+    } else {
+      Some((*bgzf).block_address as u64)
+    }
+  }
+}
+
+/// Reference name of a record, `*` if unmapped.
+pub fn reference_name(header: &HeaderView, record: &Record) -> String {
+  if record.tid() < 0 {
+    String::from("*")
+  } else {
+    String::from_utf8_lossy(header.tid2name(record.tid() as u32)).into_owned()
+  }
+}
+
+/// Value of a tag of a record as a string, if any. Array tags are not supported.
+pub fn tag_value(record: &Record, tag: &[u8]) -> Option<String> {
+  match record.aux(tag) {
+    Some(Aux::String(value)) => Some(String::from_utf8_lossy(value).into_owned()),
+    Some(Aux::Char(value)) => Some((value as char).to_string()),
+    Some(Aux::Integer(value)) => Some(value.to_string()),
+    Some(Aux::Float(value)) => Some(value.to_string()),
+    _ => None,
+  }
+}
+
+/// Value of the RG tag of a record, if any.
+pub fn read_group(record: &Record) -> Option<String> {
+  tag_value(record, b"RG")
+}
+
+/// Read group ID -> value of a field (e.g. `LB:`) declared by the @RG lines of the header.
+fn read_group_field(header: &HeaderView, name: &str) -> HashMap<String, String> {
+  str::from_utf8(header.as_bytes())
+    .unwrap_or("")
+    .lines()
+    .filter(|line| line.starts_with("@RG"))
+    .filter_map(|line| {
+      let tag = |name: &str| {
+        line
+          .split('\t')
+          .find(|field| field.starts_with(name))
+          .map(|field| String::from(&field[name.len()..]))
+      };
+
+      match (tag("ID:"), tag(name)) {
+        (Some(id), Some(value)) => Some((id, value)),
+        _ => None,
+      }
+    })
+    .collect()
+}
+
+/// Read group ID -> library (LB) declared by the @RG lines of the header.
+pub fn read_group_libraries(header: &HeaderView) -> HashMap<String, String> {
+  read_group_field(header, "LB:")
+}
+
+/// Read group ID -> sample (SM) declared by the @RG lines of the header.
+pub fn read_group_samples(header: &HeaderView) -> HashMap<String, String> {
+  read_group_field(header, "SM:")
+}
+
+/// IDs of the read groups declared in the header, in header order.
+pub fn read_group_ids(header: &HeaderView) -> Vec<String> {
+  str::from_utf8(header.as_bytes())
+    .unwrap_or("")
+    .lines()
+    .filter(|line| line.starts_with("@RG"))
+    .filter_map(|line| line.split('\t').find(|field| field.starts_with("ID:")))
+    .map(|field| String::from(&field[3..]))
+    .collect()
+}
+
+/// Copy of the header keeping only the @RG lines whose ID is in `read_groups`.
+pub fn keep_read_groups(header: &HeaderView, read_groups: &HashSet<String>) -> header::Header {
+  let text: Vec<&str> = str::from_utf8(header.as_bytes())
+    .unwrap_or("")
+    .lines()
+    .filter(|line| {
+      !line.starts_with("@RG")
+        || line
+          .split('\t')
+          .find(|field| field.starts_with("ID:"))
+          .map_or(false, |field| read_groups.contains(&field[3..]))
+    })
+    .collect();
+
+  let view = HeaderView::from_bytes(format!("{}\n", text.join("\n")).as_bytes());
+  header::Header::from_template(&view)
+}
+
+/// Copy of the header whose @HD line declares the sort order `SO:<sort_order>`, an @HD line is
+/// added if missing.
+pub fn with_sort_order(header: &HeaderView, sort_order: &str) -> HeaderView {
+  let mut lines: Vec<String> = str::from_utf8(header.as_bytes())
+    .unwrap_or("")
+    .lines()
+    .map(|line| {
+      if !line.starts_with("@HD") {
+        return String::from(line);
+      }
+
+      let mut fields: Vec<String> = line
+        .split('\t')
+        .filter(|field| !field.starts_with("SO:"))
+        .map(String::from)
+        .collect();
+      fields.push(format!("SO:{}", sort_order));
+      fields.join("\t")
+    })
+    .collect();
+
+  if !lines.iter().any(|line| line.starts_with("@HD")) {
+    lines.insert(0, format!("@HD\tVN:1.6\tSO:{}", sort_order));
+  }
+
+  HeaderView::from_bytes(format!("{}\n", lines.join("\n")).as_bytes())
+}
+
+/// Estimate the number of unique molecules in a library, with the model of Picard
+/// EstimateLibraryComplexity. None if it can't be estimated.
+pub fn estimate_library_size(read_pairs: u64, unique_read_pairs: u64) -> Option<u64> {
+  let f = |x: f64, c: f64, n: f64| c / x - 1.0 + (-n / x).exp();
+  let (n, c) = (read_pairs as f64, unique_read_pairs as f64);
+
+  if unique_read_pairs == 0 || unique_read_pairs >= read_pairs || f(c, c, n) < 0.0 {
+    return None;
+  }
+
+  let mut lower = 1.0;
+  let mut upper = 100.0;
+  while f(upper * c, c, n) > 0.0 {
+    upper *= 10.0;
+  }
+
+  for _ in 0..40 {
+    let r = (lower + upper) / 2.0;
+    let u = f(r * c, c, n);
+    if u == 0.0 {
+      break;
+    } else if u > 0.0 {
+      lower = r;
+    } else {
+      upper = r;
+    }
+  }
+
+  Some((c * (lower + upper) / 2.0) as u64)
+}
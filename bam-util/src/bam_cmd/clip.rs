@@ -0,0 +1,175 @@
+// External
+use log::*;
+use rust_htslib::bam::record::Record;
+use rust_htslib::bam::{header, Read};
+use structopt::StructOpt;
+
+// Standard
+use std::path::Path;
+
+// Custom
+extern crate bam_util;
+use bam_util::bam::clip as bam_clip;
+use bam_util::bam::util as bam_io;
+
+/// Hard clip or trim the ends of the reads
+///
+/// Mate fields (MPOS, TLEN) are not updated and the MD/NM tags of the clipped reads are dropped,
+/// run samtools fixmate/calmd afterwards if needed. Trimming the left end moves reads forward, so
+/// the output of --trim-5p, --trim-3p and --overhang is declared unsorted (SO:unsorted) and may
+/// need to be sorted again.
+#[derive(StructOpt, PartialEq, Debug)]
+#[structopt(setting=structopt::clap::AppSettings::ColoredHelp, name="Omics Tool Suite - Bam Utility - clip", author="Jingcheng Yang <yjcyxky@163.com>")]
+pub struct Arguments {
+  /// Bam file to process
+  #[structopt(name = "FILE")]
+  input: String,
+
+  /// Output file, stdout if not set.
+  #[structopt(name = "output", short = "o", long = "output")]
+  output: Option<String>,
+
+  /// A format for output file.
+  #[structopt(name="format", short="O", long="format", possible_values=&["BAM", "SAM", "CRAM"], default_value="BAM")]
+  format: String,
+
+  /// Reference fasta file, required for reading or writing CRAM.
+  #[structopt(name = "reference", short = "r", long = "reference")]
+  reference: Option<String>,
+
+  /// Compression level of the output file, from 0 (uncompressed) to 9 (best).
+  #[structopt(name = "level", short = "l", long = "level", possible_values=&["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"])]
+  level: Option<u32>,
+
+  /// Convert the soft clips of at least this length into hard clips
+  #[structopt(name = "soft_to_hard", long = "soft-to-hard")]
+  soft_to_hard: Option<u32>,
+
+  /// Number of bases to trim from the 5' end of the reads
+  #[structopt(name = "trim_5p", long = "trim-5p", default_value = "0")]
+  trim_5p: u32,
+
+  /// Number of bases to trim from the 3' end of the reads
+  #[structopt(name = "trim_3p", long = "trim-3p", default_value = "0")]
+  trim_3p: u32,
+
+  /// Trim the bases of FR pairs extending past the start of their mate (adapter read-through)
+  #[structopt(name = "overhang", long = "overhang")]
+  overhang: bool,
+
+  /// Number of threads
+  #[structopt(
+    name = "n_threads",
+    short = "n",
+    long = "n_threads",
+    default_value = "1"
+  )]
+  n_threads: usize,
+}
+
+/// Read bases to clip from the left and the right end (reference orientation) of a mapped record.
+fn clip_lengths(args: &Arguments, record: &Record) -> (u32, u32) {
+  let cigar = record.cigar();
+  let ops: Vec<_> = cigar.iter().cloned().collect();
+  let (mut left, mut right) = if record.is_reverse() {
+    (args.trim_3p, args.trim_5p)
+  } else {
+    (args.trim_5p, args.trim_3p)
+  };
+
+  let is_fr_pair = record.is_paired()
+    && !record.is_mate_unmapped()
+    && record.tid() == record.mtid()
+    && record.is_reverse() != record.is_mate_reverse();
+  if args.overhang && is_fr_pair {
+    if !record.is_reverse() && record.insert_size() > 0 {
+      let fragment_end = record.pos() + record.insert_size();
+      right = right.max(bam_clip::bases_after(&ops, cigar.end_pos(), fragment_end));
+    } else if record.is_reverse() && record.insert_size() < 0 {
+      left = left.max(bam_clip::bases_before(&ops, record.pos(), record.mpos()));
+    }
+  }
+
+  if let Some(min_length) = args.soft_to_hard {
+    let leading = cigar.leading_softclips() as u32;
+    let trailing = cigar.trailing_softclips() as u32;
+    if leading >= min_length.max(1) {
+      left = left.max(leading);
+    }
+    if trailing >= min_length.max(1) {
+      right = right.max(trailing);
+    }
+  }
+
+  (left, right)
+}
+
+pub fn run(args: &Arguments) {
+  info!("{} - Clip reads: {:?}", module_path!(), args.input);
+
+  if args.format == "CRAM" && args.reference.is_none() {
+    error!(
+      "{} - CRAM output requires a reference (--reference).",
+      module_path!()
+    );
+    std::process::exit(exitcode::USAGE);
+  }
+
+  if Path::new(&args.input).exists() {
+    clip(args);
+  } else {
+    error!("{} - Not Found: {:?}", module_path!(), args.input);
+    std::process::exit(exitcode::NOINPUT);
+  }
+}
+
+pub fn clip(args: &Arguments) {
+  let reference = args.reference.as_deref();
+  let mut reader = bam_io::open_reader(&args.input, reference, args.n_threads).unwrap();
+  // Only the soft clips are hard clipped otherwise, which does not move the reads.
+  let moves_pos = args.trim_5p > 0 || args.trim_3p > 0 || args.overhang;
+  let header = if moves_pos {
+    header::Header::from_template(&bam_io::with_sort_order(reader.header(), "unsorted"))
+  } else {
+    header::Header::from_template(reader.header())
+  };
+  let mut writer = bam_io::open_writer(
+    args.output.as_deref(),
+    &header,
+    bam_io::parse_format(&args.format),
+    reference,
+    args.level,
+    args.n_threads,
+  )
+  .unwrap();
+  let (mut clipped, mut too_short) = (0, 0);
+
+  for record in reader.records() {
+    let mut record = record.unwrap();
+
+    if !record.is_unmapped() {
+      let (left, right) = clip_lengths(args, &record);
+      if left > 0 || right > 0 {
+        if bam_clip::hard_clip(&mut record, left, right) {
+          clipped += 1;
+        } else {
+          too_short += 1;
+          debug!(
+            "{} - Not clipped, no aligned base left: {:?}",
+            module_path!(),
+            String::from_utf8_lossy(record.qname())
+          );
+        }
+      }
+    }
+
+    writer.write(&record).unwrap();
+  }
+
+  info!(
+    "{} - {} reads clipped, {} reads left unchanged as no aligned base would be left",
+    module_path!(),
+    clipped,
+    too_short
+  );
+}
@@ -0,0 +1,256 @@
+// External
+use log::*;
+use rust_htslib::bam::record::Record;
+use rust_htslib::bam::{HeaderView, Read};
+use structopt::StructOpt;
+
+// Standard
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+// Custom
+extern crate bam_util;
+use bam_util::bam::util as bam_io;
+
+/// Extract the soft-clipped sequences of the reads as FASTQ
+#[derive(StructOpt, PartialEq, Debug)]
+#[structopt(setting=structopt::clap::AppSettings::ColoredHelp, name="Omics Tool Suite - Bam Utility - clips", author="Jingcheng Yang <yjcyxky@163.com>")]
+pub struct Arguments {
+  /// Bam file to process
+  #[structopt(name = "FILE")]
+  input: String,
+
+  /// Output file, stdout if not set.
+  #[structopt(name = "output", short = "o", long = "output")]
+  output: Option<String>,
+
+  /// Minimum length of a soft clip to extract
+  #[structopt(
+    name = "min_length",
+    short = "m",
+    long = "min-length",
+    default_value = "1"
+  )]
+  min_length: usize,
+
+  /// Reference fasta file, required for reading CRAM.
+  #[structopt(name = "reference", short = "r", long = "reference")]
+  reference: Option<String>,
+
+  /// Number of threads
+  #[structopt(
+    name = "n_threads",
+    short = "n",
+    long = "n_threads",
+    default_value = "1"
+  )]
+  n_threads: usize,
+}
+
+/// Write a clip as a FASTQ entry, sequence and qualities stay on the reference strand.
+///
+/// Header: @<read name> side=<left|right> pos=<chrom>:<pos> strand=<+|->, pos is the 1-based
+/// reference position of the aligned base next to the clip.
+fn write_clip<W: Write>(
+  writer: &mut W,
+  record: &Record,
+  chrom: &str,
+  side: &str,
+  pos: i64,
+  seq: &[u8],
+  qual: &[u8],
+) -> io::Result<()> {
+  // 0xff means the qualities are missing, FASTQ can not encode qualities above 93 ('~').
+  let qual: Vec<u8> = qual
+    .iter()
+    .map(|&q| if q == 0xff { b'!' } else { q.min(93) + 33 })
+    .collect();
+
+  writeln!(
+    writer,
+    "@{} side={} pos={}:{} strand={}",
+    String::from_utf8_lossy(record.qname()),
+    side,
+    chrom,
+    pos,
+    if record.is_reverse() { "-" } else { "+" }
+  )?;
+  writer.write_all(seq)?;
+  writeln!(writer, "\n+")?;
+  writer.write_all(&qual)?;
+  writeln!(writer)
+}
+
+/// Write the leading and trailing soft clips of a record, return the number of clips written.
+///
+/// `min_length` must be at least 1.
+fn extract<W: Write>(
+  writer: &mut W,
+  header: &HeaderView,
+  record: &Record,
+  min_length: usize,
+) -> io::Result<u64> {
+  let cigar = record.cigar();
+  let leading = cigar.leading_softclips() as usize;
+  let trailing = cigar.trailing_softclips() as usize;
+  if leading < min_length && trailing < min_length {
+    return Ok(0);
+  }
+
+  let chrom = bam_io::reference_name(header, record);
+  let seq = record.seq().as_bytes();
+  let qual = record.qual();
+  // A cigar clipping more bases than the sequence has is malformed, the record is skipped.
+  let start = match seq.len().checked_sub(trailing) {
+    Some(start) if leading <= start && qual.len() == seq.len() => start,
+    _ => {
+      warn!(
+        "{} - Clips longer than the sequence, skipped: {:?}",
+        module_path!(),
+        String::from_utf8_lossy(record.qname())
+      );
+      return Ok(0);
+    }
+  };
+  let mut written = 0;
+
+  if leading >= min_length {
+    write_clip(
+      writer,
+      record,
+      &chrom,
+      "left",
+      record.pos() + 1,
+      &seq[..leading],
+      &qual[..leading],
+    )?;
+    written += 1;
+  }
+
+  if trailing >= min_length {
+    write_clip(
+      writer,
+      record,
+      &chrom,
+      "right",
+      cigar.end_pos(),
+      &seq[start..],
+      &qual[start..],
+    )?;
+    written += 1;
+  }
+
+  Ok(written)
+}
+
+pub fn run(args: &Arguments) {
+  info!("{} - Extract soft clips: {:?}", module_path!(), args.input);
+
+  if Path::new(&args.input).exists() {
+    clips(args);
+  } else {
+    error!("{} - Not Found: {:?}", module_path!(), args.input);
+    std::process::exit(exitcode::NOINPUT);
+  }
+}
+
+/// Unmapped and secondary alignments are skipped, supplementary alignments are kept as their
+/// clips point to the other side of a split read.
+pub fn clips(args: &Arguments) {
+  let mut reader =
+    bam_io::open_reader(&args.input, args.reference.as_deref(), args.n_threads).unwrap();
+  let header_view = reader.header().clone();
+  let mut writer: Box<dyn Write> = match &args.output {
+    Some(output) => Box::new(BufWriter::new(File::create(output).unwrap())),
+    None => Box::new(BufWriter::new(io::stdout())),
+  };
+  let mut written = 0;
+
+  for record in reader.records() {
+    let record = record.unwrap();
+    if record.is_unmapped() || record.is_secondary() || record.seq_len() == 0 {
+      continue;
+    }
+
+    written += extract(&mut writer, &header_view, &record, args.min_length.max(1)).unwrap();
+  }
+
+  info!("{} - {} soft clips extracted", module_path!(), written);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rust_htslib::bam::record::{Cigar, CigarString};
+
+  fn read(cigar: Vec<Cigar>, seq: &[u8], qual: &[u8], flags: u16) -> Record {
+    let mut record = Record::new();
+    record.set(b"read1", Some(&CigarString(cigar)), seq, qual);
+    record.set_tid(0);
+    record.set_pos(100);
+    record.set_flags(flags);
+    record
+  }
+
+  fn clips(record: &Record, min_length: usize) -> (u64, String) {
+    let header = HeaderView::from_bytes(b"@SQ\tSN:chr1\tLN:1000\n");
+    let mut fastq = vec![];
+    let written = extract(&mut fastq, &header, record, min_length).unwrap();
+    (written, String::from_utf8(fastq).unwrap())
+  }
+
+  #[test]
+  fn leading_and_trailing() {
+    let cigar = vec![Cigar::SoftClip(3), Cigar::Match(5), Cigar::SoftClip(2)];
+    let record = read(cigar, b"AACCGTTTGG", &[30; 10], 0x10);
+
+    assert_eq!(
+      clips(&record, 1),
+      (
+        2,
+        String::from(
+          "@read1 side=left pos=chr1:101 strand=-\nAAC\n+\n???\n\
+           @read1 side=right pos=chr1:105 strand=-\nGG\n+\n??\n"
+        )
+      )
+    );
+    // Only the leading clip is long enough.
+    let (written, fastq) = clips(&record, 3);
+    assert_eq!(written, 1);
+    assert!(fastq.starts_with("@read1 side=left"));
+    assert_eq!(clips(&record, 4), (0, String::new()));
+  }
+
+  #[test]
+  fn qualities() {
+    let record = read(
+      vec![Cigar::SoftClip(4), Cigar::Match(2)],
+      b"ACGTAC",
+      &[0, 40, 93, 120, 30, 30],
+      0,
+    );
+    assert!(clips(&record, 1).1.ends_with("\nACGT\n+\n!I~~\n"));
+
+    // Missing qualities.
+    let record = read(
+      vec![Cigar::SoftClip(2), Cigar::Match(2)],
+      b"ACGT",
+      &[0xff; 4],
+      0,
+    );
+    assert!(clips(&record, 1).1.ends_with("\nAC\n+\n!!\n"));
+  }
+
+  #[test]
+  fn malformed_cigar() {
+    // The clips are longer than the sequence.
+    let record = read(
+      vec![Cigar::SoftClip(4), Cigar::Match(4), Cigar::SoftClip(4)],
+      b"ACGTAC",
+      &[30; 6],
+      0,
+    );
+    assert_eq!(clips(&record, 1), (0, String::new()));
+  }
+}
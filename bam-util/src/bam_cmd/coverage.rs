@@ -0,0 +1,372 @@
+// External
+use log::*;
+use rust_htslib::bam::record::Record;
+use rust_htslib::bam::{HeaderView, Read};
+use structopt::StructOpt;
+
+// Standard
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+// Custom
+extern crate bam_util;
+use bam_util::bam::cigar::Expression;
+use bam_util::bam::region as bam_region;
+use bam_util::bam::util as bam_io;
+
+/// Depth thresholds reported as percent of bases covered.
+const THRESHOLDS: [u32; 5] = [1, 10, 20, 30, 100];
+
+/// Compute depth and coverage over target regions
+#[derive(StructOpt, PartialEq, Debug)]
+#[structopt(setting=structopt::clap::AppSettings::ColoredHelp, name="Omics Tool Suite - Bam Utility - coverage", author="Jingcheng Yang <yjcyxky@163.com>")]
+pub struct Arguments {
+  /// Bam file to process, must be indexed
+  #[structopt(name = "FILE")]
+  input: String,
+
+  /// Target regions
+  #[structopt(name = "bed", short = "b", long = "bed")]
+  bed: String,
+
+  /// Output file for the summary of each region, stdout if not set.
+  #[structopt(name = "output", short = "o", long = "output")]
+  output: Option<String>,
+
+  /// Output file for the per-base depth (bedGraph), over the regions merged where they overlap
+  #[structopt(name = "bedgraph", long = "bedgraph")]
+  bedgraph: Option<String>,
+
+  /// Only count the reads matching the cigar expression, same syntax as filter. e.g. sum(S) < 20
+  #[structopt(name = "cigar", short = "c", long = "cigar")]
+  cigar: Option<String>,
+
+  /// Only count the reads with a mapping quality greater than or equal to it
+  #[structopt(name = "min_mapq", short = "q", long = "min-mapq", default_value = "0")]
+  min_mapq: u8,
+
+  /// Reference fasta file, required for reading CRAM.
+  #[structopt(name = "reference", short = "r", long = "reference")]
+  reference: Option<String>,
+
+  /// Number of threads
+  #[structopt(
+    name = "n_threads",
+    short = "n",
+    long = "n_threads",
+    default_value = "1"
+  )]
+  n_threads: usize,
+}
+
+/// Unmapped, secondary, QC-failed and duplicate reads are never counted, as in samtools depth.
+fn is_counted(record: &Record, min_mapq: u8, expression: Option<&Expression>) -> bool {
+  !record.is_unmapped()
+    && !record.is_secondary()
+    && !record.is_quality_check_failed()
+    && !record.is_duplicate()
+    && record.mapq() >= min_mapq
+    && expression.map_or(true, |expression| expression.eval(&record.cigar()))
+}
+
+/// Add the aligned bases (M/=/X) of a record to the depth of the region starting at `start`.
+fn add_depth(depth: &mut [u32], start: i64, record: &Record) {
+  let end = start + depth.len() as i64;
+  let mut pos = record.pos();
+
+  for cigar in record.cigar().iter() {
+    let len = cigar.len() as i64;
+    match cigar.char() {
+      'M' | '=' | 'X' => {
+        for i in pos.max(start)..(pos + len).min(end) {
+          depth[(i - start) as usize] += 1;
+        }
+        pos += len;
+      }
+      'D' | 'N' => pos += len,
+      _ => {}
+    }
+
+    if pos >= end {
+      break;
+    }
+  }
+}
+
+/// Summary of the depth of a region.
+struct Summary {
+  mean: f64,
+  median: u32,
+  // Percent of bases with a depth of at least each of THRESHOLDS.
+  covered: Vec<f64>,
+}
+
+fn summarize(depth: &[u32]) -> Summary {
+  let mut histogram: BTreeMap<u32, u64> = BTreeMap::new();
+  for &value in depth {
+    *histogram.entry(value).or_insert(0) += 1;
+  }
+
+  let n = depth.len() as u64;
+  let total: u64 = depth.iter().map(|&value| value as u64).sum();
+  let mut median = 0;
+  let mut seen = 0;
+  for (&value, &count) in &histogram {
+    seen += count;
+    if seen * 2 >= n {
+      median = value;
+      break;
+    }
+  }
+
+  let covered = THRESHOLDS
+    .iter()
+    .map(|&threshold| {
+      let count = depth.iter().filter(|&&value| value >= threshold).count();
+      count as f64 / n.max(1) as f64 * 100.0
+    })
+    .collect();
+
+  Summary {
+    mean: total as f64 / n.max(1) as f64,
+    median,
+    covered,
+  }
+}
+
+/// Write the runs of equal depth as bedGraph lines.
+fn write_bedgraph<W: Write>(
+  writer: &mut W,
+  chrom: &str,
+  start: u64,
+  depth: &[u32],
+) -> io::Result<()> {
+  let mut run_start = 0;
+  for i in 1..=depth.len() {
+    if i == depth.len() || depth[i] != depth[run_start] {
+      writeln!(
+        writer,
+        "{}\t{}\t{}\t{}",
+        chrom,
+        start + run_start as u64,
+        start + i as u64,
+        depth[run_start]
+      )?;
+      run_start = i;
+    }
+  }
+
+  Ok(())
+}
+
+/// The regions within each of the merged `intervals` (see `bam_region::merge_regions`), as their
+/// index in `regions` and their bounds. The regions not in the header or empty are left out.
+fn group_regions(
+  header: &HeaderView,
+  regions: &[bam_region::Region],
+  intervals: &[(u32, u64, u64)],
+) -> Vec<Vec<(usize, u64, u64)>> {
+  let mut by_interval = vec![vec![]; intervals.len()];
+
+  for (idx, region) in regions.iter().enumerate() {
+    let (tid, start, end) = match region.resolve(header) {
+      Some((tid, start, end)) if start < end => (tid, start, end),
+      _ => continue,
+    };
+    let interval =
+      intervals.partition_point(|&(other, other_start, _)| (other, other_start) <= (tid, start));
+    if interval > 0 {
+      by_interval[interval - 1].push((idx, start, end));
+    }
+  }
+
+  by_interval
+}
+
+pub fn run(args: &Arguments) {
+  info!("{} - Compute coverage: {:?}", module_path!(), args.input);
+
+  if let Some(cigar) = &args.cigar {
+    if Expression::compile(cigar).is_none() {
+      error!("{} - Not valid expression: {:?}", module_path!(), cigar);
+      std::process::exit(exitcode::USAGE);
+    }
+  }
+
+  if Path::new(&args.input).exists() {
+    coverage(args);
+  } else {
+    error!("{} - Not Found: {:?}", module_path!(), args.input);
+    std::process::exit(exitcode::NOINPUT);
+  }
+}
+
+pub fn coverage(args: &Arguments) {
+  let mut reader =
+    bam_io::open_indexed_reader(&args.input, args.reference.as_deref(), args.n_threads).unwrap();
+  let header_view = reader.header().clone();
+  let regions = bam_region::read_bed(&args.bed).unwrap();
+  let expression = args
+    .cigar
+    .as_ref()
+    .map(|cigar| Expression::compile(cigar).unwrap());
+
+  let mut writer: Box<dyn Write> = match &args.output {
+    Some(output) => Box::new(BufWriter::new(File::create(output).unwrap())),
+    None => Box::new(io::stdout()),
+  };
+  let mut bedgraph = args
+    .bedgraph
+    .as_ref()
+    .map(|bedgraph| BufWriter::new(File::create(bedgraph).unwrap()));
+
+  let thresholds: Vec<String> = THRESHOLDS
+    .iter()
+    .map(|threshold| format!("pct_{}x", threshold))
+    .collect();
+  writeln!(
+    writer,
+    "chrom\tstart\tend\tmean_depth\tmedian_depth\t{}",
+    thresholds.join("\t")
+  )
+  .unwrap();
+
+  // The depth is computed once over the merged regions, so that overlapping regions do not give
+  // overlapping bedGraph lines. The summaries are written in the order of the bed file.
+  let intervals = bam_region::merge_regions(&header_view, &regions);
+  let by_interval = group_regions(&header_view, &regions, &intervals);
+  let mut lines: Vec<Option<String>> = vec![None; regions.len()];
+
+  for (&(tid, start, end), members) in intervals.iter().zip(&by_interval) {
+    let chrom = String::from_utf8_lossy(header_view.tid2name(tid)).into_owned();
+    let mut depth = vec![0u32; (end - start) as usize];
+    reader.fetch(tid, start, end).unwrap();
+    for record in reader.records() {
+      let record = record.unwrap();
+      if is_counted(&record, args.min_mapq, expression.as_ref()) {
+        add_depth(&mut depth, start as i64, &record);
+      }
+    }
+
+    if let Some(bedgraph) = bedgraph.as_mut() {
+      write_bedgraph(bedgraph, &chrom, start, &depth).unwrap();
+    }
+
+    for &(idx, region_start, region_end) in members {
+      let depth = &depth[(region_start - start) as usize..(region_end - start) as usize];
+      let summary = summarize(depth);
+      let covered: Vec<String> = summary
+        .covered
+        .iter()
+        .map(|value| format!("{:.2}", value))
+        .collect();
+      lines[idx] = Some(format!(
+        "{}\t{}\t{}\t{:.2}\t{}\t{}",
+        chrom,
+        region_start,
+        region_end,
+        summary.mean,
+        summary.median,
+        covered.join("\t")
+      ));
+    }
+  }
+
+  for line in lines.into_iter().flatten() {
+    writeln!(writer, "{}", line).unwrap();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rust_htslib::bam::record::{Cigar, CigarString};
+
+  fn read(pos: i64, cigar: Vec<Cigar>) -> Record {
+    let cigar = CigarString(cigar);
+    let len = cigar
+      .iter()
+      .filter(|op| matches!(op, Cigar::Match(_) | Cigar::Ins(_) | Cigar::SoftClip(_)))
+      .map(|op| op.len() as usize)
+      .sum();
+    let mut record = Record::new();
+    record.set(b"read", Some(&cigar), &vec![b'A'; len], &vec![30; len]);
+    record.set_pos(pos);
+    record
+  }
+
+  fn region(chrom: &str, start: u64, end: u64) -> bam_region::Region {
+    bam_region::Region {
+      chrom: String::from(chrom),
+      start,
+      end: Some(end),
+      whole_name: None,
+    }
+  }
+
+  #[test]
+  fn depth() {
+    let mut depth = vec![0; 10];
+    // Soft clips and insertions are not counted, deletions are skipped.
+    add_depth(
+      &mut depth,
+      100,
+      &read(
+        98,
+        vec![
+          Cigar::SoftClip(5),
+          Cigar::Match(4),
+          Cigar::Ins(2),
+          Cigar::Del(2),
+          Cigar::Match(3),
+        ],
+      ),
+    );
+    add_depth(&mut depth, 100, &read(108, vec![Cigar::Match(10)]));
+    assert_eq!(depth, vec![1, 1, 0, 0, 1, 1, 1, 0, 1, 1]);
+  }
+
+  #[test]
+  fn summary() {
+    let summary = summarize(&[0, 1, 1, 10, 30, 30, 100, 100]);
+    assert_eq!(summary.mean, 34.0);
+    assert_eq!(summary.median, 10);
+    assert_eq!(summary.covered, vec![87.5, 62.5, 50.0, 50.0, 25.0]);
+
+    let empty = summarize(&[]);
+    assert_eq!((empty.mean, empty.median), (0.0, 0));
+  }
+
+  #[test]
+  fn bedgraph() {
+    let mut bedgraph = vec![];
+    write_bedgraph(&mut bedgraph, "chr1", 100, &[0, 0, 3, 3, 3, 1]).unwrap();
+    assert_eq!(
+      String::from_utf8(bedgraph).unwrap(),
+      "chr1\t100\t102\t0\nchr1\t102\t105\t3\nchr1\t105\t106\t1\n"
+    );
+  }
+
+  #[test]
+  fn overlapping_regions() {
+    let header = HeaderView::from_bytes(b"@SQ\tSN:chr1\tLN:1000\n@SQ\tSN:chr2\tLN:1000\n");
+    let regions = vec![
+      region("chr1", 100, 200),
+      region("chr2", 0, 50),
+      region("chr1", 150, 300),
+      region("chr1", 120, 130),
+      region("chr3", 0, 10),
+    ];
+    let intervals = bam_region::merge_regions(&header, &regions);
+    assert_eq!(intervals, vec![(0, 100, 300), (1, 0, 50)]);
+    assert_eq!(
+      group_regions(&header, &regions, &intervals),
+      vec![
+        vec![(0, 100, 200), (2, 150, 300), (3, 120, 130)],
+        vec![(1, 0, 50)]
+      ]
+    );
+  }
+}
@@ -0,0 +1,361 @@
+// External
+use log::*;
+use rust_htslib::bam::record::Record;
+use rust_htslib::bam::{header, Format, HeaderView, Read};
+use structopt::StructOpt;
+
+// Standard
+use std::cmp::Ordering;
+use std::path::Path;
+
+// Custom
+extern crate bam_util;
+use bam_util::bam::region as bam_region;
+use bam_util::bam::util as bam_io;
+
+/// Downsample reads, keeping the mates of a pair together
+#[derive(StructOpt, PartialEq, Debug)]
+#[structopt(setting=structopt::clap::AppSettings::ColoredHelp, name="Omics Tool Suite - Bam Utility - downsample", author="Jingcheng Yang <yjcyxky@163.com>")]
+pub struct Arguments {
+  /// Bam file to process, must be coordinate-sorted and indexed with --target-depth
+  #[structopt(name = "FILE")]
+  input: String,
+
+  /// Output file, stdout if not set. A .bai/.csi index is built when the output is coordinate-sorted.
+  #[structopt(name = "output", short = "o", long = "output")]
+  output: Option<String>,
+
+  /// A format for output file.
+  #[structopt(name="format", short="O", long="format", possible_values=&["BAM", "SAM", "CRAM"], default_value="BAM")]
+  format: String,
+
+  /// Reference fasta file, required for reading or writing CRAM.
+  #[structopt(name = "reference", short = "r", long = "reference")]
+  reference: Option<String>,
+
+  /// Compression level of the output file, from 0 (uncompressed) to 9 (best).
+  #[structopt(name = "level", short = "l", long = "level", possible_values=&["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"])]
+  level: Option<u32>,
+
+  /// Fraction of the read pairs to keep, between 0 and 1
+  #[structopt(
+    name = "fraction",
+    short = "f",
+    long = "fraction",
+    required_unless = "target_depth",
+    conflicts_with = "target_depth"
+  )]
+  fraction: Option<f64>,
+
+  /// Downsample each region to this mean depth, regions below it are kept as they are.
+  /// Unmapped, secondary and supplementary reads and reads outside of the regions are dropped.
+  #[structopt(name = "target_depth", short = "d", long = "target-depth")]
+  target_depth: Option<f64>,
+
+  /// Regions for --target-depth, windows of --window bases over the whole genome if not set
+  #[structopt(name = "bed", long = "bed")]
+  bed: Option<String>,
+
+  /// Window size for --target-depth when no bed file is given
+  #[structopt(name = "window", long = "window", default_value = "1000000")]
+  window: u64,
+
+  /// Seed of the sampling, the same seed keeps the same reads
+  #[structopt(name = "seed", short = "s", long = "seed", default_value = "0")]
+  seed: u64,
+
+  /// Number of threads
+  #[structopt(
+    name = "n_threads",
+    short = "n",
+    long = "n_threads",
+    default_value = "1"
+  )]
+  n_threads: usize,
+}
+
+/// Map a read name and a seed to [0, 1), the same for both mates of a pair.
+fn qname_hash(qname: &[u8], seed: u64) -> f64 {
+  // FNV-1a, then the splitmix64 finalizer to spread the bits.
+  let mut hash: u64 = 0xcbf2_9ce4_8422_2325 ^ seed;
+  for &byte in qname {
+    hash ^= byte as u64;
+    hash = hash.wrapping_mul(0x0100_0000_01b3);
+  }
+
+  hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+  hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+  hash ^= hash >> 31;
+
+  (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Index of the interval containing the position, intervals must be sorted and non-overlapping.
+fn find_interval(intervals: &[(u32, u64, u64)], tid: i32, pos: i64) -> Option<usize> {
+  if tid < 0 || pos < 0 {
+    return None;
+  }
+
+  let (tid, pos) = (tid as u32, pos as u64);
+  intervals
+    .binary_search_by(|&(itid, start, end)| {
+      if (itid, end) <= (tid, pos) {
+        Ordering::Less
+      } else if (itid, start) > (tid, pos) {
+        Ordering::Greater
+      } else {
+        Ordering::Equal
+      }
+    })
+    .ok()
+}
+
+/// Windows tiling all the contigs of the header.
+fn windows(header: &HeaderView, window: u64) -> Vec<bam_region::Region> {
+  let window = window.max(1);
+  let mut regions = vec![];
+
+  for tid in 0..header.target_count() {
+    let chrom = String::from_utf8_lossy(header.tid2name(tid)).into_owned();
+    let target_len = header.target_len(tid).unwrap_or(0);
+    let mut start = 0;
+    while start < target_len {
+      regions.push(bam_region::Region {
+        chrom: chrom.clone(),
+        start,
+        end: Some((start + window).min(target_len)),
+        whole_name: None,
+      });
+      start += window;
+    }
+  }
+
+  regions
+}
+
+fn is_counted(record: &Record) -> bool {
+  !record.is_unmapped() && !record.is_secondary() && !record.is_supplementary()
+}
+
+pub fn run(args: &Arguments) {
+  info!("{} - Downsample: {:?}", module_path!(), args.input);
+
+  if let Some(fraction) = args.fraction {
+    if !(0.0..=1.0).contains(&fraction) {
+      error!(
+        "{} - Fraction must be between 0 and 1: {}",
+        module_path!(),
+        fraction
+      );
+      std::process::exit(exitcode::USAGE);
+    }
+  }
+
+  if args.format == "CRAM" && args.reference.is_none() {
+    error!(
+      "{} - CRAM output requires a reference (--reference).",
+      module_path!()
+    );
+    std::process::exit(exitcode::USAGE);
+  }
+
+  if Path::new(&args.input).exists() {
+    downsample(args);
+  } else {
+    error!("{} - Not Found: {:?}", module_path!(), args.input);
+    std::process::exit(exitcode::NOINPUT);
+  }
+}
+
+pub fn downsample(args: &Arguments) {
+  let format = bam_io::parse_format(&args.format);
+  let (header_view, kept, total) = match args.target_depth {
+    Some(target_depth) => downsample_to_depth(args, target_depth),
+    None => downsample_by_fraction(args, args.fraction.unwrap_or(1.0)),
+  };
+
+  info!("{} - {} of {} reads kept", module_path!(), kept, total);
+
+  if let Some(output) = &args.output {
+    if format != Format::SAM && bam_io::is_coordinate_sorted(&header_view) {
+      info!("{} - Build index for {:?}", module_path!(), output);
+      bam_io::build_index(output, &header_view, args.n_threads).unwrap();
+    }
+  }
+}
+
+/// Keep the reads whose name hashes below the fraction. Return the header, kept and total reads.
+fn downsample_by_fraction(args: &Arguments, fraction: f64) -> (HeaderView, u64, u64) {
+  let reference = args.reference.as_deref();
+  let mut reader = bam_io::open_reader(&args.input, reference, args.n_threads).unwrap();
+  let header_view = reader.header().clone();
+  let header = header::Header::from_template(&header_view);
+  let mut writer = bam_io::open_writer(
+    args.output.as_deref(),
+    &header,
+    bam_io::parse_format(&args.format),
+    reference,
+    args.level,
+    args.n_threads,
+  )
+  .unwrap();
+  let (mut kept, mut total) = (0, 0);
+
+  for record in reader.records() {
+    let record = record.unwrap();
+    total += 1;
+
+    if qname_hash(record.qname(), args.seed) < fraction {
+      writer.write(&record).unwrap();
+      kept += 1;
+    }
+  }
+
+  (header_view, kept, total)
+}
+
+/// Compute a fraction for each region from its mean depth, then keep the reads starting in a
+/// region with the fraction of the region. A pair whose mates start in different regions, or on
+/// different contigs, gets the lower fraction of the two regions on both mates, so that pairs stay
+/// together. Only the primary alignments of mapped reads are written, the secondary and
+/// supplementary ones start elsewhere than their primary and cannot follow its decision. Return
+/// the header, kept and total reads.
+fn downsample_to_depth(args: &Arguments, target_depth: f64) -> (HeaderView, u64, u64) {
+  let reference = args.reference.as_deref();
+  let mut reader = bam_io::open_indexed_reader(&args.input, reference, args.n_threads).unwrap();
+  let header_view = reader.header().clone();
+  let regions = match &args.bed {
+    Some(bed) => bam_region::read_bed(bed).unwrap(),
+    None => windows(&header_view, args.window),
+  };
+  let intervals = bam_region::merge_regions(&header_view, &regions);
+
+  // First pass: mean depth of the reads starting in each interval.
+  let fractions: Vec<f64> = intervals
+    .iter()
+    .map(|&(tid, start, end)| {
+      let mut bases = 0;
+      reader.fetch(tid, start, end).unwrap();
+      for record in reader.records() {
+        let record = record.unwrap();
+        if is_counted(&record) && record.pos() as u64 >= start {
+          bases += (record.cigar().end_pos() - record.pos()) as u64;
+        }
+      }
+
+      let depth = bases as f64 / (end - start) as f64;
+      if depth > target_depth {
+        target_depth / depth
+      } else {
+        1.0
+      }
+    })
+    .collect();
+
+  let header = header::Header::from_template(&header_view);
+  let mut writer = bam_io::open_writer(
+    args.output.as_deref(),
+    &header,
+    bam_io::parse_format(&args.format),
+    reference,
+    args.level,
+    args.n_threads,
+  )
+  .unwrap();
+  let (mut kept, mut total) = (0, 0);
+
+  // Second pass: sample the reads starting in each interval.
+  for (idx, &(tid, start, end)) in intervals.iter().enumerate() {
+    reader.fetch(tid, start, end).unwrap();
+    for record in reader.records() {
+      let record = record.unwrap();
+      if (record.pos() as u64) < start {
+        continue;
+      }
+      total += 1;
+
+      if !is_counted(&record) {
+        continue;
+      }
+
+      // Both mates compute the same fraction from the two regions, whichever comes first.
+      let fraction = if record.is_paired() {
+        find_interval(&intervals, record.mtid(), record.mpos())
+          .map_or(fractions[idx], |i| fractions[idx].min(fractions[i]))
+      } else {
+        fractions[idx]
+      };
+
+      if qname_hash(record.qname(), args.seed) < fraction {
+        writer.write(&record).unwrap();
+        kept += 1;
+      }
+    }
+  }
+
+  (header_view, kept, total)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn qname_hash_range() {
+    for qname in &[&b"read1"[..], b"read2", b"", b"SRR000001.1"] {
+      let value = qname_hash(qname, 0);
+      assert!((0.0..1.0).contains(&value));
+      assert_eq!(value, qname_hash(qname, 0));
+    }
+
+    assert_ne!(qname_hash(b"read1", 0), qname_hash(b"read1", 1));
+    assert_ne!(qname_hash(b"read1", 0), qname_hash(b"read2", 0));
+  }
+
+  #[test]
+  fn qname_hash_fraction() {
+    let kept = (0..10000)
+      .filter(|i| qname_hash(format!("read{}", i).as_bytes(), 7) < 0.25)
+      .count();
+    assert!((2250..2750).contains(&kept), "{}", kept);
+  }
+
+  #[test]
+  fn find_interval_bounds() {
+    let intervals = vec![(0, 100, 200), (0, 300, 400), (1, 0, 50)];
+
+    assert_eq!(find_interval(&intervals, 0, 99), None);
+    assert_eq!(find_interval(&intervals, 0, 100), Some(0));
+    assert_eq!(find_interval(&intervals, 0, 199), Some(0));
+    assert_eq!(find_interval(&intervals, 0, 200), None);
+    assert_eq!(find_interval(&intervals, 0, 350), Some(1));
+    assert_eq!(find_interval(&intervals, 1, 0), Some(2));
+    assert_eq!(find_interval(&intervals, 1, 50), None);
+    assert_eq!(find_interval(&intervals, 2, 10), None);
+    assert_eq!(find_interval(&intervals, -1, 10), None);
+    assert_eq!(find_interval(&intervals, 0, -1), None);
+    assert_eq!(find_interval(&[], 0, 10), None);
+  }
+
+  #[test]
+  fn windows_tiling() {
+    let header = HeaderView::from_bytes(b"@SQ\tSN:chr1\tLN:250\n@SQ\tSN:chr2\tLN:100\n");
+    let regions: Vec<(String, u64, Option<u64>)> = windows(&header, 100)
+      .into_iter()
+      .map(|region| (region.chrom, region.start, region.end))
+      .collect();
+
+    assert_eq!(
+      regions,
+      vec![
+        (String::from("chr1"), 0, Some(100)),
+        (String::from("chr1"), 100, Some(200)),
+        (String::from("chr1"), 200, Some(250)),
+        (String::from("chr2"), 0, Some(100)),
+      ]
+    );
+
+    // A zero window is widened to one base instead of looping forever.
+    assert_eq!(windows(&header, 0).len(), 350);
+  }
+}
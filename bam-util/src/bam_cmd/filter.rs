@@ -1,14 +1,26 @@
 // External
 use log::*;
-use rust_htslib::bam::{header, Format, Read, Reader, Writer};
+use rust_htslib::bam::record::{Aux, Record};
+use rust_htslib::bam::{header, Format, HeaderView, Read, Writer};
 use structopt::StructOpt;
 
 // Standard
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::fs;
 use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
 
 // Custom
 extern crate bam_util;
 use bam_util::bam::cigar as bam_cigar;
+use bam_util::bam::cigar::Expression;
+use bam_util::bam::filter::{PairBuffer, PairMode};
+use bam_util::bam::progress::Progress;
+use bam_util::bam::region as bam_region;
+use bam_util::bam::util as bam_io;
 
 /// Filter Bam file by some flags or indicators
 #[derive(StructOpt, PartialEq, Debug)]
@@ -18,15 +30,52 @@ pub struct Arguments {
   #[structopt(name = "FILE")]
   input: String,
 
+  /// Only filter the reads overlapping the region, e.g. chr1:1-1000 (requires an index, can be repeated)
+  #[structopt(name = "region", long = "region", number_of_values = 1)]
+  regions: Vec<bam_region::Region>,
+
+  /// Only filter the reads overlapping the regions of the bed file (requires an index)
+  #[structopt(name = "bed", long = "bed")]
+  bed: Option<String>,
+
+  /// Output file, stdout if not set. A .bai/.csi index is built when the output is coordinate-sorted.
+  #[structopt(name = "output", short = "o", long = "output")]
+  output: Option<String>,
+
+  /// Output file for the reads which don't pass the filter, same format as the output.
+  #[structopt(name = "rejected", long = "rejected")]
+  rejected: Option<String>,
+
   /// A format for output file.
-  #[structopt(name="format", short="O", long="format", possible_values=&["BAM", "SAM"], default_value="BAM")]
+  #[structopt(name="format", short="O", long="format", possible_values=&["BAM", "SAM", "CRAM"], default_value="BAM")]
   format: String,
 
-  /// A filtered expression for cigar. e.g. each(S) > 100
+  /// Reference fasta file, required for reading or writing CRAM.
+  #[structopt(name = "reference", short = "r", long = "reference")]
+  reference: Option<String>,
+
+  /// Compression level of the output file, from 0 (uncompressed) to 9 (best).
+  #[structopt(name = "level", short = "l", long = "level", possible_values=&["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"])]
+  level: Option<u32>,
+
+  /// A filtered expression for cigar. e.g. each(S) > 100, any(I) > 5 && leading(S) < 20, ref_len >= 100
+  ///
+  /// Functions: each, any, sum, sum_ratio, max, min, count, leading, trailing over M/I/D/N/S/H/P/=/X, and query_len, ref_len
   #[structopt(name = "cigar", short = "c", long = "cigar")]
   cigar: String,
 
-  /// Number of threads
+  /// How the mates of a pair are filtered: any (each read alone), both (keep the pair if both
+  /// mates pass) or either (keep the pair if one mate passes).
+  ///
+  /// Pairs are kept or dropped together, the input must be coordinate-sorted or grouped by read name.
+  /// The sort order is read from the @HD line, the mates are expected next to each other (with a
+  /// warning) when it is neither coordinate nor queryname.
+  /// The reads are written as soon as their pair is decided, so the output is unsorted and not
+  /// indexed in the both and either modes.
+  #[structopt(name="pair_mode", long="pair-mode", possible_values=&["any", "both", "either"], default_value="any")]
+  pair_mode: PairMode,
+
+  /// Number of threads, used for BGZF compression and for evaluating the expression
   #[structopt(
     name = "n_threads",
     short = "n",
@@ -36,45 +85,469 @@ pub struct Arguments {
   n_threads: usize,
 }
 
+/// Number of passed and failed reads.
+#[derive(Debug, Default)]
+struct Counter {
+  passed: u64,
+  failed: u64,
+}
+
+impl Counter {
+  fn add(&mut self, passed: bool) {
+    if passed {
+      self.passed += 1;
+    } else {
+      self.failed += 1;
+    }
+  }
+}
+
+fn log_summary<'a, I: IntoIterator<Item = (String, &'a Counter)>>(name: &str, counters: I) {
+  for (key, counter) in counters {
+    info!(
+      "{} - Summary by {} {}: {} passed, {} failed",
+      module_path!(),
+      name,
+      key,
+      counter.passed,
+      counter.failed
+    );
+  }
+}
+
 pub fn run(args: &Arguments) {
   info!("{} - Cigar Expression: {:?}", module_path!(), args.cigar);
 
+  if args.format == "CRAM" && args.reference.is_none() {
+    error!(
+      "{} - CRAM output requires a reference (--reference).",
+      module_path!()
+    );
+    std::process::exit(exitcode::USAGE);
+  }
+
   if Path::new(&args.input).exists() {
-    filter(&args.input, &args.cigar, &args.format, args.n_threads);
+    filter(args);
   } else {
     error!("{} - Not Found: {:?}", module_path!(), args.input);
+    std::process::exit(exitcode::NOINPUT);
   }
 }
 
-pub fn filter(inputpath: &str, cigar_exp: &str, format: &str, n_threads: usize) {
-  let mut reader = Reader::from_path(inputpath).unwrap();
-  let header = header::Header::from_template(reader.header());
-  let format = if format == "BAM" {
-    Format::BAM
-  } else {
-    Format::SAM
-  };
-  let mut writer = Writer::from_stdout(&header, format).unwrap();
+/// Passing/rejected writers and the pass/fail counters of a filter run.
+struct Outputs {
+  header_view: HeaderView,
+  format: Format,
+  writer: Writer,
+  rejected_writer: Option<Writer>,
+  // By tid, the unmapped reads without a reference last. The names are only resolved for the
+  // summary, not for each record.
+  by_reference: Vec<Counter>,
+  // By RG value, `*` without RG.
+  by_read_group: HashMap<Vec<u8>, Counter>,
+}
 
-  reader.set_threads(n_threads).unwrap();
-  writer.set_threads(n_threads).unwrap();
+impl Outputs {
+  fn new(args: &Arguments, header_view: &HeaderView) -> Self {
+    let reference = args.reference.as_deref();
+    // The mates of a pair are written together, out of the input order.
+    let header_view = if args.pair_mode == PairMode::Any {
+      header_view.clone()
+    } else {
+      bam_io::with_sort_order(header_view, "unsorted")
+    };
+    let header = header::Header::from_template(&header_view);
+    let format = bam_io::parse_format(&args.format);
+    let writer = bam_io::open_writer(
+      args.output.as_deref(),
+      &header,
+      format,
+      reference,
+      args.level,
+      args.n_threads,
+    )
+    .unwrap();
+    let rejected_writer = args.rejected.as_deref().map(|rejected| {
+      bam_io::open_writer(
+        Some(rejected),
+        &header,
+        format,
+        reference,
+        args.level,
+        args.n_threads,
+      )
+      .unwrap()
+    });
 
-  bam_cigar::check_expr(cigar_exp);
+    let by_reference = (0..=header_view.target_count())
+      .map(|_| Counter::default())
+      .collect();
 
-  for record in reader.records() {
-    let record = record.unwrap();
-    let cigar = record.cigar();
-    let results = bam_cigar::exec(&cigar, cigar_exp);
+    Outputs {
+      header_view,
+      format,
+      writer,
+      rejected_writer,
+      by_reference,
+      by_read_group: HashMap::new(),
+    }
+  }
 
-    debug!(
-      "{} - Cigar Expression Results: {:?} {:?}",
-      module_path!(),
-      std::str::from_utf8(record.qname()).unwrap(),
+  fn write(&mut self, record: &Record, passed: bool) {
+    let unmapped = self.by_reference.len() - 1;
+    let tid = usize::try_from(record.tid()).map_or(unmapped, |tid| tid.min(unmapped));
+    self.by_reference[tid].add(passed);
+
+    match record.aux(b"RG") {
+      Some(Aux::String(read_group)) => self.add_read_group(read_group, passed),
+      Some(_) => {
+        let read_group = bam_io::read_group(record).unwrap_or_default();
+        self.add_read_group(read_group.as_bytes(), passed);
+      }
+      None => self.add_read_group(b"*", passed),
+    }
+
+    if passed {
+      self.writer.write(record).unwrap();
+    } else if let Some(rejected_writer) = self.rejected_writer.as_mut() {
+      rejected_writer.write(record).unwrap();
+    }
+  }
+
+  /// Count a record of `read_group`, its key is only allocated for the first record.
+  fn add_read_group(&mut self, read_group: &[u8], passed: bool) {
+    match self.by_read_group.get_mut(read_group) {
+      Some(counter) => counter.add(passed),
+      None => {
+        let mut counter = Counter::default();
+        counter.add(passed);
+        self.by_read_group.insert(read_group.to_vec(), counter);
+      }
+    }
+  }
+
+  fn finish(self, args: &Arguments) {
+    let Outputs {
+      header_view,
+      format,
+      writer,
+      rejected_writer,
+      by_reference,
+      by_read_group,
+    } = self;
+
+    // The writers must be flushed and closed before indexing.
+    drop(writer);
+    drop(rejected_writer);
+
+    let unmapped = by_reference.len() - 1;
+    let by_reference = by_reference
+      .iter()
+      .enumerate()
+      .filter(|(_, counter)| counter.passed + counter.failed > 0)
+      .map(|(tid, counter)| {
+        let name = if tid == unmapped {
+          String::from("*")
+        } else {
+          String::from_utf8_lossy(header_view.tid2name(tid as u32)).into_owned()
+        };
+        (name, counter)
+      });
+    log_summary("reference", by_reference);
+    let by_read_group = by_read_group
+      .iter()
+      .map(|(read_group, counter)| (String::from_utf8_lossy(read_group).into_owned(), counter))
+      .collect::<BTreeMap<_, _>>();
+    log_summary("read group", by_read_group);
+
+    for path in args.output.iter().chain(args.rejected.iter()) {
+      if format != Format::SAM && bam_io::is_coordinate_sorted(&header_view) {
+        info!("{} - Build index for {:?}", module_path!(), path);
+        bam_io::build_index(path, &header_view, args.n_threads).unwrap();
+      }
+    }
+  }
+}
+
+/// Number of records evaluated by a worker at once.
+const CHUNK_SIZE: usize = 4096;
+
+/// Raw cigars of a chunk, one after the other, decoded by the workers.
+struct Job {
+  id: usize,
+  positions: Vec<i64>,
+  // End of the cigar of each record in `raw`.
+  ends: Vec<usize>,
+  raw: Vec<u32>,
+}
+
+impl Job {
+  fn new(id: usize, chunk: &[Record]) -> Self {
+    let mut job = Job {
+      id,
+      positions: Vec::with_capacity(chunk.len()),
+      ends: Vec::with_capacity(chunk.len()),
+      raw: vec![],
+    };
+
+    for record in chunk {
+      job.raw.extend_from_slice(record.raw_cigar());
+      job.positions.push(record.pos());
+      job.ends.push(job.raw.len());
+    }
+    job
+  }
+
+  fn eval(&self, expression: &Expression) -> Vec<bool> {
+    let starts = std::iter::once(0).chain(self.ends.iter().cloned());
+    starts
+      .zip(&self.ends)
+      .zip(&self.positions)
+      // htslib does not read a record with an unknown operation, it would fail the expression.
+      .map(|((start, &end), &pos)| {
+        bam_cigar::decode_raw(&self.raw[start..end], pos)
+          .map_or(false, |cigar| expression.eval(&cigar))
+      })
+      .collect()
+  }
+}
+
+/// Evaluates the cigar expression on a pool of worker threads, and hands the records over to the
+/// pair buffer in input order. Records stay on the reading thread, only their raw cigars are sent
+/// and decoded by the workers.
+struct Evaluator {
+  expression: Arc<Expression>,
+  chunk: Vec<Record>,
+  // Chunks sent to the workers, in input order.
+  in_flight: VecDeque<Vec<Record>>,
+  // Results received ahead of the front chunk, by chunk id.
+  ready: BTreeMap<usize, Vec<bool>>,
+  next_id: usize,
+  front_id: usize,
+  max_in_flight: usize,
+  jobs: Option<Sender<Job>>,
+  results: Receiver<(usize, Vec<bool>)>,
+  workers: Vec<JoinHandle<()>>,
+}
+
+impl Evaluator {
+  /// Evaluate on the current thread if `n_workers` is 1 or less.
+  fn new(expression: Expression, n_workers: usize) -> Self {
+    let expression = Arc::new(expression);
+    let (jobs, job_receiver) = channel::<Job>();
+    let (result_sender, results) = channel();
+    let job_receiver = Arc::new(Mutex::new(job_receiver));
+    let n_workers = if n_workers > 1 { n_workers } else { 0 };
+
+    let workers = (0..n_workers)
+      .map(|_| {
+        let job_receiver = Arc::clone(&job_receiver);
+        let result_sender = result_sender.clone();
+        let expression = Arc::clone(&expression);
+
+        thread::spawn(move || loop {
+          let job = job_receiver.lock().unwrap().recv();
+          match job {
+            Ok(job) => {
+              let passed = job.eval(&expression);
+              if result_sender.send((job.id, passed)).is_err() {
+                break;
+              }
+            }
+            Err(_) => break,
+          }
+        })
+      })
+      .collect();
+
+    Evaluator {
+      expression,
+      chunk: Vec::with_capacity(CHUNK_SIZE),
+      in_flight: VecDeque::new(),
+      ready: BTreeMap::new(),
+      next_id: 0,
+      front_id: 0,
+      max_in_flight: 2 * n_workers,
+      jobs: Some(jobs),
       results,
-    );
+      workers,
+    }
+  }
+
+  fn push(&mut self, record: Record, pairs: &mut PairBuffer, outputs: &mut Outputs) {
+    if self.workers.is_empty() {
+      let passed = self.expression.eval(&record.cigar());
+
+      debug!(
+        "{} - Cigar Expression Results: {:?} {:?}",
+        module_path!(),
+        std::str::from_utf8(record.qname()).unwrap(),
+        passed,
+      );
+
+      pairs.push(record, passed, &mut |record, passed| {
+        outputs.write(&record, passed)
+      });
+      return;
+    }
+
+    self.chunk.push(record);
+    if self.chunk.len() >= CHUNK_SIZE {
+      self.dispatch();
 
-    if results {
-      writer.write(&record).unwrap();
+      while self.in_flight.len() >= self.max_in_flight {
+        self.receive(pairs, outputs);
+      }
     }
   }
+
+  fn dispatch(&mut self) {
+    let chunk = std::mem::replace(&mut self.chunk, Vec::with_capacity(CHUNK_SIZE));
+    let job = Job::new(self.next_id, &chunk);
+
+    self.jobs.as_ref().unwrap().send(job).unwrap();
+    self.in_flight.push_back(chunk);
+    self.next_id += 1;
+  }
+
+  /// Wait for one result, then release the chunks which are complete in input order.
+  fn receive(&mut self, pairs: &mut PairBuffer, outputs: &mut Outputs) {
+    let (id, passed) = self.results.recv().unwrap();
+    self.ready.insert(id, passed);
+
+    while let Some(passed) = self.ready.remove(&self.front_id) {
+      let chunk = self.in_flight.pop_front().unwrap();
+      for (record, passed) in chunk.into_iter().zip(passed) {
+        pairs.push(record, passed, &mut |record, passed| {
+          outputs.write(&record, passed)
+        });
+      }
+      self.front_id += 1;
+    }
+  }
+
+  fn finish(mut self, pairs: &mut PairBuffer, outputs: &mut Outputs) {
+    if !self.chunk.is_empty() {
+      self.dispatch();
+    }
+
+    while !self.in_flight.is_empty() {
+      self.receive(pairs, outputs);
+    }
+
+    // Closing the job channel stops the workers.
+    self.jobs = None;
+    for worker in self.workers.drain(..) {
+      worker.join().unwrap();
+    }
+  }
+}
+
+pub fn filter(args: &Arguments) {
+  let reference = args.reference.as_deref();
+  let mut regions = args.regions.clone();
+  if let Some(bed) = &args.bed {
+    regions.extend(bam_region::read_bed(bed).unwrap());
+  }
+
+  let expression = match Expression::compile(&args.cigar) {
+    Some(expression) => expression,
+    None => {
+      error!(
+        "{} - Not valid expression: {:?}",
+        module_path!(),
+        args.cigar
+      );
+      std::process::exit(exitcode::USAGE);
+    }
+  };
+  let mut evaluator = Evaluator::new(expression, args.n_threads);
+
+  if regions.is_empty() {
+    let mut reader = bam_io::open_reader(&args.input, reference, args.n_threads).unwrap();
+    let mut outputs = Outputs::new(args, reader.header());
+    let coordinate_sorted = bam_io::is_coordinate_sorted(reader.header());
+    if args.pair_mode != PairMode::Any
+      && !coordinate_sorted
+      && !bam_io::is_grouped_by_name(reader.header())
+    {
+      warn!(
+        "{} - The header declares neither SO:coordinate nor SO:queryname, the mates are expected \
+         next to each other. Sort {:?} to pair the others.",
+        module_path!(),
+        args.input
+      );
+    }
+    let mut pairs = PairBuffer::new(args.pair_mode, coordinate_sorted);
+    // The offset in the file is only known for BGZF files, not for CRAM.
+    let total_bytes = bam_io::compressed_offset(&reader)
+      .and(fs::metadata(&args.input).ok())
+      .map(|metadata| metadata.len());
+    let mut progress = Progress::new("filter", total_bytes);
+
+    loop {
+      let mut record = Record::new();
+      match reader.read(&mut record) {
+        Some(result) => result.unwrap(),
+        None => break,
+      }
+
+      progress.tick(
+        || bam_io::compressed_offset(&reader),
+        || bam_io::reference_name(reader.header(), &record),
+      );
+      evaluator.push(record, &mut pairs, &mut outputs);
+    }
+
+    progress.finish();
+    evaluator.finish(&mut pairs, &mut outputs);
+    pairs.finish(&mut |record, passed| outputs.write(&record, passed));
+    outputs.finish(args);
+  } else {
+    let mut reader = bam_io::open_indexed_reader(&args.input, reference, args.n_threads).unwrap();
+    let mut outputs = Outputs::new(args, reader.header());
+    let mut pairs = PairBuffer::new(args.pair_mode, true);
+    let intervals = bam_region::merge_regions(reader.header(), &regions);
+    let mut last: Option<(u32, u64)> = None;
+    // The size of the regions is not known.
+    let mut progress = Progress::new("filter", None);
+
+    for (tid, start, end) in intervals {
+      info!(
+        "{} - Fetch region: {}:{}-{}",
+        module_path!(),
+        String::from_utf8_lossy(reader.header().tid2name(tid)),
+        start + 1,
+        end
+      );
+      reader.fetch(tid, start, end).unwrap();
+
+      loop {
+        let mut record = Record::new();
+        match reader.read(&mut record) {
+          Some(result) => result.unwrap(),
+          None => break,
+        }
+
+        progress.tick(|| None, || bam_io::reference_name(reader.header(), &record));
+
+        // Intervals are sorted and merged, so a read starting before the end of the previous
+        // interval on the same contig overlaps it and has been written already.
+        if let Some((last_tid, last_end)) = last {
+          if last_tid == tid && (record.pos() as u64) < last_end {
+            continue;
+          }
+        }
+
+        evaluator.push(record, &mut pairs, &mut outputs);
+      }
+
+      last = Some((tid, end));
+    }
+
+    progress.finish();
+    evaluator.finish(&mut pairs, &mut outputs);
+    pairs.finish(&mut |record, passed| outputs.write(&record, passed));
+    outputs.finish(args);
+  }
 }
@@ -0,0 +1,351 @@
+// External
+use log::*;
+use rust_htslib::bam::record::Record;
+use rust_htslib::bam::Read;
+use serde_json::{json, Map, Value};
+use structopt::StructOpt;
+
+// Standard
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+// Custom
+extern crate bam_util;
+use bam_util::bam::util as bam_io;
+
+/// Count reads by flag like samtools flagstat, overall, by read group and by library
+#[derive(StructOpt, PartialEq, Debug)]
+#[structopt(setting=structopt::clap::AppSettings::ColoredHelp, name="Omics Tool Suite - Bam Utility - flagstat", author="Jingcheng Yang <yjcyxky@163.com>")]
+pub struct Arguments {
+  /// Bam file to process
+  #[structopt(name = "FILE")]
+  input: String,
+
+  /// Output file, stdout if not set.
+  #[structopt(name = "output", short = "o", long = "output")]
+  output: Option<String>,
+
+  /// A format for output file, TEXT follows the layout of samtools flagstat.
+  #[structopt(name="format", short="O", long="format", possible_values=&["TEXT", "JSON"], default_value="TEXT")]
+  format: String,
+
+  /// Reference fasta file, required for reading CRAM.
+  #[structopt(name = "reference", short = "r", long = "reference")]
+  reference: Option<String>,
+
+  /// Number of threads
+  #[structopt(
+    name = "n_threads",
+    short = "n",
+    long = "n_threads",
+    default_value = "1"
+  )]
+  n_threads: usize,
+}
+
+/// Flag counts, index 0 for QC-passed reads and 1 for QC-failed reads.
+#[derive(Debug, Default, Clone)]
+pub struct FlagStat {
+  total: [u64; 2],
+  secondary: [u64; 2],
+  supplementary: [u64; 2],
+  duplicates: [u64; 2],
+  mapped: [u64; 2],
+  paired: [u64; 2],
+  read1: [u64; 2],
+  read2: [u64; 2],
+  properly_paired: [u64; 2],
+  both_mapped: [u64; 2],
+  singletons: [u64; 2],
+  mate_diff_chr: [u64; 2],
+  mate_diff_chr_mapq5: [u64; 2],
+}
+
+fn percent(count: u64, total: u64) -> Option<f64> {
+  if total == 0 {
+    None
+  } else {
+    Some(count as f64 / total as f64 * 100.0)
+  }
+}
+
+fn format_percent(count: u64, total: u64) -> String {
+  match percent(count, total) {
+    Some(value) => format!("{:.2}%", value),
+    None => String::from("N/A"),
+  }
+}
+
+impl FlagStat {
+  /// Same rules as samtools flagstat.
+  pub fn add(&mut self, record: &Record) {
+    let i = if record.is_quality_check_failed() {
+      1
+    } else {
+      0
+    };
+    self.total[i] += 1;
+
+    if record.is_secondary() {
+      self.secondary[i] += 1;
+    } else if record.is_supplementary() {
+      self.supplementary[i] += 1;
+    } else if record.is_paired() {
+      self.paired[i] += 1;
+
+      if record.is_proper_pair() && !record.is_unmapped() {
+        self.properly_paired[i] += 1;
+      }
+      if record.is_first_in_template() {
+        self.read1[i] += 1;
+      }
+      if record.is_last_in_template() {
+        self.read2[i] += 1;
+      }
+      if !record.is_unmapped() && !record.is_mate_unmapped() {
+        self.both_mapped[i] += 1;
+        if record.mtid() != record.tid() {
+          self.mate_diff_chr[i] += 1;
+          if record.mapq() >= 5 {
+            self.mate_diff_chr_mapq5[i] += 1;
+          }
+        }
+      }
+      if !record.is_unmapped() && record.is_mate_unmapped() {
+        self.singletons[i] += 1;
+      }
+    }
+
+    if !record.is_unmapped() {
+      self.mapped[i] += 1;
+    }
+    if record.is_duplicate() {
+      self.duplicates[i] += 1;
+    }
+  }
+
+  pub fn write_text<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+    let line = |writer: &mut W, counts: &[u64; 2], name: &str| {
+      writeln!(writer, "{} + {} {}", counts[0], counts[1], name)
+    };
+    let line_with_percent = |writer: &mut W, counts: &[u64; 2], total: &[u64; 2], name: &str| {
+      writeln!(
+        writer,
+        "{} + {} {} ({} : {})",
+        counts[0],
+        counts[1],
+        name,
+        format_percent(counts[0], total[0]),
+        format_percent(counts[1], total[1])
+      )
+    };
+
+    line(
+      writer,
+      &self.total,
+      "in total (QC-passed reads + QC-failed reads)",
+    )?;
+    line(writer, &self.secondary, "secondary")?;
+    line(writer, &self.supplementary, "supplementary")?;
+    line(writer, &self.duplicates, "duplicates")?;
+    line_with_percent(writer, &self.mapped, &self.total, "mapped")?;
+    line(writer, &self.paired, "paired in sequencing")?;
+    line(writer, &self.read1, "read1")?;
+    line(writer, &self.read2, "read2")?;
+    line_with_percent(
+      writer,
+      &self.properly_paired,
+      &self.paired,
+      "properly paired",
+    )?;
+    line(writer, &self.both_mapped, "with itself and mate mapped")?;
+    line_with_percent(writer, &self.singletons, &self.paired, "singletons")?;
+    line(
+      writer,
+      &self.mate_diff_chr,
+      "with mate mapped to a different chr",
+    )?;
+    line(
+      writer,
+      &self.mate_diff_chr_mapq5,
+      "with mate mapped to a different chr (mapQ>=5)",
+    )
+  }
+
+  /// Same keys as `samtools flagstat -O json`.
+  pub fn to_json(&self) -> Value {
+    let section = |i: usize| {
+      json!({
+        "total": self.total[i],
+        "secondary": self.secondary[i],
+        "supplementary": self.supplementary[i],
+        "duplicates": self.duplicates[i],
+        "mapped": self.mapped[i],
+        "mapped %": percent(self.mapped[i], self.total[i]),
+        "paired in sequencing": self.paired[i],
+        "read1": self.read1[i],
+        "read2": self.read2[i],
+        "properly paired": self.properly_paired[i],
+        "properly paired %": percent(self.properly_paired[i], self.paired[i]),
+        "with itself and mate mapped": self.both_mapped[i],
+        "singletons": self.singletons[i],
+        "singletons %": percent(self.singletons[i], self.paired[i]),
+        "with mate mapped to a different chr": self.mate_diff_chr[i],
+        "with mate mapped to a different chr (mapQ >= 5)": self.mate_diff_chr_mapq5[i],
+      })
+    };
+
+    json!({
+      "QC-passed reads": section(0),
+      "QC-failed reads": section(1),
+    })
+  }
+}
+
+fn groups_to_json(groups: &BTreeMap<String, FlagStat>) -> Value {
+  let map: Map<String, Value> = groups
+    .iter()
+    .map(|(name, flagstat)| (name.clone(), flagstat.to_json()))
+    .collect();
+  Value::Object(map)
+}
+
+pub fn run(args: &Arguments) {
+  info!("{} - Count flags: {:?}", module_path!(), args.input);
+
+  if Path::new(&args.input).exists() {
+    flagstat(args);
+  } else {
+    error!("{} - Not Found: {:?}", module_path!(), args.input);
+    std::process::exit(exitcode::NOINPUT);
+  }
+}
+
+pub fn flagstat(args: &Arguments) {
+  let mut reader =
+    bam_io::open_reader(&args.input, args.reference.as_deref(), args.n_threads).unwrap();
+  let libraries = bam_io::read_group_libraries(reader.header());
+  let mut all = FlagStat::default();
+  let mut by_read_group: BTreeMap<String, FlagStat> = BTreeMap::new();
+  let mut by_library: BTreeMap<String, FlagStat> = BTreeMap::new();
+
+  for record in reader.records() {
+    let record = record.unwrap();
+    let read_group = bam_io::read_group(&record).unwrap_or_else(|| String::from("*"));
+    let library = libraries
+      .get(&read_group)
+      .cloned()
+      .unwrap_or_else(|| String::from("*"));
+
+    all.add(&record);
+    by_read_group.entry(read_group).or_default().add(&record);
+    by_library.entry(library).or_default().add(&record);
+  }
+
+  let mut writer: Box<dyn Write> = match &args.output {
+    Some(output) => Box::new(File::create(output).unwrap()),
+    None => Box::new(io::stdout()),
+  };
+
+  if args.format == "JSON" {
+    let report = json!({
+      "all": all.to_json(),
+      "read_groups": groups_to_json(&by_read_group),
+      "libraries": groups_to_json(&by_library),
+    });
+    serde_json::to_writer_pretty(&mut writer, &report).unwrap();
+    writeln!(writer).unwrap();
+  } else {
+    all.write_text(&mut writer).unwrap();
+
+    for (name, groups) in &[("Read group", &by_read_group), ("Library", &by_library)] {
+      for (key, flagstat) in groups.iter() {
+        writeln!(writer, "\n# {}: {}", name, key).unwrap();
+        flagstat.write_text(&mut writer).unwrap();
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn record(flags: u16, tid: i32, mtid: i32, mapq: u8) -> Record {
+    let mut record = Record::new();
+    record.set_flags(flags);
+    record.set_tid(tid);
+    record.set_mtid(mtid);
+    record.set_mapq(mapq);
+    record
+  }
+
+  /// Records with the expected samtools flagstat counts.
+  fn flagstat() -> FlagStat {
+    let mut flagstat = FlagStat::default();
+    let records = [
+      // A proper pair on the same chromosome.
+      record(0x1 | 0x2 | 0x40, 0, 0, 60),
+      record(0x1 | 0x2 | 0x80, 0, 0, 60),
+      // Mates on different chromosomes, one of them with MAPQ < 5.
+      record(0x1 | 0x40, 0, 1, 3),
+      record(0x1 | 0x80, 1, 0, 30),
+      // A singleton and its unmapped mate, flagged proper pair but not counted as such.
+      record(0x1 | 0x2 | 0x8 | 0x40, 0, 0, 60),
+      record(0x1 | 0x2 | 0x4 | 0x80, 0, 0, 0),
+      // Secondary and supplementary alignments are only counted as mapped.
+      record(0x1 | 0x100 | 0x40, 0, 0, 0),
+      record(0x1 | 0x800 | 0x80, 0, 0, 60),
+      // A QC-failed duplicate of a single-end read.
+      record(0x200 | 0x400, 0, -1, 60),
+    ];
+    for record in records.iter() {
+      flagstat.add(record);
+    }
+    flagstat
+  }
+
+  #[test]
+  fn add() {
+    let flagstat = flagstat();
+    assert_eq!(flagstat.total, [8, 1]);
+    assert_eq!(flagstat.secondary, [1, 0]);
+    assert_eq!(flagstat.supplementary, [1, 0]);
+    assert_eq!(flagstat.duplicates, [0, 1]);
+    assert_eq!(flagstat.mapped, [7, 1]);
+    assert_eq!(flagstat.paired, [6, 0]);
+    assert_eq!(flagstat.read1, [3, 0]);
+    assert_eq!(flagstat.read2, [3, 0]);
+    assert_eq!(flagstat.properly_paired, [3, 0]);
+    assert_eq!(flagstat.both_mapped, [4, 0]);
+    assert_eq!(flagstat.singletons, [1, 0]);
+    assert_eq!(flagstat.mate_diff_chr, [2, 0]);
+    assert_eq!(flagstat.mate_diff_chr_mapq5, [1, 0]);
+  }
+
+  #[test]
+  fn text_and_json() {
+    let mut text = vec![];
+    flagstat().write_text(&mut text).unwrap();
+    let text = String::from_utf8(text).unwrap();
+    let lines = text.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 13);
+    assert_eq!(
+      lines[0],
+      "8 + 1 in total (QC-passed reads + QC-failed reads)"
+    );
+    assert_eq!(lines[4], "7 + 1 mapped (87.50% : 100.00%)");
+    assert_eq!(lines[8], "3 + 0 properly paired (50.00% : N/A)");
+    assert_eq!(
+      lines[12],
+      "1 + 0 with mate mapped to a different chr (mapQ>=5)"
+    );
+
+    let json = flagstat().to_json();
+    assert_eq!(json["QC-passed reads"]["singletons"], json!(1));
+    assert_eq!(json["QC-failed reads"]["properly paired %"], Value::Null);
+    assert_eq!(json["QC-failed reads"]["mapped %"], json!(100.0));
+  }
+}
@@ -0,0 +1,343 @@
+// External
+use log::*;
+use rust_htslib::bam::record::Record;
+use rust_htslib::bam::Read;
+use serde_json::{json, Map, Value};
+use structopt::StructOpt;
+
+// Standard
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+// Custom
+extern crate bam_util;
+use bam_util::bam::util as bam_io;
+
+/// Report insert size distribution by pair orientation and estimate library complexity
+#[derive(StructOpt, PartialEq, Debug)]
+#[structopt(setting=structopt::clap::AppSettings::ColoredHelp, name="Omics Tool Suite - Bam Utility - insert-size", author="Jingcheng Yang <yjcyxky@163.com>")]
+pub struct Arguments {
+  /// Bam file to process, duplicates should be marked for the library complexity
+  #[structopt(name = "FILE")]
+  input: String,
+
+  /// Output file, stdout if not set.
+  #[structopt(name = "output", short = "o", long = "output")]
+  output: Option<String>,
+
+  /// A format for output file.
+  #[structopt(name="format", short="O", long="format", possible_values=&["TSV", "JSON"], default_value="TSV")]
+  format: String,
+
+  /// Reference fasta file, required for reading CRAM.
+  #[structopt(name = "reference", short = "r", long = "reference")]
+  reference: Option<String>,
+
+  /// Number of threads
+  #[structopt(
+    name = "n_threads",
+    short = "n",
+    long = "n_threads",
+    default_value = "1"
+  )]
+  n_threads: usize,
+}
+
+/// Pair orientations, as defined by Picard.
+const ORIENTATIONS: [&str; 3] = ["FR", "RF", "TANDEM"];
+
+/// Orientation of the pair of a record, both mates must be mapped on the same contig.
+fn orientation(record: &Record) -> &'static str {
+  if record.is_reverse() == record.is_mate_reverse() {
+    return "TANDEM";
+  }
+
+  let (positive_five_prime, negative_five_prime) = if record.is_reverse() {
+    (record.mpos(), record.cigar().end_pos() - 1)
+  } else {
+    (record.pos(), record.pos() + record.insert_size())
+  };
+
+  if positive_five_prime < negative_five_prime {
+    "FR"
+  } else {
+    "RF"
+  }
+}
+
+/// Value at the given fraction of a histogram (0.5 for the median).
+fn quantile(histogram: &BTreeMap<u64, u64>, fraction: f64) -> u64 {
+  let n: u64 = histogram.values().sum();
+  let mut seen = 0;
+  for (&value, &count) in histogram {
+    seen += count;
+    if seen as f64 >= n as f64 * fraction {
+      return value;
+    }
+  }
+
+  0
+}
+
+/// Median absolute deviation of a histogram.
+fn median_absolute_deviation(histogram: &BTreeMap<u64, u64>) -> u64 {
+  let median = quantile(histogram, 0.5);
+  let mut deviations = BTreeMap::new();
+  for (&value, &count) in histogram {
+    let deviation = if value > median {
+      value - median
+    } else {
+      median - value
+    };
+    *deviations.entry(deviation).or_insert(0) += count;
+  }
+
+  quantile(&deviations, 0.5)
+}
+
+/// Insert size histograms and duplicate counts of the read pairs.
+#[derive(Debug, Default)]
+pub struct InsertSize {
+  histograms: BTreeMap<&'static str, BTreeMap<u64, u64>>,
+  read_pairs: u64,
+  duplicate_pairs: u64,
+}
+
+impl InsertSize {
+  /// Each pair is counted once, by its first read.
+  pub fn add(&mut self, record: &Record) {
+    if !record.is_paired()
+      || !record.is_first_in_template()
+      || record.is_unmapped()
+      || record.is_mate_unmapped()
+      || record.is_secondary()
+      || record.is_supplementary()
+    {
+      return;
+    }
+
+    self.read_pairs += 1;
+    if record.is_duplicate() {
+      self.duplicate_pairs += 1;
+      return;
+    }
+
+    if record.tid() != record.mtid() || record.insert_size() == 0 {
+      return;
+    }
+
+    *self
+      .histograms
+      .entry(orientation(record))
+      .or_default()
+      .entry(record.insert_size().abs() as u64)
+      .or_insert(0) += 1;
+  }
+
+  fn library_size(&self) -> Option<u64> {
+    bam_io::estimate_library_size(self.read_pairs, self.read_pairs - self.duplicate_pairs)
+  }
+
+  fn percent_duplication(&self) -> f64 {
+    if self.read_pairs == 0 {
+      0.0
+    } else {
+      self.duplicate_pairs as f64 / self.read_pairs as f64 * 100.0
+    }
+  }
+
+  pub fn write_tsv<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+    writeln!(writer, "orientation\tpairs\tmedian\tmad")?;
+    for (orientation, histogram) in &self.histograms {
+      writeln!(
+        writer,
+        "{}\t{}\t{}\t{}",
+        orientation,
+        histogram.values().sum::<u64>(),
+        quantile(histogram, 0.5),
+        median_absolute_deviation(histogram)
+      )?;
+    }
+
+    writeln!(writer)?;
+    writeln!(
+      writer,
+      "read_pairs\tduplicate_pairs\tpercent_duplication\testimated_library_size"
+    )?;
+    writeln!(
+      writer,
+      "{}\t{}\t{:.2}\t{}",
+      self.read_pairs,
+      self.duplicate_pairs,
+      self.percent_duplication(),
+      self
+        .library_size()
+        .map_or(String::from("NA"), |size| size.to_string())
+    )?;
+
+    writeln!(writer)?;
+    writeln!(writer, "insert_size\t{}", ORIENTATIONS.join("\t"))?;
+    let sizes: BTreeSet<u64> = self
+      .histograms
+      .values()
+      .flat_map(|histogram| histogram.keys().cloned())
+      .collect();
+    for size in &sizes {
+      let counts: Vec<String> = ORIENTATIONS
+        .iter()
+        .map(|orientation| {
+          self
+            .histograms
+            .get(orientation)
+            .and_then(|histogram| histogram.get(size))
+            .unwrap_or(&0)
+            .to_string()
+        })
+        .collect();
+      writeln!(writer, "{}\t{}", size, counts.join("\t"))?;
+    }
+
+    Ok(())
+  }
+
+  pub fn to_json(&self) -> Value {
+    let orientations: Map<String, Value> = self
+      .histograms
+      .iter()
+      .map(|(orientation, histogram)| {
+        let counts: Map<String, Value> = histogram
+          .iter()
+          .map(|(size, count)| (size.to_string(), json!(count)))
+          .collect();
+        let report = json!({
+          "pairs": histogram.values().sum::<u64>(),
+          "median": quantile(histogram, 0.5),
+          "mad": median_absolute_deviation(histogram),
+          "histogram": counts,
+        });
+        (String::from(*orientation), report)
+      })
+      .collect();
+
+    json!({
+      "orientations": orientations,
+      "library": {
+        "read_pairs": self.read_pairs,
+        "duplicate_pairs": self.duplicate_pairs,
+        "percent_duplication": self.percent_duplication(),
+        "estimated_library_size": self.library_size(),
+      },
+    })
+  }
+}
+
+pub fn run(args: &Arguments) {
+  info!("{} - Collect insert size: {:?}", module_path!(), args.input);
+
+  if Path::new(&args.input).exists() {
+    insert_size(args);
+  } else {
+    error!("{} - Not Found: {:?}", module_path!(), args.input);
+    std::process::exit(exitcode::NOINPUT);
+  }
+}
+
+pub fn insert_size(args: &Arguments) {
+  let mut reader =
+    bam_io::open_reader(&args.input, args.reference.as_deref(), args.n_threads).unwrap();
+  let mut metrics = InsertSize::default();
+
+  for record in reader.records() {
+    metrics.add(&record.unwrap());
+  }
+
+  let mut writer: Box<dyn Write> = match &args.output {
+    Some(output) => Box::new(File::create(output).unwrap()),
+    None => Box::new(io::stdout()),
+  };
+
+  if args.format == "JSON" {
+    serde_json::to_writer_pretty(&mut writer, &metrics.to_json()).unwrap();
+    writeln!(writer).unwrap();
+  } else {
+    metrics.write_tsv(&mut writer).unwrap();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rust_htslib::bam::record::{Cigar, CigarString};
+
+  /// A mapped read of 100 bases at `pos` on chr1, its mate at `mpos`.
+  fn read(flags: u16, pos: i64, mpos: i64, insert_size: i64) -> Record {
+    let mut record = Record::new();
+    let cigar = CigarString(vec![Cigar::Match(100)]);
+    record.set(b"read", Some(&cigar), &[b'A'; 100], &[30; 100]);
+    record.set_flags(flags);
+    record.set_tid(0);
+    record.set_pos(pos);
+    record.set_mtid(0);
+    record.set_mpos(mpos);
+    record.set_insert_size(insert_size);
+    record
+  }
+
+  fn histogram(values: &[(u64, u64)]) -> BTreeMap<u64, u64> {
+    values.iter().cloned().collect()
+  }
+
+  #[test]
+  fn orientations() {
+    // Forward read, reverse mate.
+    assert_eq!(orientation(&read(0x1 | 0x20, 100, 300, 300)), "FR");
+    assert_eq!(orientation(&read(0x1 | 0x20, 300, 100, -100)), "RF");
+    // Reverse read, forward mate.
+    assert_eq!(orientation(&read(0x1 | 0x10, 300, 100, -300)), "FR");
+    assert_eq!(orientation(&read(0x1 | 0x10, 100, 300, 100)), "RF");
+    assert_eq!(orientation(&read(0x1, 100, 300, 300)), "TANDEM");
+    assert_eq!(
+      orientation(&read(0x1 | 0x10 | 0x20, 100, 300, 300)),
+      "TANDEM"
+    );
+  }
+
+  #[test]
+  fn quantiles() {
+    let values = histogram(&[(1, 1), (2, 1), (3, 1), (10, 1)]);
+    assert_eq!(quantile(&values, 0.5), 2);
+    assert_eq!(quantile(&values, 0.75), 3);
+    assert_eq!(quantile(&values, 1.0), 10);
+    assert_eq!(quantile(&BTreeMap::new(), 0.5), 0);
+    // Deviations 1, 0, 1 and 8 from the median 2.
+    assert_eq!(median_absolute_deviation(&values), 1);
+    assert_eq!(median_absolute_deviation(&histogram(&[(300, 5)])), 0);
+  }
+
+  #[test]
+  fn add() {
+    let mut metrics = InsertSize::default();
+    // read1 of an FR pair, its read2 is not counted again.
+    metrics.add(&read(0x1 | 0x20 | 0x40, 100, 300, 300));
+    metrics.add(&read(0x1 | 0x10 | 0x80, 300, 100, -300));
+    // A duplicate pair, only counted for the duplication.
+    metrics.add(&read(0x1 | 0x20 | 0x40 | 0x400, 100, 300, 300));
+    // A pair with an unmapped mate and a secondary alignment, not counted.
+    metrics.add(&read(0x1 | 0x8 | 0x40, 100, 100, 0));
+    metrics.add(&read(0x1 | 0x20 | 0x40 | 0x100, 100, 300, 300));
+
+    assert_eq!(metrics.read_pairs, 2);
+    assert_eq!(metrics.duplicate_pairs, 1);
+    assert_eq!(metrics.percent_duplication(), 50.0);
+    assert_eq!(metrics.histograms.len(), 1);
+    assert_eq!(metrics.histograms["FR"], histogram(&[(300, 1)]));
+
+    let mut tsv = vec![];
+    metrics.write_tsv(&mut tsv).unwrap();
+    let tsv = String::from_utf8(tsv).unwrap();
+    assert!(tsv.starts_with("orientation\tpairs\tmedian\tmad\nFR\t1\t300\t0\n"));
+    assert!(tsv.ends_with("insert_size\tFR\tRF\tTANDEM\n300\t1\t0\t0\n"));
+  }
+}
@@ -0,0 +1,935 @@
+// External
+use log::*;
+use rust_htslib::bam::record::Record;
+use rust_htslib::bam::{header, Format, HeaderView, Read};
+use structopt::StructOpt;
+
+// Standard
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+// Custom
+extern crate bam_util;
+use bam_util::bam::cigar as bam_cigar;
+use bam_util::bam::util as bam_io;
+
+/// Mark PCR and optical duplicates of a coordinate-sorted file
+///
+/// Reads are duplicates when they share library, unclipped 5' positions and orientation (and the
+/// UMI with --umi), the pair or read with the highest sum of base qualities is kept. The unmapped
+/// mate of a duplicate, and the secondary and supplementary alignments of a duplicate with an SA
+/// tag, are marked too. The input is read twice, the first pass holds the reads around the current
+/// position and the reads waiting for a mate on another contig.
+#[derive(StructOpt, PartialEq, Debug)]
+#[structopt(setting=structopt::clap::AppSettings::ColoredHelp, name="Omics Tool Suite - Bam Utility - markdup", author="Jingcheng Yang <yjcyxky@163.com>")]
+pub struct Arguments {
+  /// Bam file to process, must be coordinate-sorted
+  #[structopt(name = "FILE")]
+  input: String,
+
+  /// Output file, stdout if not set. A .bai/.csi index is built for the output.
+  #[structopt(name = "output", short = "o", long = "output")]
+  output: Option<String>,
+
+  /// Metrics file in the format of Picard DuplicationMetrics, only logged if not set.
+  #[structopt(name = "metrics", short = "M", long = "metrics")]
+  metrics: Option<String>,
+
+  /// A format for output file.
+  #[structopt(name="format", short="O", long="format", possible_values=&["BAM", "SAM", "CRAM"], default_value="BAM")]
+  format: String,
+
+  /// Reference fasta file, required for reading or writing CRAM.
+  #[structopt(name = "reference", short = "r", long = "reference")]
+  reference: Option<String>,
+
+  /// Compression level of the output file, from 0 (uncompressed) to 9 (best).
+  #[structopt(name = "level", short = "l", long = "level", possible_values=&["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"])]
+  level: Option<u32>,
+
+  /// Only reads with the same UMI (RX tag) can be duplicates
+  #[structopt(name = "umi", long = "umi")]
+  umi: bool,
+
+  /// Remove the duplicates instead of marking them
+  #[structopt(name = "remove", long = "remove")]
+  remove: bool,
+
+  /// Maximum distance in pixels between two duplicate clusters to count them as optical
+  /// duplicates, read names must look like Illumina ones (...:tile:x:y)
+  #[structopt(
+    name = "optical_distance",
+    long = "optical-distance",
+    default_value = "100"
+  )]
+  optical_distance: i64,
+
+  /// Number of threads
+  #[structopt(
+    name = "n_threads",
+    short = "n",
+    long = "n_threads",
+    default_value = "1"
+  )]
+  n_threads: usize,
+}
+
+/// Minimum base quality counted in the score of a read, as in Picard.
+const MIN_SCORE_QUALITY: u8 = 15;
+
+/// Library of the reads without one in their read group, as in Picard.
+const UNKNOWN_LIBRARY: &str = "Unknown Library";
+
+/// Unclipped 5' end of a read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct ReadEnd {
+  tid: i32,
+  pos: i64,
+  reverse: bool,
+}
+
+impl ReadEnd {
+  fn from_record(record: &Record) -> Self {
+    let cigar = record.cigar();
+    let pos = if record.is_reverse() {
+      bam_cigar::unclipped_end(&cigar) - 1
+    } else {
+      bam_cigar::unclipped_start(&cigar)
+    };
+
+    ReadEnd {
+      tid: record.tid(),
+      pos,
+      reverse: record.is_reverse(),
+    }
+  }
+}
+
+/// Sort key of a position, unmapped reads last.
+fn position_key(tid: i32, pos: i64) -> (i64, i64) {
+  (if tid < 0 { i64::MAX } else { tid as i64 }, pos)
+}
+
+/// Reads with the same key are duplicates of each other.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key<T> {
+  library: String,
+  umi: Option<String>,
+  ends: T,
+}
+
+/// A read or a pair competing to be kept.
+#[derive(Debug)]
+struct Candidate {
+  qname: Vec<u8>,
+  // Index in the file and whether it is the second read of its pair, of the reads to mark: the
+  // mapped reads and the unmapped mate of a read.
+  reads: Vec<(u64, bool)>,
+  score: u32,
+  // Whether a read has supplementary alignments (SA tag), marked along with it.
+  supplementary: bool,
+}
+
+/// A read waiting for its mate.
+struct Mate {
+  end: ReadEnd,
+  idx: u64,
+  last: bool,
+  score: u32,
+  supplementary: bool,
+  library: String,
+  umi: Option<String>,
+}
+
+/// Groups and ends resolved once the reads have gone past their 5' position.
+enum Pending {
+  Pair(Key<(ReadEnd, ReadEnd)>),
+  Fragment(Key<ReadEnd>),
+  PairedEnd(Key<ReadEnd>),
+}
+
+/// Indexes of the duplicates in the file, a bit per read.
+#[derive(Debug, Default)]
+struct Marks(Vec<u64>);
+
+impl Marks {
+  fn insert(&mut self, idx: u64) {
+    let (word, bit) = ((idx / 64) as usize, idx % 64);
+    if word >= self.0.len() {
+      self.0.resize(word + 1, 0);
+    }
+    self.0[word] |= 1 << bit;
+  }
+
+  fn contains(&self, idx: u64) -> bool {
+    let (word, bit) = ((idx / 64) as usize, idx % 64);
+    self
+      .0
+      .get(word)
+      .map_or(false, |bits| bits & (1 << bit) != 0)
+  }
+}
+
+/// Duplicates found by the first pass.
+struct Duplicates {
+  marks: Marks,
+  // (Read name, is second read of the pair) of the duplicates with supplementary alignments.
+  supplementary: HashSet<(Vec<u8>, bool)>,
+}
+
+impl Duplicates {
+  fn contains(&self, idx: u64, record: &Record) -> bool {
+    if record.is_secondary() || record.is_supplementary() {
+      let key = (record.qname().to_vec(), record.is_last_in_template());
+      self.supplementary.contains(&key)
+    } else {
+      self.marks.contains(idx)
+    }
+  }
+}
+
+/// Duplication metrics of a library, named as in Picard DuplicationMetrics.
+#[derive(Debug, Default)]
+struct Metrics {
+  unpaired_reads_examined: u64,
+  read_pairs_examined: u64,
+  secondary_or_supplementary_rds: u64,
+  unmapped_reads: u64,
+  unpaired_read_duplicates: u64,
+  read_pair_duplicates: u64,
+  read_pair_optical_duplicates: u64,
+}
+
+impl Metrics {
+  fn percent_duplication(&self) -> f64 {
+    let examined = self.unpaired_reads_examined + self.read_pairs_examined * 2;
+    if examined == 0 {
+      0.0
+    } else {
+      (self.unpaired_read_duplicates + self.read_pair_duplicates * 2) as f64 / examined as f64
+    }
+  }
+
+  fn library_size(&self) -> Option<u64> {
+    bam_io::estimate_library_size(
+      self.read_pairs_examined - self.read_pair_optical_duplicates,
+      self.read_pairs_examined - self.read_pair_duplicates,
+    )
+  }
+}
+
+fn write_metrics<W: Write>(writer: &mut W, metrics: &BTreeMap<String, Metrics>) -> io::Result<()> {
+  writeln!(writer, "## METRICS CLASS\tpicard.sam.DuplicationMetrics")?;
+  writeln!(
+    writer,
+    "LIBRARY\tUNPAIRED_READS_EXAMINED\tREAD_PAIRS_EXAMINED\tSECONDARY_OR_SUPPLEMENTARY_RDS\t\
+     UNMAPPED_READS\tUNPAIRED_READ_DUPLICATES\tREAD_PAIR_DUPLICATES\t\
+     READ_PAIR_OPTICAL_DUPLICATES\tPERCENT_DUPLICATION\tESTIMATED_LIBRARY_SIZE"
+  )?;
+
+  for (library, metrics) in metrics {
+    writeln!(
+      writer,
+      "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.6}\t{}",
+      library,
+      metrics.unpaired_reads_examined,
+      metrics.read_pairs_examined,
+      metrics.secondary_or_supplementary_rds,
+      metrics.unmapped_reads,
+      metrics.unpaired_read_duplicates,
+      metrics.read_pair_duplicates,
+      metrics.read_pair_optical_duplicates,
+      metrics.percent_duplication(),
+      metrics
+        .library_size()
+        .map_or(String::new(), |size| size.to_string())
+    )?;
+  }
+
+  Ok(())
+}
+
+/// Sum of the base qualities of at least `MIN_SCORE_QUALITY`.
+fn score(record: &Record) -> u32 {
+  record
+    .qual()
+    .iter()
+    .filter(|&&qual| qual >= MIN_SCORE_QUALITY && qual != 0xff)
+    .map(|&qual| qual as u32)
+    .sum()
+}
+
+/// Flowcell location (lane and tile, x, y) of an Illumina read name, e.g.
+/// `A00123:8:H7T2LDSXY:1:1101:10004:10019`.
+fn location(qname: &[u8]) -> Option<(&[u8], i64, i64)> {
+  let fields: Vec<&[u8]> = qname.split(|&c| c == b':').collect();
+  if fields.len() < 5 {
+    return None;
+  }
+
+  let n = fields.len();
+  let number = |field: &[u8]| std::str::from_utf8(field).ok()?.parse::<i64>().ok();
+  let (x, y) = (number(fields[n - 2])?, number(fields[n - 1])?);
+  let tile_len = qname.len() - fields[n - 2].len() - fields[n - 1].len() - 2;
+
+  Some((&qname[..tile_len], x, y))
+}
+
+/// Number of duplicates of a group close enough to another read of the group on the flowcell.
+fn optical_duplicates(candidates: &[Candidate], distance: i64) -> u64 {
+  let locations: Vec<_> = candidates
+    .iter()
+    .filter_map(|candidate| location(&candidate.qname))
+    .collect();
+  let mut optical = 0;
+
+  for (idx, (tile, x, y)) in locations.iter().enumerate() {
+    let close = locations[..idx]
+      .iter()
+      .any(|(other_tile, other_x, other_y)| {
+        tile == other_tile && (x - other_x).abs() <= distance && (y - other_y).abs() <= distance
+      });
+    if close {
+      optical += 1;
+    }
+  }
+
+  optical.min(candidates.len().saturating_sub(1) as u64)
+}
+
+/// Index of the candidate to keep, the first one with the highest score.
+fn best(candidates: &[Candidate]) -> usize {
+  let mut best = 0;
+  for (idx, candidate) in candidates.iter().enumerate() {
+    if candidate.score > candidates[best].score {
+      best = idx;
+    }
+  }
+
+  best
+}
+
+/// Groups the reads of the first pass, a group is resolved once the reads have gone past its last
+/// 5' position by more than the longest read, so that only the groups around the current position
+/// and the reads waiting for their mate are held.
+struct Collector {
+  umi: bool,
+  optical_distance: i64,
+  libraries: HashMap<String, String>,
+  pairs: HashMap<Key<(ReadEnd, ReadEnd)>, Vec<Candidate>>,
+  fragments: HashMap<Key<ReadEnd>, Vec<Candidate>>,
+  // Ends of the pairs and their number of reads, a read alone at one of them is a duplicate.
+  paired_ends: HashMap<Key<ReadEnd>, usize>,
+  // Groups and paired ends by their last 5' position.
+  pending: BTreeMap<(i64, i64), Vec<Pending>>,
+  // Longest read seen, clips included.
+  max_len: i64,
+  // Read name -> the first read of the pairs waiting for their mate.
+  waiting: HashMap<Vec<u8>, Mate>,
+  // Position of the last read and, at this position, the reads whose mate is unmapped (read name
+  // -> group and candidate) and the unmapped reads whose mate is mapped (read name -> read).
+  position: (i64, i64),
+  mapped_mates: HashMap<Vec<u8>, (Key<ReadEnd>, usize)>,
+  unmapped_mates: HashMap<Vec<u8>, (u64, bool)>,
+  idx: u64,
+  duplicates: Duplicates,
+  metrics: BTreeMap<String, Metrics>,
+}
+
+impl Collector {
+  fn new(args: &Arguments, header_view: &HeaderView) -> Self {
+    Collector {
+      umi: args.umi,
+      optical_distance: args.optical_distance,
+      libraries: bam_io::read_group_libraries(header_view),
+      pairs: HashMap::new(),
+      fragments: HashMap::new(),
+      paired_ends: HashMap::new(),
+      pending: BTreeMap::new(),
+      max_len: 0,
+      waiting: HashMap::new(),
+      position: (-1, -1),
+      mapped_mates: HashMap::new(),
+      unmapped_mates: HashMap::new(),
+      idx: 0,
+      duplicates: Duplicates {
+        marks: Marks::default(),
+        supplementary: HashSet::new(),
+      },
+      metrics: BTreeMap::new(),
+    }
+  }
+
+  fn library(&self, record: &Record) -> String {
+    bam_io::read_group(record)
+      .and_then(|rg| self.libraries.get(&rg).cloned())
+      .unwrap_or_else(|| String::from(UNKNOWN_LIBRARY))
+  }
+
+  fn add(&mut self, record: &Record) {
+    let idx = self.idx;
+    self.idx += 1;
+
+    let position = position_key(record.tid(), record.pos());
+    if position != self.position {
+      self.match_unmapped_mates();
+      self.position = position;
+      self.resolve(Some(position));
+    }
+
+    let library = self.library(record);
+    let metrics = self.metrics.entry(library.clone()).or_default();
+
+    if record.is_secondary() || record.is_supplementary() {
+      metrics.secondary_or_supplementary_rds += 1;
+      return;
+    }
+
+    if record.is_unmapped() {
+      metrics.unmapped_reads += 1;
+      if record.is_paired() && !record.is_mate_unmapped() {
+        self
+          .unmapped_mates
+          .insert(record.qname().to_vec(), (idx, record.is_last_in_template()));
+      }
+      return;
+    }
+
+    let umi = if self.umi {
+      bam_io::tag_value(record, b"RX")
+    } else {
+      None
+    };
+    let end = ReadEnd::from_record(record);
+    let cigar = record.cigar();
+    self.max_len = self
+      .max_len
+      .max(bam_cigar::unclipped_end(&cigar) - bam_cigar::unclipped_start(&cigar));
+    let supplementary = record.aux(b"SA").is_some();
+
+    if !record.is_paired() || record.is_mate_unmapped() {
+      metrics.unpaired_reads_examined += 1;
+      let key = Key {
+        library,
+        umi,
+        ends: end,
+      };
+      let candidate = Candidate {
+        qname: record.qname().to_vec(),
+        reads: vec![(idx, record.is_last_in_template())],
+        score: score(record),
+        supplementary,
+      };
+
+      let idx = self.add_fragment(key.clone(), candidate);
+      if record.is_paired() {
+        self
+          .mapped_mates
+          .insert(record.qname().to_vec(), (key, idx));
+      }
+      return;
+    }
+
+    match self.waiting.remove(record.qname()) {
+      None => {
+        // The end of the pair is known before its mate.
+        self.add_paired_end(Key {
+          library: library.clone(),
+          umi: umi.clone(),
+          ends: end,
+        });
+        self.waiting.insert(
+          record.qname().to_vec(),
+          Mate {
+            end,
+            idx,
+            last: record.is_last_in_template(),
+            score: score(record),
+            supplementary,
+            library,
+            umi,
+          },
+        );
+      }
+      Some(mate) => {
+        self
+          .metrics
+          .entry(mate.library.clone())
+          .or_default()
+          .read_pairs_examined += 1;
+        let ends = if mate.end <= end {
+          (mate.end, end)
+        } else {
+          (end, mate.end)
+        };
+
+        self.add_paired_end(Key {
+          library: mate.library.clone(),
+          umi: mate.umi.clone(),
+          ends: end,
+        });
+
+        let key = Key {
+          library: mate.library,
+          umi: mate.umi,
+          ends,
+        };
+        if !self.pairs.contains_key(&key) {
+          self
+            .pending
+            .entry(position_key(ends.1.tid, ends.1.pos))
+            .or_default()
+            .push(Pending::Pair(key.clone()));
+        }
+        self.pairs.entry(key).or_default().push(Candidate {
+          qname: record.qname().to_vec(),
+          reads: vec![(mate.idx, mate.last), (idx, record.is_last_in_template())],
+          score: mate.score + score(record),
+          supplementary: mate.supplementary || supplementary,
+        });
+      }
+    }
+  }
+
+  /// Add a candidate to its fragment group, return its index in the group.
+  fn add_fragment(&mut self, key: Key<ReadEnd>, candidate: Candidate) -> usize {
+    if !self.fragments.contains_key(&key) {
+      self
+        .pending
+        .entry(position_key(key.ends.tid, key.ends.pos))
+        .or_default()
+        .push(Pending::Fragment(key.clone()));
+    }
+
+    let candidates = self.fragments.entry(key).or_default();
+    candidates.push(candidate);
+    candidates.len() - 1
+  }
+
+  fn add_paired_end(&mut self, key: Key<ReadEnd>) {
+    let position = position_key(key.ends.tid, key.ends.pos);
+    let count = self.paired_ends.entry(key.clone()).or_default();
+    *count += 1;
+    if *count == 1 {
+      self
+        .pending
+        .entry(position)
+        .or_default()
+        .push(Pending::PairedEnd(key));
+    }
+  }
+
+  /// Withdraw the end of a read whose mate is missing, if its position is not resolved yet.
+  fn remove_paired_end(&mut self, key: &Key<ReadEnd>) {
+    if let Some(count) = self.paired_ends.get_mut(key) {
+      *count -= 1;
+      if *count == 0 {
+        self.paired_ends.remove(key);
+      }
+    }
+  }
+
+  /// Unmapped reads sit at the position of their mate, they are marked along with it.
+  fn match_unmapped_mates(&mut self) {
+    for (qname, read) in self.unmapped_mates.drain() {
+      if let Some((key, idx)) = self.mapped_mates.get(&qname) {
+        if let Some(candidate) = self
+          .fragments
+          .get_mut(key)
+          .and_then(|candidates| candidates.get_mut(*idx))
+        {
+          candidate.reads.push(read);
+        }
+      }
+    }
+    self.mapped_mates.clear();
+  }
+
+  /// Resolve the groups whose reads are all known at `position`, all of them if None.
+  fn resolve(&mut self, position: Option<(i64, i64)>) {
+    loop {
+      let last = match self.pending.keys().next() {
+        Some(&last) => last,
+        None => break,
+      };
+      // A read starts at most `max_len` after its 5' position, on the same contig.
+      if let Some((tid, pos)) = position {
+        if tid < last.0 || (tid == last.0 && pos <= last.1.saturating_add(self.max_len)) {
+          break;
+        }
+      }
+
+      let mut groups = self.pending.remove(&last).unwrap();
+      // The fragments need the paired ends of their position.
+      groups.sort_by_key(|group| matches!(group, Pending::PairedEnd(_)));
+      for group in groups {
+        match group {
+          Pending::Pair(key) => {
+            let candidates = self.pairs.remove(&key).unwrap();
+            self.resolve_pairs(&key, candidates);
+          }
+          Pending::Fragment(key) => {
+            let candidates = self.fragments.remove(&key).unwrap();
+            self.resolve_fragments(&key, candidates);
+          }
+          Pending::PairedEnd(key) => {
+            self.paired_ends.remove(&key);
+          }
+        }
+      }
+    }
+  }
+
+  fn mark(&mut self, candidate: &Candidate) {
+    for &(idx, last) in &candidate.reads {
+      self.duplicates.marks.insert(idx);
+      if candidate.supplementary {
+        self
+          .duplicates
+          .supplementary
+          .insert((candidate.qname.clone(), last));
+      }
+    }
+  }
+
+  fn resolve_pairs(&mut self, key: &Key<(ReadEnd, ReadEnd)>, candidates: Vec<Candidate>) {
+    let keep = best(&candidates);
+    let optical = optical_duplicates(&candidates, self.optical_distance);
+    let metrics = self.metrics.entry(key.library.clone()).or_default();
+    metrics.read_pair_duplicates += candidates.len() as u64 - 1;
+    metrics.read_pair_optical_duplicates += optical;
+
+    for (idx, candidate) in candidates.iter().enumerate() {
+      if idx != keep {
+        self.mark(candidate);
+      }
+    }
+  }
+
+  fn resolve_fragments(&mut self, key: &Key<ReadEnd>, candidates: Vec<Candidate>) {
+    let keep = if self.paired_ends.contains_key(key) {
+      None
+    } else {
+      Some(best(&candidates))
+    };
+    let metrics = self.metrics.entry(key.library.clone()).or_default();
+    let duplicates = candidates.len() - keep.map_or(0, |_| 1);
+    metrics.unpaired_read_duplicates += duplicates as u64;
+
+    for (idx, candidate) in candidates.iter().enumerate() {
+      if Some(idx) != keep {
+        self.mark(candidate);
+      }
+    }
+  }
+
+  /// Resolve the remaining groups, return the duplicates and the metrics.
+  fn finish(mut self) -> (Duplicates, BTreeMap<String, Metrics>) {
+    self.match_unmapped_mates();
+
+    let waiting: Vec<(Vec<u8>, Mate)> = self.waiting.drain().collect();
+    if !waiting.is_empty() {
+      warn!(
+        "{} - {} reads without their mate in the file, handled as unpaired reads",
+        module_path!(),
+        waiting.len()
+      );
+    }
+    // The fragments resolved before the end of the file have seen these reads as paired ends.
+    for (qname, mate) in waiting {
+      self
+        .metrics
+        .entry(mate.library.clone())
+        .or_default()
+        .unpaired_reads_examined += 1;
+      let key = Key {
+        library: mate.library,
+        umi: mate.umi,
+        ends: mate.end,
+      };
+      self.remove_paired_end(&key);
+      self.add_fragment(
+        key,
+        Candidate {
+          qname,
+          reads: vec![(mate.idx, mate.last)],
+          score: mate.score,
+          supplementary: mate.supplementary,
+        },
+      );
+    }
+
+    self.resolve(None);
+    (self.duplicates, self.metrics)
+  }
+}
+
+pub fn run(args: &Arguments) {
+  info!("{} - Mark duplicates: {:?}", module_path!(), args.input);
+
+  if args.format == "CRAM" && args.reference.is_none() {
+    error!(
+      "{} - CRAM output requires a reference (--reference).",
+      module_path!()
+    );
+    std::process::exit(exitcode::USAGE);
+  }
+
+  if Path::new(&args.input).exists() {
+    markdup(args);
+  } else {
+    error!("{} - Not Found: {:?}", module_path!(), args.input);
+    std::process::exit(exitcode::NOINPUT);
+  }
+}
+
+pub fn markdup(args: &Arguments) {
+  let reference = args.reference.as_deref();
+  let mut reader = bam_io::open_reader(&args.input, reference, args.n_threads).unwrap();
+  let header_view = reader.header().clone();
+
+  if !bam_io::is_coordinate_sorted(&header_view) {
+    error!(
+      "{} - The input must be coordinate-sorted: {:?}",
+      module_path!(),
+      args.input
+    );
+    std::process::exit(exitcode::DATAERR);
+  }
+
+  // First pass: group the reads by their 5' ends.
+  let mut collector = Collector::new(args, &header_view);
+  for record in reader.records() {
+    collector.add(&record.unwrap());
+  }
+  let (duplicates, metrics) = collector.finish();
+
+  // Second pass: set or clear the duplicate flag of every mapped read.
+  let mut reader = bam_io::open_reader(&args.input, reference, args.n_threads).unwrap();
+  let header = header::Header::from_template(&header_view);
+  let format = bam_io::parse_format(&args.format);
+  let mut writer = bam_io::open_writer(
+    args.output.as_deref(),
+    &header,
+    format,
+    reference,
+    args.level,
+    args.n_threads,
+  )
+  .unwrap();
+  let (mut marked, mut total) = (0, 0);
+
+  for (idx, record) in reader.records().enumerate() {
+    let mut record = record.unwrap();
+    total += 1;
+
+    if duplicates.contains(idx as u64, &record) {
+      marked += 1;
+      if args.remove {
+        continue;
+      }
+      record.set_duplicate();
+    } else {
+      record.unset_duplicate();
+    }
+
+    writer.write(&record).unwrap();
+  }
+  drop(writer);
+
+  info!(
+    "{} - {} of {} reads {}",
+    module_path!(),
+    marked,
+    total,
+    if args.remove { "removed" } else { "marked" }
+  );
+
+  match &args.metrics {
+    Some(path) => write_metrics(&mut File::create(path).unwrap(), &metrics).unwrap(),
+    None => {
+      for (library, metrics) in &metrics {
+        info!(
+          "{} - {}: {:.2}% duplication, {} optical duplicate pairs",
+          module_path!(),
+          library,
+          metrics.percent_duplication() * 100.0,
+          metrics.read_pair_optical_duplicates
+        );
+      }
+    }
+  }
+
+  if let Some(output) = &args.output {
+    if format != Format::SAM {
+      info!("{} - Build index for {:?}", module_path!(), output);
+      bam_io::build_index(output, &header_view, args.n_threads).unwrap();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rust_htslib::bam::record::{Aux, Cigar, CigarString};
+
+  fn args(extra: &[&str]) -> Arguments {
+    let mut argv = vec!["markdup", "/data/sample.bam"];
+    argv.extend_from_slice(extra);
+    Arguments::from_iter(argv)
+  }
+
+  fn header() -> HeaderView {
+    HeaderView::from_bytes(
+      b"@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:10000\n\
+        @RG\tID:rg1\tLB:lib1\n@RG\tID:rg2\tLB:lib2\n",
+    )
+  }
+
+  /// A 10 bases read on chr1 with the given base quality, unmapped reads have no CIGAR.
+  fn read(qname: &str, flags: u16, pos: i64, mpos: i64, qual: u8, rg: &str) -> Record {
+    let mut record = Record::new();
+    let cigar = CigarString(vec![Cigar::Match(10)]);
+    let cigar = if flags & 0x4 == 0 { Some(&cigar) } else { None };
+    record.set(qname.as_bytes(), cigar, &[b'A'; 10], &[qual; 10]);
+    record.set_flags(flags);
+    record.set_tid(0);
+    record.set_pos(pos);
+    record.set_mtid(if flags & 0x1 != 0 { 0 } else { -1 });
+    record.set_mpos(if flags & 0x1 != 0 { mpos } else { -1 });
+    record.push_aux(b"RG", &Aux::String(rg.as_bytes()));
+    record
+  }
+
+  /// Indexes of the reads marked as duplicates, and the metrics.
+  fn markdup(
+    args: &Arguments,
+    records: &[Record],
+  ) -> (Vec<u64>, Duplicates, BTreeMap<String, Metrics>) {
+    let mut collector = Collector::new(args, &header());
+    for record in records {
+      collector.add(record);
+    }
+
+    let (duplicates, metrics) = collector.finish();
+    let marks = (0..records.len() as u64)
+      .filter(|&idx| duplicates.marks.contains(idx))
+      .collect();
+    (marks, duplicates, metrics)
+  }
+
+  #[test]
+  fn pairs() {
+    let records = [
+      read("a", 0x1 | 0x40 | 0x20, 100, 200, 30, "rg1"),
+      read("b", 0x1 | 0x40 | 0x20, 100, 200, 20, "rg1"),
+      read("a", 0x1 | 0x80 | 0x10, 200, 100, 30, "rg1"),
+      read("b", 0x1 | 0x80 | 0x10, 200, 100, 20, "rg1"),
+      // Same starts but another orientation of the second read.
+      read("c", 0x1 | 0x40 | 0x20, 100, 200, 20, "rg1"),
+      read("c", 0x1 | 0x80, 200, 100, 20, "rg1"),
+    ];
+    let (marks, _, metrics) = markdup(&args(&[]), &records);
+
+    assert_eq!(marks, vec![1, 3]);
+    assert_eq!(metrics["lib1"].read_pairs_examined, 3);
+    assert_eq!(metrics["lib1"].read_pair_duplicates, 1);
+    assert_eq!(metrics["lib1"].unpaired_read_duplicates, 0);
+  }
+
+  #[test]
+  fn fragment_and_paired_end() {
+    let records = [
+      read("pair", 0x1 | 0x40 | 0x20, 100, 200, 20, "rg1"),
+      // A better fragment at the end of a pair is still a duplicate.
+      read("fragment", 0, 100, 0, 40, "rg1"),
+      read("pair", 0x1 | 0x80 | 0x10, 200, 100, 20, "rg1"),
+      read("alone", 0, 500, 0, 20, "rg1"),
+    ];
+    let (marks, _, metrics) = markdup(&args(&[]), &records);
+
+    assert_eq!(marks, vec![1]);
+    assert_eq!(metrics["lib1"].unpaired_reads_examined, 2);
+    assert_eq!(metrics["lib1"].unpaired_read_duplicates, 1);
+    assert_eq!(metrics["lib1"].read_pair_duplicates, 0);
+  }
+
+  #[test]
+  fn unmapped_mates() {
+    let mut second = read("b", 0x1 | 0x8 | 0x40, 100, 100, 20, "rg1");
+    second.push_aux(b"SA", &Aux::String(b"chr1,5000,+,10M,60,0;"));
+    let records = [
+      read("a", 0x1 | 0x8 | 0x40, 100, 100, 30, "rg1"),
+      read("a", 0x1 | 0x4 | 0x80, 100, 100, 30, "rg1"),
+      second,
+      read("b", 0x1 | 0x4 | 0x80, 100, 100, 20, "rg1"),
+      read("c", 0, 300, 0, 20, "rg1"),
+    ];
+    let (marks, duplicates, metrics) = markdup(&args(&[]), &records);
+
+    assert_eq!(marks, vec![2, 3]);
+    assert_eq!(metrics["lib1"].unmapped_reads, 2);
+    assert_eq!(metrics["lib1"].unpaired_reads_examined, 3);
+    assert!(duplicates.supplementary.contains(&(b"b".to_vec(), false)));
+
+    let supplementary = read("b", 0x1 | 0x8 | 0x40 | 0x800, 5000, 100, 20, "rg1");
+    assert!(duplicates.contains(99, &supplementary));
+  }
+
+  #[test]
+  fn orphans() {
+    // The mate is flagged mapped but missing from the file, the read is not a duplicate of itself.
+    let records = [read("orphan", 0x1 | 0x40, 100, 5000, 20, "rg1")];
+    let (marks, _, metrics) = markdup(&args(&[]), &records);
+    assert!(marks.is_empty());
+    assert_eq!(metrics["lib1"].unpaired_reads_examined, 1);
+    assert_eq!(metrics["lib1"].read_pairs_examined, 0);
+
+    // It competes with the fragments at its end, and its supplementary alignments follow it.
+    let mut orphan = read("orphan", 0x1 | 0x80, 100, 5000, 20, "rg1");
+    orphan.push_aux(b"SA", &Aux::String(b"chr1,8000,+,10M,60,0;"));
+    let records = [orphan, read("fragment", 0, 100, 0, 30, "rg1")];
+    let (marks, duplicates, _) = markdup(&args(&[]), &records);
+    assert_eq!(marks, vec![0]);
+    assert!(!duplicates
+      .supplementary
+      .contains(&(b"orphan".to_vec(), false)));
+    assert!(duplicates
+      .supplementary
+      .contains(&(b"orphan".to_vec(), true)));
+
+    // A real pair at the same end still makes the fragments duplicates.
+    let records = [
+      read("orphan", 0x1 | 0x40, 100, 5000, 20, "rg1"),
+      read("pair", 0x1 | 0x40 | 0x20, 100, 105, 20, "rg1"),
+      read("fragment", 0, 100, 0, 40, "rg1"),
+      read("pair", 0x1 | 0x80 | 0x10, 105, 100, 20, "rg1"),
+    ];
+    let (marks, _, _) = markdup(&args(&[]), &records);
+    assert_eq!(marks, vec![0, 2]);
+  }
+
+  #[test]
+  fn libraries_and_umis() {
+    let with_umi = |mut record: Record, umi: &[u8]| {
+      record.push_aux(b"RX", &Aux::String(umi));
+      record
+    };
+    let records = [
+      with_umi(read("a", 0, 100, 0, 30, "rg1"), b"ACGT"),
+      with_umi(read("b", 0, 100, 0, 20, "rg2"), b"ACGT"),
+      with_umi(read("c", 0, 100, 0, 20, "rg1"), b"TTTT"),
+      with_umi(read("d", 0, 100, 0, 20, "rg1"), b"ACGT"),
+    ];
+
+    let (marks, _, metrics) = markdup(&args(&[]), &records);
+    assert_eq!(marks, vec![2, 3]);
+    assert_eq!(metrics["lib1"].unpaired_read_duplicates, 2);
+    assert_eq!(metrics["lib2"].unpaired_read_duplicates, 0);
+
+    let (marks, _, _) = markdup(&args(&["--umi"]), &records);
+    assert_eq!(marks, vec![3]);
+  }
+}
@@ -1 +1,10 @@
-pub mod filter;
\ No newline at end of file
+pub mod clip;
+pub mod clips;
+pub mod coverage;
+pub mod downsample;
+pub mod filter;
+pub mod flagstat;
+pub mod insert_size;
+pub mod markdup;
+pub mod split;
+pub mod stats;
@@ -0,0 +1,480 @@
+// External
+use log::*;
+use rust_htslib::bam::record::Record;
+use rust_htslib::bam::{Format, HeaderView, Read, Writer};
+use rust_htslib::tpool::ThreadPool;
+use structopt::StructOpt;
+
+// Standard
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::Path;
+use std::str::FromStr;
+
+// Custom
+extern crate bam_util;
+use bam_util::bam::util as bam_io;
+
+/// Split reads into one file per read group, sample, reference or tag value
+///
+/// Each output keeps the header of the input, with only the read groups of its own reads. When
+/// there are more outputs than --max-open-files, the input is read once per batch of outputs.
+#[derive(StructOpt, PartialEq, Debug)]
+#[structopt(setting=structopt::clap::AppSettings::ColoredHelp, name="Omics Tool Suite - Bam Utility - split", author="Jingcheng Yang <yjcyxky@163.com>")]
+pub struct Arguments {
+  /// Bam file to process
+  #[structopt(name = "FILE")]
+  input: String,
+
+  /// How to split the reads: rg, sample, chrom or tag:XX (e.g. tag:CB)
+  #[structopt(name = "by", short = "b", long = "by", default_value = "rg")]
+  by: SplitBy,
+
+  /// Output file name, {prefix}, {key} and {ext} are replaced by the prefix, the value the reads are
+  /// split by and the extension of the format.
+  #[structopt(
+    name = "template",
+    short = "t",
+    long = "template",
+    default_value = "{prefix}.{key}.{ext}"
+  )]
+  template: String,
+
+  /// Prefix of the output files, the input file name without extension if not set.
+  #[structopt(name = "prefix", short = "p", long = "prefix")]
+  prefix: Option<String>,
+
+  /// Key of the reads without a value to split by (no read group, unmapped, missing tag).
+  #[structopt(name = "unassigned", long = "unassigned", default_value = "unassigned")]
+  unassigned: String,
+
+  /// Maximum number of output files open at once.
+  #[structopt(
+    name = "max_open_files",
+    short = "m",
+    long = "max-open-files",
+    default_value = "256"
+  )]
+  max_open_files: usize,
+
+  /// A format for output file.
+  #[structopt(name="format", short="O", long="format", possible_values=&["BAM", "SAM", "CRAM"], default_value="BAM")]
+  format: String,
+
+  /// Reference fasta file, required for reading or writing CRAM.
+  #[structopt(name = "reference", short = "r", long = "reference")]
+  reference: Option<String>,
+
+  /// Compression level of the output file, from 0 (uncompressed) to 9 (best).
+  #[structopt(name = "level", short = "l", long = "level", possible_values=&["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"])]
+  level: Option<u32>,
+
+  /// Number of threads
+  #[structopt(
+    name = "n_threads",
+    short = "n",
+    long = "n_threads",
+    default_value = "1"
+  )]
+  n_threads: usize,
+}
+
+/// What the reads are split by.
+#[derive(Debug, PartialEq, Clone)]
+pub enum SplitBy {
+  ReadGroup,
+  Sample,
+  Chrom,
+  Tag(String),
+}
+
+impl FromStr for SplitBy {
+  type Err = String;
+
+  fn from_str(by: &str) -> Result<Self, Self::Err> {
+    match by {
+      "rg" => Ok(SplitBy::ReadGroup),
+      "sample" => Ok(SplitBy::Sample),
+      "chrom" => Ok(SplitBy::Chrom),
+      _ if by.starts_with("tag:") && by.len() == 6 => Ok(SplitBy::Tag(String::from(&by[4..]))),
+      _ => Err(format!("Not valid split mode: {:?}", by)),
+    }
+  }
+}
+
+/// Output key -> read groups of the reads written to it.
+type Groups = BTreeMap<String, BTreeSet<String>>;
+
+/// Computes the output key of the records.
+struct Splitter {
+  by: SplitBy,
+  unassigned: String,
+  header_view: HeaderView,
+  // Read group ID -> sample, for --by sample.
+  samples: HashMap<String, String>,
+}
+
+impl Splitter {
+  fn new(args: &Arguments, header_view: &HeaderView) -> Self {
+    Splitter {
+      by: args.by.clone(),
+      unassigned: args.unassigned.clone(),
+      header_view: header_view.clone(),
+      samples: bam_io::read_group_samples(header_view),
+    }
+  }
+
+  fn key(&self, record: &Record) -> String {
+    let key = match &self.by {
+      SplitBy::ReadGroup => bam_io::read_group(record),
+      SplitBy::Sample => bam_io::read_group(record).and_then(|rg| self.samples.get(&rg).cloned()),
+      SplitBy::Chrom if record.tid() >= 0 => {
+        Some(bam_io::reference_name(&self.header_view, record))
+      }
+      SplitBy::Chrom => None,
+      SplitBy::Tag(tag) => bam_io::tag_value(record, tag.as_bytes()),
+    };
+
+    key.unwrap_or_else(|| self.unassigned.clone())
+  }
+
+  /// Outputs and their read groups known from the header, None if the reads must be scanned.
+  fn header_groups(&self) -> Option<Groups> {
+    let mut groups = Groups::new();
+    let read_groups = bam_io::read_group_ids(&self.header_view);
+
+    match &self.by {
+      SplitBy::ReadGroup => {
+        for rg in read_groups {
+          groups.entry(rg.clone()).or_default().insert(rg);
+        }
+      }
+      SplitBy::Sample => {
+        for rg in read_groups {
+          let key = self.samples.get(&rg).unwrap_or(&self.unassigned).clone();
+          groups.entry(key).or_default().insert(rg);
+        }
+      }
+      _ => return None,
+    }
+
+    groups.entry(self.unassigned.clone()).or_default();
+    Some(groups)
+  }
+}
+
+/// Replace the characters not allowed in a file name.
+fn sanitize(key: &str) -> String {
+  key
+    .chars()
+    .map(|c| {
+      if c.is_ascii_alphanumeric() || "._-".contains(c) {
+        c
+      } else {
+        '_'
+      }
+    })
+    .collect()
+}
+
+/// Input file name without its directory and extension.
+fn default_prefix(input: &str) -> String {
+  Path::new(input)
+    .file_stem()
+    .map(|stem| stem.to_string_lossy().into_owned())
+    .unwrap_or_else(|| String::from("split"))
+}
+
+/// Output path of each key, an error if two keys end up with the same path.
+fn output_paths(args: &Arguments, groups: &Groups) -> Result<BTreeMap<String, String>, String> {
+  let prefix = args
+    .prefix
+    .clone()
+    .unwrap_or_else(|| default_prefix(&args.input));
+  let ext = args.format.to_lowercase();
+  let mut paths = BTreeMap::new();
+  let mut seen: HashMap<String, &str> = HashMap::new();
+
+  for key in groups.keys() {
+    let path = args
+      .template
+      .replace("{prefix}", &prefix)
+      .replace("{key}", &sanitize(key))
+      .replace("{ext}", &ext);
+
+    if let Some(other) = seen.insert(path.clone(), key.as_str()) {
+      return Err(format!(
+        "{:?} and {:?} are both written to {:?}",
+        other, key, path
+      ));
+    }
+    paths.insert(key.clone(), path);
+  }
+
+  Ok(paths)
+}
+
+pub fn run(args: &Arguments) {
+  info!(
+    "{} - Split reads by {:?}: {:?}",
+    module_path!(),
+    args.by,
+    args.input
+  );
+
+  if !args.template.contains("{key}") {
+    error!(
+      "{} - The template must contain {{key}}: {:?}",
+      module_path!(),
+      args.template
+    );
+    std::process::exit(exitcode::USAGE);
+  }
+
+  if args.format == "CRAM" && args.reference.is_none() {
+    error!(
+      "{} - CRAM output requires a reference (--reference).",
+      module_path!()
+    );
+    std::process::exit(exitcode::USAGE);
+  }
+
+  if Path::new(&args.input).exists() {
+    split(args);
+  } else {
+    error!("{} - Not Found: {:?}", module_path!(), args.input);
+    std::process::exit(exitcode::NOINPUT);
+  }
+}
+
+pub fn split(args: &Arguments) {
+  let reference = args.reference.as_deref();
+  let reader = bam_io::open_reader(&args.input, reference, args.n_threads).unwrap();
+  let header_view = reader.header().clone();
+  let splitter = Splitter::new(args, &header_view);
+  drop(reader);
+
+  let groups = match splitter.header_groups() {
+    Some(groups) => groups,
+    None => {
+      info!("{} - Scan the reads for the outputs", module_path!());
+      scan(args, &splitter)
+    }
+  };
+
+  let paths = match output_paths(args, &groups) {
+    Ok(paths) => paths,
+    Err(msg) => {
+      error!("{} - {}, change the template.", module_path!(), msg);
+      std::process::exit(exitcode::USAGE);
+    }
+  };
+
+  let keys: Vec<&String> = groups.keys().collect();
+  let batches: Vec<&[&String]> = keys.chunks(args.max_open_files.max(1)).collect();
+  if batches.len() > 1 {
+    info!(
+      "{} - {} outputs, the input is read {} times",
+      module_path!(),
+      keys.len(),
+      batches.len()
+    );
+  }
+
+  let format = bam_io::parse_format(&args.format);
+  for batch in batches {
+    let batch: HashSet<&str> = batch.iter().map(|key| key.as_str()).collect();
+    let counts = write_batch(args, &splitter, &groups, &paths, &batch);
+
+    for (key, count) in counts {
+      let path = &paths[&key];
+      info!("{} - {} reads written to {:?}", module_path!(), count, path);
+
+      if format != Format::SAM && bam_io::is_coordinate_sorted(&header_view) {
+        info!("{} - Build index for {:?}", module_path!(), path);
+        bam_io::build_index(path, &header_view, args.n_threads).unwrap();
+      }
+    }
+  }
+}
+
+/// Read group IDs of the reads of each output.
+fn scan(args: &Arguments, splitter: &Splitter) -> Groups {
+  let mut reader =
+    bam_io::open_reader(&args.input, args.reference.as_deref(), args.n_threads).unwrap();
+  let mut groups = Groups::new();
+
+  for record in reader.records() {
+    let record = record.unwrap();
+    let read_groups = groups.entry(splitter.key(&record)).or_default();
+    if let Some(rg) = bam_io::read_group(&record) {
+      read_groups.insert(rg);
+    }
+  }
+
+  groups
+}
+
+/// Write the reads of the keys in the batch, return the number of reads of each output written.
+/// An output is only created when it gets its first read. Reads with a key missing from the
+/// outputs (e.g. a read group not declared in the header) go to the unassigned output, and the
+/// RG tag of a read is removed if its read group is not declared in the header.
+///
+/// The reader and all the writers share one pool of `n_threads` threads.
+fn write_batch(
+  args: &Arguments,
+  splitter: &Splitter,
+  groups: &Groups,
+  paths: &BTreeMap<String, String>,
+  batch: &HashSet<&str>,
+) -> BTreeMap<String, u64> {
+  let reference = args.reference.as_deref();
+  let pool = ThreadPool::new(args.n_threads.max(1) as u32).unwrap();
+  let mut reader = bam_io::open_pooled_reader(&args.input, reference, &pool).unwrap();
+  let header_view = reader.header().clone();
+  let declared: HashSet<String> = bam_io::read_group_ids(&header_view).into_iter().collect();
+  let mut writers: HashMap<String, Writer> = HashMap::new();
+  let mut counts = BTreeMap::new();
+  let mut undeclared = 0;
+
+  for record in reader.records() {
+    let mut record = record.unwrap();
+    let mut key = splitter.key(&record);
+    if !groups.contains_key(&key) {
+      key = args.unassigned.clone();
+    }
+
+    if !batch.contains(key.as_str()) {
+      continue;
+    }
+
+    if !writers.contains_key(&key) {
+      let read_groups: HashSet<String> = groups[&key].iter().cloned().collect();
+      let header = bam_io::keep_read_groups(&header_view, &read_groups);
+      let writer = bam_io::open_pooled_writer(
+        Some(&paths[&key]),
+        &header,
+        bam_io::parse_format(&args.format),
+        reference,
+        args.level,
+        &pool,
+      )
+      .unwrap();
+      writers.insert(key.clone(), writer);
+    }
+
+    if let Some(rg) = bam_io::read_group(&record) {
+      if !declared.contains(&rg) {
+        record.remove_aux(b"RG");
+        undeclared += 1;
+      }
+    }
+
+    writers.get_mut(&key).unwrap().write(&record).unwrap();
+    *counts.entry(key).or_insert(0) += 1;
+  }
+
+  if undeclared > 0 {
+    warn!(
+      "{} - RG tag removed from {} reads of read groups not declared in the header",
+      module_path!(),
+      undeclared
+    );
+  }
+
+  // The writers must be flushed and closed before indexing, and before the pool is dropped.
+  drop(writers);
+  drop(reader);
+  counts
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rust_htslib::bam::record::Aux;
+
+  fn args(extra: &[&str]) -> Arguments {
+    let mut argv = vec!["split", "/data/sample.bam"];
+    argv.extend_from_slice(extra);
+    Arguments::from_iter(argv)
+  }
+
+  fn header() -> HeaderView {
+    HeaderView::from_bytes(
+      b"@SQ\tSN:chr1\tLN:1000\n@RG\tID:rg1\tSM:NA12878\n@RG\tID:rg2\tSM:NA12878\n@RG\tID:rg3\n",
+    )
+  }
+
+  fn groups(keys: &[&str]) -> Groups {
+    keys
+      .iter()
+      .map(|key| (String::from(*key), BTreeSet::new()))
+      .collect()
+  }
+
+  #[test]
+  fn split_by() {
+    assert_eq!("rg".parse::<SplitBy>(), Ok(SplitBy::ReadGroup));
+    assert_eq!("sample".parse::<SplitBy>(), Ok(SplitBy::Sample));
+    assert_eq!("chrom".parse::<SplitBy>(), Ok(SplitBy::Chrom));
+    assert_eq!(
+      "tag:CB".parse::<SplitBy>(),
+      Ok(SplitBy::Tag(String::from("CB")))
+    );
+    for invalid in &["tag:", "tag:CBX", "RG", ""] {
+      assert!(invalid.parse::<SplitBy>().is_err(), "{}", invalid);
+    }
+  }
+
+  #[test]
+  fn file_names() {
+    assert_eq!(sanitize("NA12878.L-1_x"), "NA12878.L-1_x");
+    assert_eq!(sanitize("a b/c:d*"), "a_b_c_d_");
+    assert_eq!(default_prefix("/data/sample.bam"), "sample");
+    assert_eq!(default_prefix("sample"), "sample");
+  }
+
+  #[test]
+  fn paths() {
+    let paths = output_paths(&args(&[]), &groups(&["rg1", "unassigned"])).unwrap();
+    assert_eq!(paths["rg1"], "sample.rg1.bam");
+    assert_eq!(paths["unassigned"], "sample.unassigned.bam");
+
+    let args = args(&["-O", "SAM", "-p", "out", "-t", "split/{key}/{prefix}.{ext}"]);
+    let paths = output_paths(&args, &groups(&["AAAC-1"])).unwrap();
+    assert_eq!(paths["AAAC-1"], "split/AAAC-1/out.sam");
+  }
+
+  #[test]
+  fn path_collisions() {
+    // Both are sanitized into a_b.
+    let err = output_paths(&args(&[]), &groups(&["a/b", "a:b"])).unwrap_err();
+    assert!(err.contains("sample.a_b.bam"), "{}", err);
+    let err = output_paths(&args(&["-t", "{prefix}.{ext}"]), &groups(&["rg1", "rg2"]));
+    assert!(err.is_err());
+  }
+
+  #[test]
+  fn keys_and_groups() {
+    let header = header();
+    let mut record = Record::new();
+    record.set_tid(-1);
+    record.push_aux(b"RG", &Aux::String(b"rg2"));
+
+    let splitter = Splitter::new(&args(&["-b", "sample"]), &header);
+    assert_eq!(splitter.key(&record), "NA12878");
+    let groups = splitter.header_groups().unwrap();
+    assert_eq!(
+      groups.keys().collect::<Vec<_>>(),
+      vec!["NA12878", "unassigned"]
+    );
+    assert_eq!(groups["NA12878"].len(), 2);
+    assert!(groups["unassigned"].contains("rg3"));
+
+    let splitter = Splitter::new(&args(&["-b", "chrom", "--unassigned", "none"]), &header);
+    assert_eq!(splitter.key(&record), "none");
+    assert!(splitter.header_groups().is_none());
+
+    let splitter = Splitter::new(&args(&["-b", "tag:CB"]), &header);
+    assert_eq!(splitter.key(&record), "unassigned");
+  }
+}
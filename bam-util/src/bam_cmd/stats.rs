@@ -0,0 +1,280 @@
+// External
+use log::*;
+use rust_htslib::bam::record::Record;
+use rust_htslib::bam::Read;
+use serde_json::{json, Map, Value};
+use structopt::StructOpt;
+
+// Standard
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+// Custom
+extern crate bam_util;
+use bam_util::bam::cigar as bam_cigar;
+use bam_util::bam::util as bam_io;
+
+/// Cigar operations, in the order of the report.
+const OPERATORS: [char; 9] = ['M', 'I', 'D', 'N', 'S', 'H', 'P', '=', 'X'];
+
+/// Report the distribution of cigar operations, clipping, MAPQ and read length
+#[derive(StructOpt, PartialEq, Debug)]
+#[structopt(setting=structopt::clap::AppSettings::ColoredHelp, name="Omics Tool Suite - Bam Utility - stats", author="Jingcheng Yang <yjcyxky@163.com>")]
+pub struct Arguments {
+  /// Bam file to process
+  #[structopt(name = "FILE")]
+  input: String,
+
+  /// Output file, stdout if not set.
+  #[structopt(name = "output", short = "o", long = "output")]
+  output: Option<String>,
+
+  /// A format for output file, JSON can be loaded by MultiQC.
+  #[structopt(name="format", short="O", long="format", possible_values=&["TSV", "JSON"], default_value="TSV")]
+  format: String,
+
+  /// Reference fasta file, required for reading CRAM.
+  #[structopt(name = "reference", short = "r", long = "reference")]
+  reference: Option<String>,
+
+  /// Number of threads
+  #[structopt(
+    name = "n_threads",
+    short = "n",
+    long = "n_threads",
+    default_value = "1"
+  )]
+  n_threads: usize,
+}
+
+type Histogram = BTreeMap<u32, u64>;
+
+/// Statistics of the primary alignments of a bam file.
+#[derive(Debug, Default)]
+pub struct Stats {
+  records: u64,
+  operator_totals: BTreeMap<char, u64>,
+  soft_clip_length: Histogram,
+  hard_clip_length: Histogram,
+  insertion_length: Histogram,
+  deletion_length: Histogram,
+  mapq: Histogram,
+  read_length: Histogram,
+}
+
+fn add_all(histogram: &mut Histogram, values: Vec<u32>) {
+  for value in values {
+    *histogram.entry(value).or_insert(0) += 1;
+  }
+}
+
+fn histogram_to_json(histogram: &Histogram) -> Value {
+  let map: Map<String, Value> = histogram
+    .iter()
+    .map(|(value, count)| (value.to_string(), json!(count)))
+    .collect();
+  Value::Object(map)
+}
+
+impl Stats {
+  /// Secondary and supplementary alignments are skipped, so that each read is counted once.
+  pub fn add(&mut self, record: &Record) {
+    if record.is_secondary() || record.is_supplementary() {
+      return;
+    }
+
+    let cigar = record.cigar();
+    self.records += 1;
+
+    for &operator in OPERATORS.iter() {
+      *self.operator_totals.entry(operator).or_insert(0) +=
+        bam_cigar::sum_by(&cigar, operator) as u64;
+    }
+
+    add_all(
+      &mut self.soft_clip_length,
+      bam_cigar::len_vector(&cigar, 'S'),
+    );
+    add_all(
+      &mut self.hard_clip_length,
+      bam_cigar::len_vector(&cigar, 'H'),
+    );
+    add_all(
+      &mut self.insertion_length,
+      bam_cigar::len_vector(&cigar, 'I'),
+    );
+    add_all(
+      &mut self.deletion_length,
+      bam_cigar::len_vector(&cigar, 'D'),
+    );
+    add_all(&mut self.mapq, vec![record.mapq() as u32]);
+    add_all(
+      &mut self.read_length,
+      vec![record.seq().len() as u32 + bam_cigar::dispatch(&cigar, 'H')],
+    );
+  }
+
+  fn histograms(&self) -> Vec<(&'static str, &Histogram)> {
+    vec![
+      ("soft_clip_length", &self.soft_clip_length),
+      ("hard_clip_length", &self.hard_clip_length),
+      ("insertion_length", &self.insertion_length),
+      ("deletion_length", &self.deletion_length),
+      ("mapq", &self.mapq),
+      ("read_length", &self.read_length),
+    ]
+  }
+
+  /// Long format table: metric, value, count.
+  pub fn write_tsv<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+    writeln!(writer, "metric\tvalue\tcount")?;
+    writeln!(writer, "records\t-\t{}", self.records)?;
+
+    for (operator, total) in &self.operator_totals {
+      writeln!(writer, "operator_total\t{}\t{}", operator, total)?;
+    }
+
+    for (name, histogram) in self.histograms() {
+      for (value, count) in histogram {
+        writeln!(writer, "{}\t{}\t{}", name, value, count)?;
+      }
+    }
+
+    Ok(())
+  }
+
+  pub fn to_json(&self) -> Value {
+    let mut report = Map::new();
+    report.insert(String::from("records"), json!(self.records));
+
+    let operator_totals: Map<String, Value> = self
+      .operator_totals
+      .iter()
+      .map(|(operator, total)| (operator.to_string(), json!(total)))
+      .collect();
+    report.insert(
+      String::from("operator_totals"),
+      Value::Object(operator_totals),
+    );
+
+    for (name, histogram) in self.histograms() {
+      report.insert(String::from(name), histogram_to_json(histogram));
+    }
+
+    Value::Object(report)
+  }
+}
+
+pub fn run(args: &Arguments) {
+  info!("{} - Collect statistics: {:?}", module_path!(), args.input);
+
+  if Path::new(&args.input).exists() {
+    stats(args);
+  } else {
+    error!("{} - Not Found: {:?}", module_path!(), args.input);
+    std::process::exit(exitcode::NOINPUT);
+  }
+}
+
+pub fn stats(args: &Arguments) {
+  let mut reader =
+    bam_io::open_reader(&args.input, args.reference.as_deref(), args.n_threads).unwrap();
+  let mut stats = Stats::default();
+
+  for record in reader.records() {
+    stats.add(&record.unwrap());
+  }
+
+  let mut writer: Box<dyn Write> = match &args.output {
+    Some(output) => Box::new(File::create(output).unwrap()),
+    None => Box::new(io::stdout()),
+  };
+
+  if args.format == "JSON" {
+    serde_json::to_writer_pretty(&mut writer, &stats.to_json()).unwrap();
+    writeln!(writer).unwrap();
+  } else {
+    stats.write_tsv(&mut writer).unwrap();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rust_htslib::bam::record::{Cigar, CigarString};
+
+  fn record(cigar: Vec<Cigar>, mapq: u8, flags: u16) -> Record {
+    let cigar = CigarString(cigar);
+    let len = cigar
+      .iter()
+      .filter(|op| !matches!(op, Cigar::Del(_) | Cigar::HardClip(_)));
+    let len = len.map(|op| op.len() as usize).sum();
+    let mut record = Record::new();
+    record.set(b"read", Some(&cigar), &vec![b'A'; len], &vec![30; len]);
+    record.set_mapq(mapq);
+    record.set_flags(flags);
+    record
+  }
+
+  fn stats() -> Stats {
+    let mut stats = Stats::default();
+    stats.add(&record(
+      vec![
+        Cigar::HardClip(5),
+        Cigar::SoftClip(10),
+        Cigar::Match(80),
+        Cigar::Ins(2),
+        Cigar::Match(8),
+      ],
+      60,
+      0,
+    ));
+    stats.add(&record(
+      vec![Cigar::Match(50), Cigar::Del(3), Cigar::Match(50)],
+      20,
+      0,
+    ));
+    // Secondary and supplementary, skipped.
+    stats.add(&record(vec![Cigar::Match(100)], 0, 0x100));
+    stats.add(&record(vec![Cigar::Match(100)], 0, 0x800));
+    stats
+  }
+
+  #[test]
+  fn add() {
+    let stats = stats();
+    assert_eq!(stats.records, 2);
+    assert_eq!(stats.operator_totals[&'M'], 188);
+    assert_eq!(stats.operator_totals[&'S'], 10);
+    assert_eq!(stats.operator_totals[&'X'], 0);
+    assert_eq!(stats.soft_clip_length, vec![(10, 1)].into_iter().collect());
+    assert_eq!(stats.deletion_length, vec![(3, 1)].into_iter().collect());
+    assert_eq!(stats.mapq, vec![(20, 1), (60, 1)].into_iter().collect());
+    // The hard clipped bases are part of the read.
+    assert_eq!(
+      stats.read_length,
+      vec![(100, 1), (105, 1)].into_iter().collect()
+    );
+  }
+
+  #[test]
+  fn tsv_and_json() {
+    let stats = stats();
+    let mut tsv = vec![];
+    stats.write_tsv(&mut tsv).unwrap();
+    let tsv = String::from_utf8(tsv).unwrap();
+    let lines = tsv.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], "metric\tvalue\tcount");
+    assert_eq!(lines[1], "records\t-\t2");
+    assert!(lines.contains(&"operator_total\tI\t2"));
+    assert!(lines.contains(&"insertion_length\t2\t1"));
+    assert!(lines.contains(&"read_length\t105\t1"));
+
+    let json = stats.to_json();
+    assert_eq!(json["records"], json!(2));
+    assert_eq!(json["operator_totals"]["D"], json!(3));
+    assert_eq!(json["mapq"]["60"], json!(1));
+  }
+}
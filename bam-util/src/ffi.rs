@@ -0,0 +1,321 @@
+//! `Ffi` is the C API of the bam_util shared and static libraries, see `include/bam_util.h`.
+//!
+//! Every function returns a `BamUtilStatus` instead of panicking, the message of the last error of
+//! the calling thread is returned by `bam_util_last_error`. Handles are created by the `*_open` /
+//! `*_compile` functions and must be released with the matching `*_close` / `*_free` function.
+//!
+//! ```c
+//! BamUtilExpression *expr = NULL;
+//! if (bam_util_expression_compile("sum(S) > 20 && ref_len >= 50", &expr) != BAM_UTIL_STATUS_OK) {
+//!   fprintf(stderr, "%s\n", bam_util_last_error());
+//! }
+//! ```
+use rust_htslib::bam::record::{CigarString, Record};
+use rust_htslib::bam::{Read, Reader};
+
+// Standard
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+// Custom
+use crate::bam::cigar::Expression;
+use crate::bam::util as bam_io;
+
+/// Result of the C API functions.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BamUtilStatus {
+  Ok = 0,
+  /// No more records to read.
+  End = 1,
+  /// A NULL pointer, a string which is not UTF-8 or an invalid value.
+  InvalidArgument = 2,
+  IoError = 3,
+  /// An invalid cigar expression or cigar string.
+  ParseError = 4,
+  /// An unexpected internal error, the library should not be used anymore.
+  Panic = 5,
+}
+
+/// An open BAM/SAM/CRAM file and its current record.
+pub struct BamUtilReader {
+  reader: Reader,
+  record: Record,
+  qname: CString,
+}
+
+/// A compiled cigar expression, see `bam::cigar::Expression`.
+pub struct BamUtilExpression {
+  expression: Expression,
+}
+
+/// Fields of the current record of a reader.
+///
+/// `qname` is owned by the reader and valid until the next call of `bam_util_reader_next`.
+#[repr(C)]
+pub struct BamUtilRecordInfo {
+  pub tid: i32,
+  /// 0-based leftmost position.
+  pub pos: i64,
+  /// 0-based position after the last aligned base.
+  pub end_pos: i64,
+  pub mapq: u8,
+  pub flags: u16,
+  pub qname: *const c_char,
+}
+
+thread_local! {
+  static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+type FfiResult = Result<BamUtilStatus, (BamUtilStatus, String)>;
+
+fn set_last_error(msg: &str) {
+  let msg = CString::new(msg.replace('\0', " ")).unwrap_or_default();
+  LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(msg));
+}
+
+/// Run `f`, record its error or panic as the last error and return its status.
+fn guard<F: FnOnce() -> FfiResult>(f: F) -> BamUtilStatus {
+  match panic::catch_unwind(AssertUnwindSafe(f)) {
+    Ok(Ok(status)) => status,
+    Ok(Err((status, msg))) => {
+      set_last_error(&msg);
+      status
+    }
+    Err(_) => {
+      set_last_error("bam_util panicked, see stderr for details");
+      BamUtilStatus::Panic
+    }
+  }
+}
+
+fn invalid(msg: &str) -> (BamUtilStatus, String) {
+  (BamUtilStatus::InvalidArgument, String::from(msg))
+}
+
+/// Borrow a C string as a str, None for NULL.
+unsafe fn optional_str<'a>(
+  value: *const c_char,
+  name: &str,
+) -> Result<Option<&'a str>, (BamUtilStatus, String)> {
+  if value.is_null() {
+    return Ok(None);
+  }
+
+  CStr::from_ptr(value)
+    .to_str()
+    .map(Some)
+    .map_err(|_| invalid(&format!("{} is not valid UTF-8", name)))
+}
+
+unsafe fn required_str<'a>(
+  value: *const c_char,
+  name: &str,
+) -> Result<&'a str, (BamUtilStatus, String)> {
+  optional_str(value, name)?.ok_or_else(|| invalid(&format!("{} is NULL", name)))
+}
+
+/// Message of the last error of the calling thread, NULL if none.
+///
+/// The string is owned by the library and valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn bam_util_last_error() -> *const c_char {
+  LAST_ERROR.with(|last_error| {
+    last_error
+      .borrow()
+      .as_ref()
+      .map_or(ptr::null(), |msg| msg.as_ptr())
+  })
+}
+
+/// Open a BAM/SAM/CRAM file, `reference` is required for CRAM and may be NULL otherwise.
+///
+/// # Safety
+///
+/// `path` and `reference` must be NULL or valid C strings, `out` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn bam_util_reader_open(
+  path: *const c_char,
+  reference: *const c_char,
+  n_threads: c_int,
+  out: *mut *mut BamUtilReader,
+) -> BamUtilStatus {
+  guard(|| {
+    if out.is_null() {
+      return Err(invalid("out is NULL"));
+    }
+
+    let path = required_str(path, "path")?;
+    let reference = optional_str(reference, "reference")?;
+    let reader = bam_io::open_reader(path, reference, n_threads.max(1) as usize)
+      .map_err(|err| (BamUtilStatus::IoError, format!("{}: {}", path, err)))?;
+
+    *out = Box::into_raw(Box::new(BamUtilReader {
+      reader,
+      record: Record::new(),
+      qname: CString::default(),
+    }));
+    Ok(BamUtilStatus::Ok)
+  })
+}
+
+/// Read the next record, `BAM_UTIL_STATUS_END` at the end of the file.
+///
+/// # Safety
+///
+/// `reader` must be a handle returned by `bam_util_reader_open`.
+#[no_mangle]
+pub unsafe extern "C" fn bam_util_reader_next(reader: *mut BamUtilReader) -> BamUtilStatus {
+  guard(|| {
+    let reader = reader.as_mut().ok_or_else(|| invalid("reader is NULL"))?;
+    match reader.reader.read(&mut reader.record) {
+      None => Ok(BamUtilStatus::End),
+      Some(Err(err)) => Err((BamUtilStatus::IoError, err.to_string())),
+      Some(Ok(())) => {
+        reader.qname = CString::new(reader.record.qname()).unwrap_or_default();
+        Ok(BamUtilStatus::Ok)
+      }
+    }
+  })
+}
+
+/// Fields of the current record, after a successful `bam_util_reader_next`.
+///
+/// # Safety
+///
+/// `reader` must be a handle returned by `bam_util_reader_open`, `out` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn bam_util_reader_record(
+  reader: *const BamUtilReader,
+  out: *mut BamUtilRecordInfo,
+) -> BamUtilStatus {
+  guard(|| {
+    let reader = reader.as_ref().ok_or_else(|| invalid("reader is NULL"))?;
+    let out = out.as_mut().ok_or_else(|| invalid("out is NULL"))?;
+    let record = &reader.record;
+
+    *out = BamUtilRecordInfo {
+      tid: record.tid(),
+      pos: record.pos(),
+      end_pos: record.cigar().end_pos(),
+      mapq: record.mapq(),
+      flags: record.flags(),
+      qname: reader.qname.as_ptr(),
+    };
+    Ok(BamUtilStatus::Ok)
+  })
+}
+
+/// Close a reader, NULL is ignored.
+///
+/// # Safety
+///
+/// `reader` must be NULL or a handle returned by `bam_util_reader_open`, not closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn bam_util_reader_close(reader: *mut BamUtilReader) {
+  if !reader.is_null() {
+    drop(Box::from_raw(reader));
+  }
+}
+
+/// Compile a cigar expression, e.g. `sum(S) > 20 && ref_len >= 50`.
+///
+/// # Safety
+///
+/// `expression` must be a valid C string, `out` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn bam_util_expression_compile(
+  expression: *const c_char,
+  out: *mut *mut BamUtilExpression,
+) -> BamUtilStatus {
+  guard(|| {
+    if out.is_null() {
+      return Err(invalid("out is NULL"));
+    }
+
+    let text = required_str(expression, "expression")?;
+    let expression = Expression::compile(text).ok_or_else(|| {
+      (
+        BamUtilStatus::ParseError,
+        format!("Not valid cigar expression: {:?}", text),
+      )
+    })?;
+
+    *out = Box::into_raw(Box::new(BamUtilExpression { expression }));
+    Ok(BamUtilStatus::Ok)
+  })
+}
+
+/// Evaluate an expression on a cigar string (e.g. `10S90M`) aligned at `pos`, `result` is set to 1
+/// if the expression holds, 0 otherwise.
+///
+/// # Safety
+///
+/// `expression` must be a handle returned by `bam_util_expression_compile`, `cigar` a valid C
+/// string and `result` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn bam_util_expression_eval_cigar(
+  expression: *const BamUtilExpression,
+  cigar: *const c_char,
+  pos: i64,
+  result: *mut c_int,
+) -> BamUtilStatus {
+  guard(|| {
+    let expression = expression
+      .as_ref()
+      .ok_or_else(|| invalid("expression is NULL"))?;
+    let result = result.as_mut().ok_or_else(|| invalid("result is NULL"))?;
+    let text = required_str(cigar, "cigar")?;
+    let cigar = CigarString::try_from(text).map_err(|_| {
+      (
+        BamUtilStatus::ParseError,
+        format!("Not valid cigar string: {:?}", text),
+      )
+    })?;
+
+    *result = expression.expression.eval(&cigar.into_view(pos)) as c_int;
+    Ok(BamUtilStatus::Ok)
+  })
+}
+
+/// Evaluate an expression on the current record of a reader, `result` is set to 1 if the
+/// expression holds, 0 otherwise.
+///
+/// # Safety
+///
+/// `expression` and `reader` must be handles returned by `bam_util_expression_compile` and
+/// `bam_util_reader_open`, `result` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn bam_util_expression_eval_record(
+  expression: *const BamUtilExpression,
+  reader: *const BamUtilReader,
+  result: *mut c_int,
+) -> BamUtilStatus {
+  guard(|| {
+    let expression = expression
+      .as_ref()
+      .ok_or_else(|| invalid("expression is NULL"))?;
+    let reader = reader.as_ref().ok_or_else(|| invalid("reader is NULL"))?;
+    let result = result.as_mut().ok_or_else(|| invalid("result is NULL"))?;
+
+    *result = expression.expression.eval(&reader.record.cigar()) as c_int;
+    Ok(BamUtilStatus::Ok)
+  })
+}
+
+/// Release an expression, NULL is ignored.
+///
+/// # Safety
+///
+/// `expression` must be NULL or a handle returned by `bam_util_expression_compile`, not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn bam_util_expression_free(expression: *mut BamUtilExpression) {
+  if !expression.is_null() {
+    drop(Box::from_raw(expression));
+  }
+}
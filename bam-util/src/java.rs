@@ -0,0 +1,192 @@
+//! `Java` implements the native methods of `omicstools.BamUtil` (omics-tools-clj/src/java), built
+//! with the `jni` feature.
+//!
+//! Errors are thrown as Java exceptions: `IllegalArgumentException` for invalid arguments,
+//! `java.io.FileNotFoundException` for a missing input, `omicstools.OmicsToolsException`
+//! otherwise, and `omicstools.CancelledException` when a progress listener stops the processing.
+//! An exception thrown by a progress listener is left pending and stops the processing. The partial
+//! output of a stopped processing is removed.
+#![allow(non_snake_case)]
+
+use jni::objects::{JClass, JObject, JString, JValue};
+use jni::sys::{jboolean, jint, jlongArray, JNI_FALSE, JNI_TRUE};
+use jni::JNIEnv;
+
+// Standard
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+
+// Custom
+use crate::bam::cigar::Expression;
+use crate::bam::filter;
+
+const ILLEGAL_ARGUMENT: &str = "java/lang/IllegalArgumentException";
+const FILE_NOT_FOUND: &str = "java/io/FileNotFoundException";
+const OMICS_TOOLS_EXCEPTION: &str = "omicstools/OmicsToolsException";
+const CANCELLED_EXCEPTION: &str = "omicstools/CancelledException";
+
+/// A Java exception to throw: class and message.
+type Exception = (&'static str, String);
+
+/// Throw the exception, unless the JVM has one pending already.
+fn throw(env: &JNIEnv, (class, msg): Exception) {
+  if !env.exception_check().unwrap_or(true) {
+    // Nothing else can be done if throwing fails.
+    let _ = env.throw_new(class, msg);
+  }
+}
+
+/// Run `f` and throw its error or panic, `default` is returned to the JVM when an exception is
+/// thrown.
+fn guard<T, F: FnOnce() -> Result<T, Exception>>(env: &JNIEnv, default: T, f: F) -> T {
+  match panic::catch_unwind(AssertUnwindSafe(f)) {
+    Ok(Ok(value)) => value,
+    Ok(Err(exception)) => {
+      throw(env, exception);
+      default
+    }
+    Err(_) => {
+      throw(
+        env,
+        (
+          OMICS_TOOLS_EXCEPTION,
+          String::from("bam_util panicked, see stderr for details"),
+        ),
+      );
+      default
+    }
+  }
+}
+
+/// Convert a Java string, None for null.
+fn optional_string(env: &JNIEnv, value: JString, name: &str) -> Result<Option<String>, Exception> {
+  if value.is_null() {
+    return Ok(None);
+  }
+
+  env
+    .get_string(value)
+    .map(|value| Some(value.into()))
+    .map_err(|err| (ILLEGAL_ARGUMENT, format!("{}: {}", name, err)))
+}
+
+fn required_string(env: &JNIEnv, value: JString, name: &str) -> Result<String, Exception> {
+  optional_string(env, value, name)?.ok_or_else(|| (ILLEGAL_ARGUMENT, format!("{} is null", name)))
+}
+
+/// Call `boolean onProgress(long records)` of the listener, false to stop the processing. A null
+/// listener is ignored. The listener is not called anymore once it returned false or threw, as no
+/// JNI call can be made while its exception is pending.
+fn progress<'a>(env: &'a JNIEnv, listener: JObject<'a>) -> impl FnMut(u64) -> bool + 'a {
+  let mut stopped = false;
+  move |records| {
+    if listener.is_null() {
+      return true;
+    }
+    if stopped {
+      return false;
+    }
+
+    let keep_going = env
+      .call_method(
+        listener,
+        "onProgress",
+        "(J)Z",
+        &[JValue::Long(records as i64)],
+      )
+      .and_then(|keep_going| keep_going.z())
+      .unwrap_or(false);
+    stopped = !keep_going || env.exception_check().unwrap_or(true);
+    !stopped
+  }
+}
+
+/// An error if an exception is pending, e.g. thrown by a listener, before making other JNI calls.
+/// The pending exception is the one the JVM gets, see `throw`.
+fn check_exception(env: &JNIEnv) -> Result<(), Exception> {
+  if env.exception_check().unwrap_or(true) {
+    Err((OMICS_TOOLS_EXCEPTION, String::from("Exception pending")))
+  } else {
+    Ok(())
+  }
+}
+
+/// `static boolean isValidExpression(String expression)`
+#[no_mangle]
+pub extern "system" fn Java_omicstools_BamUtil_isValidExpression(
+  env: JNIEnv,
+  _class: JClass,
+  expression: JString,
+) -> jboolean {
+  guard(&env, JNI_FALSE, || {
+    let expression = required_string(&env, expression, "expression")?;
+    Ok(match Expression::compile(&expression) {
+      Some(_) => JNI_TRUE,
+      None => JNI_FALSE,
+    })
+  })
+}
+
+/// `static long[] filter(String input, String output, String expression, String reference,
+/// int nThreads, ProgressListener listener)`, returns the number of passed and failed reads.
+/// `reference` and `listener` may be null.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub extern "system" fn Java_omicstools_BamUtil_filter(
+  env: JNIEnv,
+  _class: JClass,
+  input: JString,
+  output: JString,
+  expression: JString,
+  reference: JString,
+  n_threads: jint,
+  listener: JObject,
+) -> jlongArray {
+  guard(&env, ptr::null_mut(), || {
+    let input = required_string(&env, input, "input")?;
+    let output = required_string(&env, output, "output")?;
+    let text = required_string(&env, expression, "expression")?;
+    let reference = optional_string(&env, reference, "reference")?;
+
+    if !Path::new(&input).exists() {
+      return Err((FILE_NOT_FOUND, input));
+    }
+
+    let expression = Expression::compile(&text).ok_or_else(|| {
+      (
+        ILLEGAL_ARGUMENT,
+        format!("Not valid cigar expression: {:?}", text),
+      )
+    })?;
+    let summary = filter::filter_by_expression(
+      &input,
+      &output,
+      &expression,
+      reference.as_deref(),
+      n_threads.max(1) as usize,
+      progress(&env, listener),
+    )
+    .map_err(|err| (OMICS_TOOLS_EXCEPTION, format!("{}: {}", input, err)))?;
+
+    if summary.cancelled {
+      let _ = fs::remove_file(&output);
+      check_exception(&env)?;
+      let records = summary.passed + summary.failed;
+      return Err((
+        CANCELLED_EXCEPTION,
+        format!("{}: cancelled after {} reads", input, records),
+      ));
+    }
+    check_exception(&env)?;
+
+    let counts = env
+      .new_long_array(2)
+      .map_err(|err| (OMICS_TOOLS_EXCEPTION, err.to_string()))?;
+    env
+      .set_long_array_region(counts, 0, &[summary.passed as i64, summary.failed as i64])
+      .map_err(|err| (OMICS_TOOLS_EXCEPTION, err.to_string()))?;
+    Ok(counts)
+  })
+}
@@ -4,3 +4,8 @@
 extern crate lazy_static;
 
 pub mod bam;
+pub mod ffi;
+#[cfg(feature = "jni")]
+pub mod java;
+#[cfg(feature = "pyo3")]
+pub mod python;
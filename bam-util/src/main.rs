@@ -9,7 +9,9 @@ use structopt::StructOpt;
 
 // Custom
 pub mod bam_cmd;
-use bam_cmd::filter;
+use bam_cmd::{
+  clip, clips, coverage, downsample, filter, flagstat, insert_size, markdup, split, stats,
+};
 
 /// A suite of programs for interacting with bam file
 #[derive(StructOpt, Debug)]
@@ -34,8 +36,26 @@ struct Opt {
 
 #[derive(Debug, PartialEq, StructOpt)]
 enum SubCommands {
+  #[structopt(name = "clip")]
+  Clip(clip::Arguments),
+  #[structopt(name = "clips")]
+  Clips(clips::Arguments),
+  #[structopt(name = "coverage")]
+  Coverage(coverage::Arguments),
+  #[structopt(name = "downsample")]
+  Downsample(downsample::Arguments),
   #[structopt(name = "filter")]
   Filter(filter::Arguments),
+  #[structopt(name = "flagstat")]
+  FlagStat(flagstat::Arguments),
+  #[structopt(name = "insert-size")]
+  InsertSize(insert_size::Arguments),
+  #[structopt(name = "markdup")]
+  MarkDup(markdup::Arguments),
+  #[structopt(name = "split")]
+  Split(split::Arguments),
+  #[structopt(name = "stats")]
+  Stats(stats::Arguments),
 }
 
 fn main() {
@@ -51,8 +71,35 @@ fn main() {
     .unwrap();
 
   match opt.cmd {
+    SubCommands::Clip(args) => {
+      clip::run(&args);
+    }
+    SubCommands::Clips(args) => {
+      clips::run(&args);
+    }
+    SubCommands::Coverage(args) => {
+      coverage::run(&args);
+    }
+    SubCommands::Downsample(args) => {
+      downsample::run(&args);
+    }
     SubCommands::Filter(args) => {
       filter::run(&args);
     }
+    SubCommands::FlagStat(args) => {
+      flagstat::run(&args);
+    }
+    SubCommands::InsertSize(args) => {
+      insert_size::run(&args);
+    }
+    SubCommands::MarkDup(args) => {
+      markdup::run(&args);
+    }
+    SubCommands::Split(args) => {
+      split::run(&args);
+    }
+    SubCommands::Stats(args) => {
+      stats::run(&args);
+    }
   }
 }
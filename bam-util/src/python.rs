@@ -0,0 +1,69 @@
+//! `Python` is the `bam_util` Python extension module, built with the `pyo3` feature.
+//!
+//! ```python
+//! import pandas as pd
+//! import bam_util
+//!
+//! expr = bam_util.CigarExpression("sum(S) > 20 && ref_len >= 50")
+//! df = pd.DataFrame({"cigar": ["30S70M", "100M"]})
+//! df["clipped"] = expr.eval_many(df["cigar"].tolist())
+//! ```
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::wrap_pyfunction;
+use rust_htslib::bam::record::CigarString;
+
+// Standard
+use std::convert::TryFrom;
+
+// Custom
+use crate::bam::cigar::Expression;
+
+fn parse_cigar(cigar: &str) -> PyResult<CigarString> {
+  CigarString::try_from(cigar)
+    .map_err(|_| PyValueError::new_err(format!("Not valid cigar string: {:?}", cigar)))
+}
+
+/// A compiled cigar expression, see `bam-util filter --help` for the syntax.
+#[pyclass]
+pub struct CigarExpression {
+  expression: Expression,
+}
+
+#[pymethods]
+impl CigarExpression {
+  #[new]
+  fn new(expression: &str) -> PyResult<Self> {
+    Expression::compile(expression)
+      .map(|expression| CigarExpression { expression })
+      .ok_or_else(|| PyValueError::new_err(format!("Not valid cigar expression: {:?}", expression)))
+  }
+
+  /// Whether the expression holds for a cigar string, `pos` is the position of the read.
+  #[args(pos = "0")]
+  fn eval(&self, cigar: &str, pos: i64) -> PyResult<bool> {
+    Ok(self.expression.eval(&parse_cigar(cigar)?.into_view(pos)))
+  }
+
+  /// `eval` for a list of cigar strings, e.g. a column of a pandas.DataFrame.
+  fn eval_many(&self, cigars: Vec<String>) -> PyResult<Vec<bool>> {
+    cigars
+      .iter()
+      .map(|cigar| Ok(self.expression.eval(&parse_cigar(cigar)?.into_view(0))))
+      .collect()
+  }
+}
+
+/// Whether an expression is a valid cigar expression.
+#[pyfunction]
+fn is_valid_expression(expression: &str) -> bool {
+  Expression::compile(expression).is_some()
+}
+
+#[pymodule]
+fn bam_util(_py: Python, module: &PyModule) -> PyResult<()> {
+  module.add_class::<CigarExpression>()?;
+  module.add_function(wrap_pyfunction!(is_valid_expression, module)?)?;
+
+  Ok(())
+}
@@ -0,0 +1,116 @@
+//! `Progress` reports the progress of a long-running command on stderr, e.g. bam-util filter or
+//! vcf-util makedb: a progress bar when stderr is a terminal, a structured log line every
+//! `LOG_INTERVAL` otherwise.
+use indicatif::{ProgressBar, ProgressStyle};
+use log::*;
+
+// Standard
+use std::time::{Duration, Instant};
+
+/// Number of records between two updates.
+pub const TICK_INTERVAL: u64 = 10_000;
+
+/// Time between two log lines when stderr is not a terminal.
+pub const LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+pub struct Progress {
+  name: String,
+  bar: ProgressBar,
+  total_bytes: Option<u64>,
+  start: Instant,
+  last_log: Instant,
+  records: u64,
+  bytes: u64,
+  contig: String,
+}
+
+impl Progress {
+  /// `total_bytes` is the size of the input, a spinner is shown instead of a bar if it is unknown.
+  pub fn new(name: &str, total_bytes: Option<u64>) -> Self {
+    let bar = match total_bytes {
+      Some(total_bytes) => {
+        let bar = ProgressBar::new(total_bytes);
+        bar.set_style(ProgressStyle::default_bar().template(
+          "{spinner} [{elapsed_precise}] [{wide_bar}] {bytes}/{total_bytes} ({eta}) {msg}",
+        ));
+        bar
+      }
+      None => {
+        let bar = ProgressBar::new_spinner();
+        bar.set_style(
+          ProgressStyle::default_spinner().template("{spinner} [{elapsed_precise}] {msg}"),
+        );
+        bar
+      }
+    };
+
+    Progress {
+      name: String::from(name),
+      bar,
+      total_bytes,
+      start: Instant::now(),
+      last_log: Instant::now(),
+      records: 0,
+      bytes: 0,
+      contig: String::new(),
+    }
+  }
+
+  /// Count a record. `bytes` (read from the input so far) and `contig` (of the record) are only
+  /// evaluated every `TICK_INTERVAL` records.
+  pub fn tick<B, C>(&mut self, bytes: B, contig: C)
+  where
+    B: FnOnce() -> Option<u64>,
+    C: FnOnce() -> String,
+  {
+    self.records += 1;
+    if !self.records.is_multiple_of(TICK_INTERVAL) {
+      return;
+    }
+
+    if let Some(bytes) = bytes() {
+      self.bytes = bytes;
+    }
+    self.contig = contig();
+    self.refresh(false);
+  }
+
+  fn records_per_sec(&self) -> f64 {
+    let secs = self.start.elapsed().as_secs_f64();
+    if secs > 0.0 {
+      self.records as f64 / secs
+    } else {
+      0.0
+    }
+  }
+
+  fn refresh(&mut self, force_log: bool) {
+    if !self.bar.is_hidden() {
+      self.bar.set_position(self.bytes);
+      self.bar.set_message(&format!(
+        "{} records, {:.0} records/s, {}",
+        self.records,
+        self.records_per_sec(),
+        self.contig
+      ));
+    } else if force_log || self.last_log.elapsed() >= LOG_INTERVAL {
+      self.last_log = Instant::now();
+      info!(
+        "progress={} records={} bytes={} total_bytes={} records_per_sec={:.0} contig={} elapsed_secs={}",
+        self.name,
+        self.records,
+        self.bytes,
+        self.total_bytes.map_or(String::from("NA"), |total| total.to_string()),
+        self.records_per_sec(),
+        self.contig,
+        self.start.elapsed().as_secs()
+      );
+    }
+  }
+
+  /// Remove the progress bar, or log the last line.
+  pub fn finish(&mut self) {
+    self.refresh(true);
+    self.bar.finish_and_clear();
+  }
+}
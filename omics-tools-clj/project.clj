@@ -3,6 +3,8 @@
   :url "https://github.com/clinico-omics/omics-tools"
   :license {:name "EPL-2.0 OR GPL-2.0-or-later WITH Classpath-exception-2.0"
             :url "https://www.eclipse.org/legal/epl-2.0/"}
+  :source-paths ["src"]
+  :java-source-paths ["src/java"]
   :test-paths ["test"]
   :resource-paths ["resources"]
   :dependencies [[org.clojure/clojure "1.10.0"]]
@@ -0,0 +1,33 @@
+package omicstools;
+
+/**
+ * Native methods of the bam_util library.
+ */
+public final class BamUtil {
+  static {
+    NativeLibrary.load("bam_util");
+  }
+
+  private BamUtil() {}
+
+  /**
+   * Whether a cigar expression is valid, e.g. {@code sum(S) > 20 && ref_len >= 50}.
+   */
+  public static native boolean isValidExpression(String expression);
+
+  /**
+   * Write the reads matching a cigar expression to the output, as BAM unless the output ends
+   * with .sam or .cram.
+   *
+   * @param reference fasta file, required for CRAM, may be null otherwise
+   * @param listener may be null
+   * @return the number of passed and failed reads
+   * @throws java.io.FileNotFoundException if the input does not exist
+   * @throws IllegalArgumentException if the expression is not valid
+   * @throws CancelledException if the listener stopped the filtering, the output is removed
+   * @throws OmicsToolsException if the filtering fails
+   */
+  public static native long[] filter(String input, String output, String expression,
+      String reference, int nThreads, ProgressListener listener)
+      throws java.io.FileNotFoundException;
+}
@@ -0,0 +1,10 @@
+package omicstools;
+
+/**
+ * Thrown when a progress listener stopped a native call, the partial output is removed.
+ */
+public class CancelledException extends OmicsToolsException {
+  public CancelledException(String message) {
+    super(message);
+  }
+}
@@ -0,0 +1,54 @@
+package omicstools;
+
+import java.io.File;
+import java.io.IOException;
+import java.io.InputStream;
+import java.nio.file.Files;
+import java.nio.file.StandardCopyOption;
+
+/**
+ * Loads the native omics-tools libraries, from java.library.path or from the resources of the jar.
+ */
+final class NativeLibrary {
+  private NativeLibrary() {}
+
+  private static String resourceName(String name) {
+    String os = System.getProperty("os.name");
+    String arch = System.getProperty("os.arch");
+    boolean x86_64 = "x86_64".equals(arch) || "amd64".equals(arch);
+
+    if (x86_64 && "Linux".equals(os)) {
+      return "lib" + name + "-x86_64-linux.so";
+    } else if (x86_64 && "Mac OS X".equals(os)) {
+      return "lib" + name + "-x86_64-macosx.dylib";
+    }
+
+    throw new UnsupportedOperationException("Unsupported platform: " + os + " " + arch);
+  }
+
+  /**
+   * Load lib{name}, e.g. libvcf_util.so for vcf_util.
+   */
+  static void load(String name) {
+    try {
+      System.loadLibrary(name);
+      return;
+    } catch (UnsatisfiedLinkError e) {
+      // Not installed, use the library of the jar.
+    }
+
+    String resource = resourceName(name);
+    try (InputStream input = NativeLibrary.class.getClassLoader().getResourceAsStream(resource)) {
+      if (input == null) {
+        throw new UnsatisfiedLinkError("Not found in the resources: " + resource);
+      }
+
+      File library = File.createTempFile("lib" + name, resource.substring(resource.lastIndexOf('.')));
+      library.deleteOnExit();
+      Files.copy(input, library.toPath(), StandardCopyOption.REPLACE_EXISTING);
+      System.load(library.getAbsolutePath());
+    } catch (IOException e) {
+      throw new UnsatisfiedLinkError("Failed to extract " + resource + ": " + e.getMessage());
+    }
+  }
+}
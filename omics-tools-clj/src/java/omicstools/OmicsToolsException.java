@@ -0,0 +1,10 @@
+package omicstools;
+
+/**
+ * An error of the native omics-tools libraries.
+ */
+public class OmicsToolsException extends RuntimeException {
+  public OmicsToolsException(String message) {
+    super(message);
+  }
+}
@@ -0,0 +1,14 @@
+package omicstools;
+
+/**
+ * Receives the progress of a native call, on the calling thread.
+ */
+public interface ProgressListener {
+  /**
+   * Called with the number of records processed so far.
+   *
+   * @return false to stop the processing, the native call then throws a
+   *     {@link CancelledException}
+   */
+  boolean onProgress(long records);
+}
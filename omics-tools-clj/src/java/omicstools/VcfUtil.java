@@ -0,0 +1,25 @@
+package omicstools;
+
+/**
+ * Native methods of the vcf_util library.
+ */
+public final class VcfUtil {
+  static {
+    NativeLibrary.load("vcf_util");
+  }
+
+  private VcfUtil() {}
+
+  /**
+   * Convert a VCF file (.vcf or .vcf.gz) into a SQLite database, the output must not exist.
+   *
+   * @param listener may be null
+   * @return the number of records inserted
+   * @throws java.io.FileNotFoundException if the input does not exist
+   * @throws IllegalArgumentException if the output exists or the input is not a VCF file
+   * @throws CancelledException if the listener stopped the conversion, the output is removed
+   * @throws OmicsToolsException if the conversion fails
+   */
+  public static native long makedb(String input, String output, ProgressListener listener)
+      throws java.io.FileNotFoundException;
+}
@@ -0,0 +1,129 @@
+#ifndef VCF_UTIL_H
+#define VCF_UTIL_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Result of the C API functions.
+ */
+typedef enum VcfUtilStatus {
+  VCF_UTIL_STATUS_OK = 0,
+  /**
+   * No more records to read.
+   */
+  VCF_UTIL_STATUS_END = 1,
+  /**
+   * A NULL pointer, a string which is not UTF-8 or an invalid value.
+   */
+  VCF_UTIL_STATUS_INVALID_ARGUMENT = 2,
+  VCF_UTIL_STATUS_IO_ERROR = 3,
+  /**
+   * A malformed VCF file.
+   */
+  VCF_UTIL_STATUS_PARSE_ERROR = 4,
+  VCF_UTIL_STATUS_DATABASE_ERROR = 5,
+  /**
+   * An unexpected internal error, the library should not be used anymore.
+   */
+  VCF_UTIL_STATUS_PANIC = 6,
+} VcfUtilStatus;
+
+/**
+ * An open VCF file and its current record.
+ */
+typedef struct VcfUtilReader VcfUtilReader;
+
+/**
+ * Fields of the current record of a reader.
+ *
+ * The strings are owned by the reader and valid until the next call of `vcf_util_reader_next`.
+ * Multiple values are joined by `;` (id, filter) or `,` (alternative).
+ */
+typedef struct VcfUtilRecordInfo {
+  const char *chrom;
+  /**
+   * 1-based position.
+   */
+  uint64_t pos;
+  const char *id;
+  const char *reference;
+  const char *alternative;
+  /**
+   * NaN if missing.
+   */
+  double qual;
+  const char *filter;
+} VcfUtilRecordInfo;
+
+/**
+ * Message of the last error of the calling thread, NULL if none.
+ *
+ * The string is owned by the library and valid until the next failing call on the same thread.
+ */
+const char *vcf_util_last_error(void);
+
+/**
+ * Convert a VCF file (.vcf or .vcf.gz) into a SQLite database, `output` must not exist.
+ *
+ * # Safety
+ *
+ * `input` and `output` must be valid C strings.
+ */
+VcfUtilStatus vcf_util_makedb(const char *input, const char *output);
+
+/**
+ * Open a VCF file, gzipped unless the file name ends with .vcf or .gvcf.
+ *
+ * # Safety
+ *
+ * `path` must be a valid C string, `out` must be a valid pointer.
+ */
+VcfUtilStatus vcf_util_reader_open(const char *path, VcfUtilReader **out);
+
+/**
+ * Read the next record, `VCF_UTIL_STATUS_END` at the end of the file.
+ *
+ * # Safety
+ *
+ * `reader` must be a handle returned by `vcf_util_reader_open`.
+ */
+VcfUtilStatus vcf_util_reader_next(VcfUtilReader *reader);
+
+/**
+ * Fields of the current record, after a successful `vcf_util_reader_next`.
+ *
+ * # Safety
+ *
+ * `reader` must be a handle returned by `vcf_util_reader_open`, `out` must be a valid pointer.
+ */
+VcfUtilStatus vcf_util_reader_record(const VcfUtilReader *reader, VcfUtilRecordInfo *out);
+
+/**
+ * Value of an INFO key of the current record, values joined by `,`. `value` is set to NULL if
+ * the key is missing and to an empty string for a flag.
+ *
+ * The string is owned by the reader and valid until the next call of this function or of
+ * `vcf_util_reader_next`.
+ *
+ * # Safety
+ *
+ * `reader` must be a handle returned by `vcf_util_reader_open`, `key` a valid C string and
+ * `value` a valid pointer.
+ */
+VcfUtilStatus vcf_util_reader_info(VcfUtilReader *reader, const char *key, const char **value);
+
+/**
+ * Close a reader, NULL is ignored.
+ *
+ * # Safety
+ *
+ * `reader` must be NULL or a handle returned by `vcf_util_reader_open`, not closed yet.
+ */
+void vcf_util_reader_close(VcfUtilReader *reader);
+
+#endif /* VCF_UTIL_H */
@@ -0,0 +1,318 @@
+//! `Ffi` is the C API of the vcf_util shared and static libraries, see `include/vcf_util.h`.
+//!
+//! Every function returns a `VcfUtilStatus` instead of panicking, the message of the last error of
+//! the calling thread is returned by `vcf_util_last_error`. Readers are created by
+//! `vcf_util_reader_open` and must be released with `vcf_util_reader_close`.
+//!
+//! ```c
+//! if (vcf_util_makedb("sample.vcf.gz", "sample.db") != VCF_UTIL_STATUS_OK) {
+//!   fprintf(stderr, "%s\n", vcf_util_last_error());
+//! }
+//! ```
+use extern_vcf::{VCFReader, VCFRecord};
+
+// Standard
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::io::BufRead;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+
+// Custom
+use crate::vcf::convertor;
+use crate::vcf::error::Error;
+use crate::vcf::util;
+
+/// Result of the C API functions.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VcfUtilStatus {
+  Ok = 0,
+  /// No more records to read.
+  End = 1,
+  /// A NULL pointer, a string which is not UTF-8 or an invalid value.
+  InvalidArgument = 2,
+  IoError = 3,
+  /// A malformed VCF file.
+  ParseError = 4,
+  DatabaseError = 5,
+  /// An unexpected internal error, the library should not be used anymore.
+  Panic = 6,
+}
+
+/// An open VCF file and its current record.
+pub struct VcfUtilReader {
+  reader: VCFReader<Box<dyn BufRead>>,
+  record: VCFRecord,
+  // C strings of the current record, valid until the next record.
+  chrom: CString,
+  id: CString,
+  reference: CString,
+  alternative: CString,
+  filter: CString,
+  info: CString,
+}
+
+/// Fields of the current record of a reader.
+///
+/// The strings are owned by the reader and valid until the next call of `vcf_util_reader_next`.
+/// Multiple values are joined by `;` (id, filter) or `,` (alternative).
+#[repr(C)]
+pub struct VcfUtilRecordInfo {
+  pub chrom: *const c_char,
+  /// 1-based position.
+  pub pos: u64,
+  pub id: *const c_char,
+  pub reference: *const c_char,
+  pub alternative: *const c_char,
+  /// NaN if missing.
+  pub qual: f64,
+  pub filter: *const c_char,
+}
+
+thread_local! {
+  static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+type FfiResult = Result<VcfUtilStatus, (VcfUtilStatus, String)>;
+
+fn set_last_error(msg: &str) {
+  let msg = CString::new(msg.replace('\0', " ")).unwrap_or_default();
+  LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(msg));
+}
+
+/// Run `f`, record its error or panic as the last error and return its status.
+fn guard<F: FnOnce() -> FfiResult>(f: F) -> VcfUtilStatus {
+  match panic::catch_unwind(AssertUnwindSafe(f)) {
+    Ok(Ok(status)) => status,
+    Ok(Err((status, msg))) => {
+      set_last_error(&msg);
+      status
+    }
+    Err(_) => {
+      set_last_error("vcf_util panicked, see stderr for details");
+      VcfUtilStatus::Panic
+    }
+  }
+}
+
+fn invalid(msg: &str) -> (VcfUtilStatus, String) {
+  (VcfUtilStatus::InvalidArgument, String::from(msg))
+}
+
+unsafe fn required_str<'a>(
+  value: *const c_char,
+  name: &str,
+) -> Result<&'a str, (VcfUtilStatus, String)> {
+  if value.is_null() {
+    return Err(invalid(&format!("{} is NULL", name)));
+  }
+
+  CStr::from_ptr(value)
+    .to_str()
+    .map_err(|_| invalid(&format!("{} is not valid UTF-8", name)))
+}
+
+fn to_c_string(value: &[u8]) -> CString {
+  CString::new(value).unwrap_or_default()
+}
+
+fn join(values: &[Vec<u8>], separator: &[u8]) -> CString {
+  to_c_string(&values.join(separator))
+}
+
+/// Message of the last error of the calling thread, NULL if none.
+///
+/// The string is owned by the library and valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn vcf_util_last_error() -> *const c_char {
+  LAST_ERROR.with(|last_error| {
+    last_error
+      .borrow()
+      .as_ref()
+      .map_or(ptr::null(), |msg| msg.as_ptr())
+  })
+}
+
+fn status(err: &Error) -> VcfUtilStatus {
+  match err {
+    Error::InvalidArgument(_) => VcfUtilStatus::InvalidArgument,
+    Error::Io(_) => VcfUtilStatus::IoError,
+    Error::Header(_) | Error::Parse { .. } | Error::Schema(_) => VcfUtilStatus::ParseError,
+    Error::Database(_) => VcfUtilStatus::DatabaseError,
+  }
+}
+
+/// Convert a VCF file (.vcf or .vcf.gz) into a SQLite database, `output` must not exist.
+///
+/// # Safety
+///
+/// `input` and `output` must be valid C strings.
+#[no_mangle]
+pub unsafe extern "C" fn vcf_util_makedb(
+  input: *const c_char,
+  output: *const c_char,
+) -> VcfUtilStatus {
+  guard(|| {
+    let input = required_str(input, "input")?;
+    let output = required_str(output, "output")?;
+
+    if Path::new(output).exists() {
+      return Err(invalid(&format!("{} exists", output)));
+    }
+
+    if !Path::new(input).exists() {
+      return Err((VcfUtilStatus::IoError, format!("Not Found: {}", input)));
+    }
+
+    if !util::is_vcf_file(input) && !util::is_vcf_gz_file(input) {
+      return Err(invalid(&format!(
+        "{} is not a valid vcf/vcf.gz file",
+        input
+      )));
+    }
+
+    convertor::makedb(input, output)
+      .map(|_| VcfUtilStatus::Ok)
+      .map_err(|err| (status(&err), format!("{}: {}", input, err)))
+  })
+}
+
+/// Open a VCF file, gzipped unless the file name ends with .vcf or .gvcf.
+///
+/// # Safety
+///
+/// `path` must be a valid C string, `out` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn vcf_util_reader_open(
+  path: *const c_char,
+  out: *mut *mut VcfUtilReader,
+) -> VcfUtilStatus {
+  guard(|| {
+    if out.is_null() {
+      return Err(invalid("out is NULL"));
+    }
+
+    let path = required_str(path, "path")?;
+    if !Path::new(path).exists() {
+      return Err((VcfUtilStatus::IoError, format!("Not Found: {}", path)));
+    }
+
+    let reader =
+      convertor::open_reader(path).map_err(|err| (status(&err), format!("{}: {}", path, err)))?;
+    let record = reader.empty_record();
+
+    *out = Box::into_raw(Box::new(VcfUtilReader {
+      reader,
+      record,
+      chrom: CString::default(),
+      id: CString::default(),
+      reference: CString::default(),
+      alternative: CString::default(),
+      filter: CString::default(),
+      info: CString::default(),
+    }));
+    Ok(VcfUtilStatus::Ok)
+  })
+}
+
+/// Read the next record, `VCF_UTIL_STATUS_END` at the end of the file.
+///
+/// # Safety
+///
+/// `reader` must be a handle returned by `vcf_util_reader_open`.
+#[no_mangle]
+pub unsafe extern "C" fn vcf_util_reader_next(reader: *mut VcfUtilReader) -> VcfUtilStatus {
+  guard(|| {
+    let reader = reader.as_mut().ok_or_else(|| invalid("reader is NULL"))?;
+    let has_record = reader
+      .reader
+      .next_record(&mut reader.record)
+      .map_err(|err| (VcfUtilStatus::ParseError, err.to_string()))?;
+
+    if !has_record {
+      return Ok(VcfUtilStatus::End);
+    }
+
+    let record = &reader.record;
+    reader.chrom = to_c_string(&record.chromosome);
+    reader.id = join(&record.id, b";");
+    reader.reference = to_c_string(&record.reference);
+    reader.alternative = join(&record.alternative, b",");
+    reader.filter = join(&record.filter, b";");
+    Ok(VcfUtilStatus::Ok)
+  })
+}
+
+/// Fields of the current record, after a successful `vcf_util_reader_next`.
+///
+/// # Safety
+///
+/// `reader` must be a handle returned by `vcf_util_reader_open`, `out` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn vcf_util_reader_record(
+  reader: *const VcfUtilReader,
+  out: *mut VcfUtilRecordInfo,
+) -> VcfUtilStatus {
+  guard(|| {
+    let reader = reader.as_ref().ok_or_else(|| invalid("reader is NULL"))?;
+    let out = out.as_mut().ok_or_else(|| invalid("out is NULL"))?;
+
+    *out = VcfUtilRecordInfo {
+      chrom: reader.chrom.as_ptr(),
+      pos: reader.record.position,
+      id: reader.id.as_ptr(),
+      reference: reader.reference.as_ptr(),
+      alternative: reader.alternative.as_ptr(),
+      qual: reader.record.qual.unwrap_or(f64::NAN),
+      filter: reader.filter.as_ptr(),
+    };
+    Ok(VcfUtilStatus::Ok)
+  })
+}
+
+/// Value of an INFO key of the current record, values joined by `,`. `value` is set to NULL if
+/// the key is missing and to an empty string for a flag.
+///
+/// The string is owned by the reader and valid until the next call of this function or of
+/// `vcf_util_reader_next`.
+///
+/// # Safety
+///
+/// `reader` must be a handle returned by `vcf_util_reader_open`, `key` a valid C string and
+/// `value` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn vcf_util_reader_info(
+  reader: *mut VcfUtilReader,
+  key: *const c_char,
+  value: *mut *const c_char,
+) -> VcfUtilStatus {
+  guard(|| {
+    let reader = reader.as_mut().ok_or_else(|| invalid("reader is NULL"))?;
+    let value = value.as_mut().ok_or_else(|| invalid("value is NULL"))?;
+    let key = required_str(key, "key")?;
+
+    *value = match reader.record.info(key.as_bytes()) {
+      Some(values) => {
+        reader.info = join(values, b",");
+        reader.info.as_ptr()
+      }
+      None => ptr::null(),
+    };
+    Ok(VcfUtilStatus::Ok)
+  })
+}
+
+/// Close a reader, NULL is ignored.
+///
+/// # Safety
+///
+/// `reader` must be NULL or a handle returned by `vcf_util_reader_open`, not closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn vcf_util_reader_close(reader: *mut VcfUtilReader) {
+  if !reader.is_null() {
+    drop(Box::from_raw(reader));
+  }
+}
@@ -0,0 +1,159 @@
+//! `Java` implements the native methods of `omicstools.VcfUtil` (omics-tools-clj/src/java), built
+//! with the `jni` feature.
+//!
+//! Errors are thrown as Java exceptions: `IllegalArgumentException` for invalid arguments,
+//! `java.io.FileNotFoundException` for a missing input, `omicstools.OmicsToolsException`
+//! otherwise, and `omicstools.CancelledException` when a progress listener stops the processing.
+//! An exception thrown by a progress listener is left pending and stops the processing. The partial
+//! output of a stopped processing is removed.
+#![allow(non_snake_case)]
+
+use jni::objects::{JClass, JObject, JString, JValue};
+use jni::sys::jlong;
+use jni::JNIEnv;
+
+// Standard
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+// Custom
+use crate::vcf::convertor;
+use crate::vcf::util;
+
+const ILLEGAL_ARGUMENT: &str = "java/lang/IllegalArgumentException";
+const FILE_NOT_FOUND: &str = "java/io/FileNotFoundException";
+const OMICS_TOOLS_EXCEPTION: &str = "omicstools/OmicsToolsException";
+const CANCELLED_EXCEPTION: &str = "omicstools/CancelledException";
+
+/// A Java exception to throw: class and message.
+type Exception = (&'static str, String);
+
+/// Throw the exception, unless the JVM has one pending already.
+fn throw(env: &JNIEnv, (class, msg): Exception) {
+  if !env.exception_check().unwrap_or(true) {
+    // Nothing else can be done if throwing fails.
+    let _ = env.throw_new(class, msg);
+  }
+}
+
+/// Run `f` and throw its error or panic, `default` is returned to the JVM when an exception is
+/// thrown.
+fn guard<T, F: FnOnce() -> Result<T, Exception>>(env: &JNIEnv, default: T, f: F) -> T {
+  match panic::catch_unwind(AssertUnwindSafe(f)) {
+    Ok(Ok(value)) => value,
+    Ok(Err(exception)) => {
+      throw(env, exception);
+      default
+    }
+    Err(_) => {
+      throw(
+        env,
+        (
+          OMICS_TOOLS_EXCEPTION,
+          String::from("vcf_util panicked, see stderr for details"),
+        ),
+      );
+      default
+    }
+  }
+}
+
+fn required_string(env: &JNIEnv, value: JString, name: &str) -> Result<String, Exception> {
+  if value.is_null() {
+    return Err((ILLEGAL_ARGUMENT, format!("{} is null", name)));
+  }
+
+  env
+    .get_string(value)
+    .map(|value| value.into())
+    .map_err(|err| (ILLEGAL_ARGUMENT, format!("{}: {}", name, err)))
+}
+
+/// Call `boolean onProgress(long records)` of the listener, false to stop the processing. A null
+/// listener is ignored. The listener is not called anymore once it returned false or threw, as no
+/// JNI call can be made while its exception is pending.
+fn progress<'a>(env: &'a JNIEnv, listener: JObject<'a>) -> impl FnMut(u64) -> bool + 'a {
+  let mut stopped = false;
+  move |records| {
+    if listener.is_null() {
+      return true;
+    }
+    if stopped {
+      return false;
+    }
+
+    let keep_going = env
+      .call_method(
+        listener,
+        "onProgress",
+        "(J)Z",
+        &[JValue::Long(records as i64)],
+      )
+      .and_then(|keep_going| keep_going.z())
+      .unwrap_or(false);
+    stopped = !keep_going || env.exception_check().unwrap_or(true);
+    !stopped
+  }
+}
+
+/// An error if an exception is pending, e.g. thrown by a listener, before making other JNI calls.
+/// The pending exception is the one the JVM gets, see `throw`.
+fn check_exception(env: &JNIEnv) -> Result<(), Exception> {
+  if env.exception_check().unwrap_or(true) {
+    Err((OMICS_TOOLS_EXCEPTION, String::from("Exception pending")))
+  } else {
+    Ok(())
+  }
+}
+
+/// `static long makedb(String input, String output, ProgressListener listener)`, returns the
+/// number of records inserted. `listener` may be null, the output is removed if it stops the
+/// conversion.
+#[no_mangle]
+pub extern "system" fn Java_omicstools_VcfUtil_makedb(
+  env: JNIEnv,
+  _class: JClass,
+  input: JString,
+  output: JString,
+  listener: JObject,
+) -> jlong {
+  guard(&env, 0, || {
+    let input = required_string(&env, input, "input")?;
+    let output = required_string(&env, output, "output")?;
+
+    if Path::new(&output).exists() {
+      return Err((ILLEGAL_ARGUMENT, format!("{} exists", output)));
+    }
+
+    if !Path::new(&input).exists() {
+      return Err((FILE_NOT_FOUND, input));
+    }
+
+    if !util::is_vcf_file(&input) && !util::is_vcf_gz_file(&input) {
+      return Err((
+        ILLEGAL_ARGUMENT,
+        format!("{} is not a valid vcf/vcf.gz file", input),
+      ));
+    }
+
+    let summary = convertor::makedb_with_progress(&input, &output, progress(&env, listener))
+      .map_err(|err| (OMICS_TOOLS_EXCEPTION, format!("{}: {}", input, err)))?;
+
+    if summary.cancelled {
+      // The output did not exist before the conversion.
+      let _ = fs::remove_file(&output);
+      check_exception(&env)?;
+      return Err((
+        CANCELLED_EXCEPTION,
+        format!(
+          "{}: cancelled after {} records",
+          input, summary.records_read
+        ),
+      ));
+    }
+    check_exception(&env)?;
+
+    Ok(summary.rows_written as jlong)
+  })
+}
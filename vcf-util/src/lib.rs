@@ -4,4 +4,9 @@
 extern crate lazy_static;
 extern crate vcf as extern_vcf;
 
+pub mod ffi;
+#[cfg(feature = "jni")]
+pub mod java;
+#[cfg(feature = "pyo3")]
+pub mod python;
 pub mod vcf;
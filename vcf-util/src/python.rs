@@ -0,0 +1,391 @@
+//! `Python` is the `vcf_util` Python extension module, built with the `pyo3` feature.
+//!
+//! ```python
+//! import pandas as pd
+//! import vcf_util
+//!
+//! for record in vcf_util.VcfReader("sample.vcf.gz"):
+//!     print(record["chrom"], record["pos"], record["info"].get("DP"))
+//!
+//! df = pd.DataFrame(vcf_util.read_columns("sample.vcf.gz"))
+//! ```
+use extern_vcf::{VCFReader, VCFRecord, ValueType};
+use pyo3::exceptions::{PyFileNotFoundError, PyIOError, PyInterruptedError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use pyo3::{wrap_pyfunction, PyIterProtocol};
+
+// Standard
+use std::collections::HashMap;
+use std::fs;
+use std::io::BufRead;
+use std::path::Path;
+
+// Custom
+use crate::vcf::convertor;
+use crate::vcf::error::Error;
+use crate::vcf::util;
+
+/// A declared INFO key: name, column name (as in makedb) and type.
+struct InfoField {
+  key: Vec<u8>,
+  column: String,
+  value_type: ValueType,
+}
+
+fn info_fields<R: BufRead>(reader: &VCFReader<R>) -> Vec<InfoField> {
+  let header = reader.header();
+  header
+    .info_list()
+    .filter_map(|key| {
+      let info = header.info(key)?;
+      Some(InfoField {
+        key: key.to_vec(),
+        column: convertor::info_column(&String::from_utf8_lossy(key)),
+        value_type: info.value_type.clone(),
+      })
+    })
+    .collect()
+}
+
+/// IOError for IO errors, ValueError otherwise.
+fn py_error(path: &str, err: Error) -> PyErr {
+  let msg = format!("{}: {}", path, err);
+  match err {
+    Error::Io(_) => PyIOError::new_err(msg),
+    _ => PyValueError::new_err(msg),
+  }
+}
+
+fn open(path: &str) -> PyResult<VCFReader<Box<dyn BufRead>>> {
+  if !Path::new(path).exists() {
+    return Err(PyFileNotFoundError::new_err(format!("Not Found: {}", path)));
+  }
+
+  convertor::open_reader(path).map_err(|err| py_error(path, err))
+}
+
+fn text(value: &[u8]) -> String {
+  String::from_utf8_lossy(value).into_owned()
+}
+
+/// A single INFO value with the type declared in the header, None for `.`, and the text itself
+/// if it does not parse.
+fn typed_value(py: Python, value: &[u8], value_type: &ValueType) -> PyObject {
+  if value == b"." {
+    return py.None();
+  }
+
+  let value = text(value);
+  match value_type {
+    ValueType::Integer => value
+      .parse::<i64>()
+      .map_or_else(|_| value.to_object(py), |value| value.to_object(py)),
+    ValueType::Float => value
+      .parse::<f64>()
+      .map_or_else(|_| value.to_object(py), |value| value.to_object(py)),
+    _ => value.to_object(py),
+  }
+}
+
+/// Value of an INFO key of a record: None if missing, True for a flag, a list if there are
+/// several values.
+fn info_value(py: Python, record: &VCFRecord, field: &InfoField) -> PyObject {
+  match (record.info(&field.key), &field.value_type) {
+    (None, _) => py.None(),
+    (Some(_), ValueType::Flag) => true.to_object(py),
+    (Some(values), value_type) if values.len() == 1 => typed_value(py, &values[0], value_type),
+    (Some(values), value_type) => values
+      .iter()
+      .map(|value| typed_value(py, value, value_type))
+      .collect::<Vec<_>>()
+      .to_object(py),
+  }
+}
+
+fn record_dict(py: Python, record: &VCFRecord, fields: &[InfoField]) -> PyResult<PyObject> {
+  let info = PyDict::new(py);
+  for field in fields {
+    if record.info(&field.key).is_some() {
+      info.set_item(text(&field.key), info_value(py, record, field))?;
+    }
+  }
+
+  let dict = PyDict::new(py);
+  dict.set_item("chrom", text(&record.chromosome))?;
+  dict.set_item("pos", record.position)?;
+  dict.set_item("id", text(&record.id.join(&b';')))?;
+  dict.set_item("ref", text(&record.reference))?;
+  dict.set_item(
+    "alt",
+    record
+      .alternative
+      .iter()
+      .map(|alt| text(alt))
+      .collect::<Vec<_>>(),
+  )?;
+  dict.set_item("qual", record.qual)?;
+  dict.set_item(
+    "filter",
+    record
+      .filter
+      .iter()
+      .map(|filter| text(filter))
+      .collect::<Vec<_>>(),
+  )?;
+  dict.set_item("info", info)?;
+
+  Ok(dict.to_object(py))
+}
+
+/// Iterate over the records of a VCF file (.vcf or .vcf.gz) as dicts with the keys chrom, pos,
+/// id, ref, alt, qual, filter and info.
+#[pyclass(unsendable)]
+pub struct VcfReader {
+  reader: VCFReader<Box<dyn BufRead>>,
+  record: VCFRecord,
+  fields: Vec<InfoField>,
+}
+
+#[pymethods]
+impl VcfReader {
+  #[new]
+  fn new(path: &str) -> PyResult<Self> {
+    let reader = open(path)?;
+    let record = reader.empty_record();
+    let fields = info_fields(&reader);
+
+    Ok(VcfReader {
+      reader,
+      record,
+      fields,
+    })
+  }
+
+  /// Column name -> SQL type of the table made by makedb.
+  fn schema(&self) -> PyResult<HashMap<String, String>> {
+    convertor::infer_schema(&self.reader).map_err(|err| PyValueError::new_err(err.to_string()))
+  }
+}
+
+#[pyproto]
+impl PyIterProtocol for VcfReader {
+  fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+    slf
+  }
+
+  fn __next__(mut slf: PyRefMut<Self>) -> PyResult<Option<PyObject>> {
+    let py = slf.py();
+    let this = &mut *slf;
+
+    match this.reader.next_record(&mut this.record) {
+      Ok(true) => record_dict(py, &this.record, &this.fields).map(Some),
+      Ok(false) => Ok(None),
+      Err(err) => Err(PyValueError::new_err(err.to_string())),
+    }
+  }
+}
+
+/// Column name -> SQL type of the table makedb would make for a VCF file.
+#[pyfunction]
+fn infer_schema(path: &str) -> PyResult<HashMap<String, String>> {
+  convertor::infer_schema(&open(path)?).map_err(|err| py_error(path, err))
+}
+
+/// Values of an INFO key in the records read, with the type declared in the header: no value if
+/// the key is missing from a record, None for `.` and the values which do not parse.
+enum InfoColumn {
+  Integer(Vec<Vec<Option<i64>>>),
+  Float(Vec<Vec<Option<f64>>>),
+  Flag(Vec<bool>),
+  Text(Vec<Vec<Option<String>>>),
+}
+
+impl InfoColumn {
+  fn new(value_type: &ValueType) -> Self {
+    match value_type {
+      ValueType::Integer => InfoColumn::Integer(vec![]),
+      ValueType::Float => InfoColumn::Float(vec![]),
+      ValueType::Flag => InfoColumn::Flag(vec![]),
+      _ => InfoColumn::Text(vec![]),
+    }
+  }
+
+  fn push(&mut self, record: &VCFRecord, key: &[u8]) {
+    let values = record.info(key).map_or(&[][..], |values| &values[..]);
+    match self {
+      InfoColumn::Integer(column) => column.push(values.iter().map(|value| parse(value)).collect()),
+      InfoColumn::Float(column) => column.push(values.iter().map(|value| parse(value)).collect()),
+      InfoColumn::Flag(column) => column.push(record.info(key).is_some()),
+      InfoColumn::Text(column) => column.push(
+        values
+          .iter()
+          .map(|value| Some(text(value)).filter(|value| value != "."))
+          .collect(),
+      ),
+    }
+  }
+
+  fn into_object(self, py: Python) -> PyObject {
+    match self {
+      InfoColumn::Integer(column) => cells(py, column),
+      InfoColumn::Float(column) => cells(py, column),
+      InfoColumn::Flag(column) => column.to_object(py),
+      InfoColumn::Text(column) => cells(py, column),
+    }
+  }
+}
+
+fn parse<T: std::str::FromStr>(value: &[u8]) -> Option<T> {
+  std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+/// A list with a value per record, or a list of values per record (None if the key is missing)
+/// if a record has several values.
+fn cells<T: ToPyObject>(py: Python, column: Vec<Vec<Option<T>>>) -> PyObject {
+  if column.iter().all(|values| values.len() <= 1) {
+    column
+      .into_iter()
+      .map(|values| values.into_iter().next().flatten())
+      .collect::<Vec<_>>()
+      .to_object(py)
+  } else {
+    column
+      .into_iter()
+      .map(|values| Some(values).filter(|values| !values.is_empty()))
+      .collect::<Vec<_>>()
+      .to_object(py)
+  }
+}
+
+/// Read the records of a VCF file as columns, a dict of lists to pass to pandas.DataFrame or
+/// numpy.array. Columns are named as in makedb.
+///
+/// INFO columns hold int, float, bool (flags) or str values as declared in the header, None for
+/// the missing values and the values which do not parse as the declared type. A column whose key
+/// has several values in a record is a list of lists. The values are still converted into Python
+/// objects one by one and held in memory together, use makedb for large files.
+#[pyfunction(limit = "None")]
+fn read_columns(py: Python, path: &str, limit: Option<usize>) -> PyResult<PyObject> {
+  let mut reader = open(path)?;
+  let mut record = reader.empty_record();
+  let fields = info_fields(&reader);
+  let mut chrom = vec![];
+  let mut pos = vec![];
+  let mut id = vec![];
+  let mut reference = vec![];
+  let mut alt = vec![];
+  let mut qual = vec![];
+  let mut filter = vec![];
+  let mut info: Vec<InfoColumn> = fields
+    .iter()
+    .map(|field| InfoColumn::new(&field.value_type))
+    .collect();
+  let mut n_records = 0;
+
+  while limit.map_or(true, |limit| n_records < limit) {
+    match reader.next_record(&mut record) {
+      Ok(true) => {}
+      Ok(false) => break,
+      Err(err) => return Err(PyValueError::new_err(err.to_string())),
+    }
+
+    chrom.push(text(&record.chromosome));
+    pos.push(record.position);
+    id.push(text(&record.id.join(&b';')));
+    reference.push(text(&record.reference));
+    alt.push(text(&record.alternative.join(&b',')));
+    qual.push(record.qual);
+    filter.push(text(&record.filter.join(&b';')));
+    for (column, field) in info.iter_mut().zip(&fields) {
+      column.push(&record, &field.key);
+    }
+    n_records += 1;
+  }
+
+  let dict = PyDict::new(py);
+  dict.set_item("chrom", chrom)?;
+  dict.set_item("pos", pos)?;
+  dict.set_item("id", id)?;
+  dict.set_item("ref", reference)?;
+  dict.set_item("alt", alt)?;
+  dict.set_item("qual", qual)?;
+  dict.set_item("filter", filter)?;
+  for (column, field) in info.into_iter().zip(&fields) {
+    dict.set_item(&field.column, column.into_object(py))?;
+  }
+
+  Ok(dict.to_object(py))
+}
+
+/// Convert a VCF file into a SQLite database and return the number of records inserted.
+///
+/// `progress` is called with the number of records inserted from time to time, makedb stops
+/// when it returns False or raises: the partial output is removed, and InterruptedError or the
+/// exception of `progress` is raised.
+#[pyfunction(progress = "None")]
+fn makedb(py: Python, input: &str, output: &str, progress: Option<PyObject>) -> PyResult<u64> {
+  if Path::new(output).exists() {
+    return Err(PyValueError::new_err(format!("{} exists", output)));
+  }
+
+  if !Path::new(input).exists() {
+    return Err(PyFileNotFoundError::new_err(format!(
+      "Not Found: {}",
+      input
+    )));
+  }
+
+  if !util::is_vcf_file(input) && !util::is_vcf_gz_file(input) {
+    return Err(PyValueError::new_err(format!(
+      "{} is not a valid vcf/vcf.gz file",
+      input
+    )));
+  }
+
+  // The GIL is released during the conversion and only held to call `progress`.
+  let (summary, callback_error) = py.allow_threads(|| {
+    let mut stopped = false;
+    let mut callback_error = None;
+    let summary = convertor::makedb_with_progress(input, output, |n_records| {
+      let progress = match &progress {
+        Some(progress) if !stopped => progress,
+        _ => return !stopped,
+      };
+
+      Python::with_gil(|py| match progress.call1(py, (n_records,)) {
+        Ok(keep_going) => stopped = matches!(keep_going.extract::<bool>(py), Ok(false)),
+        Err(err) => {
+          callback_error = Some(err);
+          stopped = true;
+        }
+      });
+      !stopped
+    });
+    (summary, callback_error)
+  });
+  let summary = summary.map_err(|err| py_error(input, err))?;
+
+  if !summary.cancelled {
+    return Ok(summary.rows_written);
+  }
+
+  // The output did not exist before the conversion.
+  let _ = fs::remove_file(output);
+  Err(callback_error.unwrap_or_else(|| {
+    PyInterruptedError::new_err(format!(
+      "{}: cancelled after {} records",
+      input, summary.records_read
+    ))
+  }))
+}
+
+#[pymodule]
+fn vcf_util(_py: Python, module: &PyModule) -> PyResult<()> {
+  module.add_class::<VcfReader>()?;
+  module.add_function(wrap_pyfunction!(infer_schema, module)?)?;
+  module.add_function(wrap_pyfunction!(read_columns, module)?)?;
+  module.add_function(wrap_pyfunction!(makedb, module)?)?;
+
+  Ok(())
+}
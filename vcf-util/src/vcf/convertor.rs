@@ -2,16 +2,23 @@
 use flate2::read::MultiGzDecoder;
 use log::*;
 use regex::Regex;
-use vcf::{VCFError, VCFReader, VCFRecord, ValueType};
+use vcf::{Number, VCFError, VCFReader, VCFRecord, ValueType};
 
 // Standard Library
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{self, BufRead, BufReader};
+use std::str::Utf8Error;
 use std::{str, vec::Vec};
 
 // Custom
-use super::util;
+use super::database::{self, Summary, VcfDatabase};
+use super::error::{self, Error};
+use super::record::{Layout, Row};
+use super::util::{self, ByteCounter, CountingReader};
+
+/// Name of the table made by makedb.
+pub const TABLE_NAME: &str = "variant";
 
 // VCF
 pub fn get_reader_gz(path: &str) -> Result<VCFReader<BufReader<MultiGzDecoder<File>>>, VCFError> {
@@ -24,83 +31,168 @@ pub fn get_reader(path: &str) -> Result<VCFReader<BufReader<File>>, VCFError> {
   return reader;
 }
 
-fn infer_info_schema<R: BufRead>(
-  reader: &VCFReader<R>,
-  enable_prefix: bool,
-) -> HashMap<String, String> {
+/// The (decompressed) content of a VCF file and the counter of the bytes read from the file.
+pub(crate) fn open_input(path: &str) -> io::Result<(Box<dyn BufRead + Send>, ByteCounter)> {
+  let (file, counter) = CountingReader::new(File::open(path)?);
+  let reader: Box<dyn BufRead + Send> = if util::is_vcf_file(path) {
+    Box::new(BufReader::new(file))
+  } else {
+    Box::new(BufReader::new(MultiGzDecoder::new(file)))
+  };
+
+  Ok((reader, counter))
+}
+
+/// Open a VCF file, gzipped unless the file name ends with .vcf or .gvcf.
+pub fn open_reader(path: &str) -> error::Result<VCFReader<Box<dyn BufRead>>> {
+  let (reader, _) = open_input(path)?;
+  let reader: Box<dyn BufRead> = reader;
+  VCFReader::new(reader).map_err(|err| Error::Header(err.to_string()))
+}
+
+/// Column name of an INFO key.
+pub(crate) fn info_column(key: &str) -> String {
+  remove_non_alphabet(&format!("info_{}", key.to_lowercase()))
+}
+
+fn sql_type(value_type: Option<&ValueType>) -> String {
+  match value_type {
+    Some(ValueType::Integer) => String::from("INTEGER"),
+    Some(ValueType::Float) => String::from("FLOAT"),
+    _ => String::from("VARCHAR(32)"),
+  }
+}
+
+fn infer_info_schema<R: BufRead>(reader: &VCFReader<R>) -> error::Result<HashMap<String, String>> {
   let mut info_schema = HashMap::new();
   let header = reader.header();
-  for key in header.info_list() {
-    let info_key = if enable_prefix {
-      format!("info_{}", str::from_utf8(&key).unwrap().to_lowercase()).to_string()
-    } else {
-      str::from_utf8(&key).unwrap().to_lowercase()
+  for key in into_info_keys(reader)? {
+    let info_value = match header.info(key.as_bytes()) {
+      // The values of the keys which may have several are joined with commas.
+      Some(info) if info.number != Number::Number(1) => String::from("VARCHAR(32)"),
+      info => sql_type(info.map(|info| &info.value_type)),
     };
 
-    let info_value = match header.info(key).unwrap().value_type {
-      ValueType::String => String::from("VARCHAR(32)"),
-      ValueType::Integer => String::from("INTEGER"),
-      ValueType::Flag => String::from("VARCHAR(32)"),
-      ValueType::Character => String::from("VARCHAR(32)"),
-      ValueType::Float => String::from("FLOAT"),
-      _ => String::from("VARCHAR(32)"),
-    };
-
-    info_schema.insert(remove_non_alphabet(&info_key), info_value);
+    let column = info_column(&key);
+    if info_schema.insert(column.clone(), info_value).is_some() {
+      return Err(Error::Schema(format!(
+        "Several INFO keys have the same column name {}",
+        column
+      )));
+    }
   }
 
-  return info_schema;
+  Ok(info_schema)
 }
 
-fn into_info_keys<R: BufRead>(reader: &VCFReader<R>) -> Vec<String> {
-  let info_schema = infer_info_schema(reader, false);
+/// INFO keys declared in the header.
+pub fn into_info_keys<R: BufRead>(reader: &VCFReader<R>) -> error::Result<Vec<String>> {
   let mut keys = vec![];
-  for key in info_schema.keys() {
-    keys.push(key.clone());
+  for key in reader.header().info_list() {
+    let key = str::from_utf8(&key).map_err(|_| {
+      Error::Schema(format!(
+        "INFO key {:?} is not valid UTF-8",
+        String::from_utf8_lossy(&key)
+      ))
+    })?;
+    keys.push(String::from(key));
   }
 
-  return keys;
+  Ok(keys)
 }
 
-fn into_keys(info_keys: &Vec<String>) -> Vec<String> {
-  let mut all_keys = vec![];
-
-  for item in ["chrom", "pos", "id", "ref", "alt", "qual", "filter"].iter() {
-    all_keys.push(String::from(*item));
-  }
+/// Type of the values of an INFO key, from the most to the least specific. Several values are
+/// joined with commas in the table, so they are a `String`.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Observed {
+  Flag,
+  Integer,
+  Float,
+  String,
+}
 
-  for item in info_keys {
-    all_keys.push(format!("info_{}", item));
+fn observe(values: &Vec<Vec<u8>>) -> Observed {
+  if values.len() > 1 {
+    return Observed::String;
   }
 
-  return all_keys;
+  values
+    .iter()
+    .filter(|value| &value[..] != b".")
+    .map(|value| match str::from_utf8(value) {
+      Ok(value) if value.parse::<i64>().is_ok() => Observed::Integer,
+      Ok(value) if value.parse::<f64>().is_ok() => Observed::Float,
+      _ => Observed::String,
+    })
+    .fold(Observed::Flag, |observed, value| {
+      if value > observed {
+        value
+      } else {
+        observed
+      }
+    })
 }
 
-fn into_named_keys(keys: &Vec<String>) -> Vec<String> {
-  let mut all_keys = vec![];
-  for item in keys {
-    all_keys.push(format!(":{}", item));
-  }
+/// INFO keys used by the records but not declared in the header, with the SQL type of the values
+/// observed. `limit` records are read, all of them if None. The type is only inferred when all the
+/// records are read, the keys are text columns otherwise as the other records may hold any value.
+pub fn discover_info_keys<R: BufRead>(
+  reader: &mut VCFReader<R>,
+  limit: Option<u64>,
+) -> error::Result<BTreeMap<String, String>> {
+  let declared: HashSet<Vec<u8>> = reader.header().info_list().cloned().collect();
+  let mut observed: BTreeMap<Vec<u8>, Observed> = BTreeMap::new();
+  let mut vcf_record = reader.empty_record();
+  let mut n_records = 0;
+  let mut complete = false;
+
+  while limit.map_or(true, |limit| n_records < limit) {
+    match reader.next_record(&mut vcf_record) {
+      Ok(true) => n_records += 1,
+      Ok(false) => {
+        complete = true;
+        break;
+      }
+      Err(err) => {
+        // The malformed records are handled by the conversion.
+        warn!(
+          "Stop looking for undeclared INFO keys after {} records: {}",
+          n_records, err
+        );
+        break;
+      }
+    }
 
-  return all_keys;
-}
+    for (key, values) in &vcf_record.info {
+      if declared.contains(key) {
+        continue;
+      }
 
-fn to_info_map(vcf_record: &VCFRecord, keys: &Vec<String>) -> HashMap<String, String> {
-  let mut info = HashMap::new();
-  for key in keys {
-    let info_value = match vcf_record.info(key.to_uppercase().as_bytes()) {
-      Some(info_value) => info_value.to_vec(),
-      None => vec![],
-    };
+      let value = observe(values);
+      let entry = observed.entry(key.clone()).or_insert(value);
+      if value > *entry {
+        *entry = value;
+      }
+    }
+  }
 
-    let value = into_vec_u8(&info_value);
-    info.insert(
-      format!(":info_{}", key),
-      String::from_utf8(value).expect("Found invalid UTF-8"),
-    );
+  let mut keys = BTreeMap::new();
+  for (key, observed) in observed {
+    let key = String::from_utf8(key).map_err(|err| {
+      Error::Schema(format!(
+        "INFO key {:?} is not valid UTF-8",
+        String::from_utf8_lossy(err.as_bytes())
+      ))
+    })?;
+    let sql_type = match observed {
+      Observed::Integer if complete => "INTEGER",
+      Observed::Float if complete => "FLOAT",
+      _ => "VARCHAR(32)",
+    };
+    keys.insert(key, String::from(sql_type));
   }
 
-  return info;
+  Ok(keys)
 }
 
 fn infer_format_schema<R: BufRead>(reader: &VCFReader<R>) -> HashMap<String, String> {
@@ -122,8 +214,9 @@ fn infer_format_schema<R: BufRead>(reader: &VCFReader<R>) -> HashMap<String, Str
   return info_schema;
 }
 
-pub fn infer_schema<R: BufRead>(reader: &VCFReader<R>) -> HashMap<String, String> {
-  let mut schema: HashMap<String, String> = [
+/// Schema of the columns which are not INFO keys.
+pub(crate) fn fixed_schema() -> HashMap<String, String> {
+  [
     ("chrom", "INTEGER"),
     ("pos", "INTEGER"),
     ("id", "VARCHAR(32)"),
@@ -134,26 +227,18 @@ pub fn infer_schema<R: BufRead>(reader: &VCFReader<R>) -> HashMap<String, String
   ]
   .iter()
   .map(|item| (String::from(item.0), String::from(item.1)))
-  .collect();
+  .collect()
+}
 
-  let info_schema = infer_info_schema(reader, true);
+pub fn infer_schema<R: BufRead>(reader: &VCFReader<R>) -> error::Result<HashMap<String, String>> {
+  let mut schema = fixed_schema();
+  let info_schema = infer_info_schema(reader)?;
   // let format_schema = infer_format_schema(reader);
 
   schema.extend(info_schema);
   // schema.extend(format_schema);
 
-  return schema;
-}
-
-fn into_vec_u8(items: &Vec<Vec<u8>>) -> Vec<u8> {
-  let mut record = vec![];
-  for item in items {
-    for i in item {
-      record.push(i.clone());
-    }
-  }
-
-  return record;
+  Ok(schema)
 }
 
 fn f64_into_vec_u8(value: std::option::Option<f64>) -> Vec<u8> {
@@ -163,41 +248,24 @@ fn f64_into_vec_u8(value: std::option::Option<f64>) -> Vec<u8> {
   }
 }
 
-fn f64_into_string(value: std::option::Option<f64>) -> String {
-  match value {
-    None => String::from(""),
-    Some(i) => format!("{}", i),
-  }
-}
-
-fn into_string(items: &Vec<Vec<u8>>) -> String {
-  return items.into_iter().flatten().map(|c| *c as char).collect();
-}
-
-fn vec_u8_to_string(items: &Vec<u8>) -> String {
-  return items.into_iter().map(|c| *c as char).collect();
-}
-
-pub fn into_row_map(vcf_record: &VCFRecord, info_keys: &Vec<String>) -> HashMap<String, String> {
-  let mut record: HashMap<String, String> = HashMap::new();
-  record.insert(
-    String::from(":chrom"),
-    vec_u8_to_string(&vcf_record.chromosome),
-  );
-  record.insert(String::from(":pos"), vcf_record.position.to_string());
-  record.insert(String::from(":id"), into_string(&vcf_record.id));
-  record.insert(
-    String::from(":ref"),
-    vec_u8_to_string(&vcf_record.reference),
-  );
-  record.insert(String::from(":alt"), into_string(&vcf_record.alternative));
-  record.insert(String::from(":qual"), f64_into_string(vcf_record.qual));
-  record.insert(String::from(":filter"), into_string(&vcf_record.filter));
-
-  record.extend(to_info_map(vcf_record, info_keys));
-  // record.extend(to_info_map(&vcf_record, format_keys));
-
-  return record;
+/// Values of a record by parameter name (`:column`), with the columns of `layout`, see
+/// `Layout::new`. An error if an INFO value is not valid UTF-8. See `Layout::view` for converting
+/// many records without a map per record.
+pub fn into_row_map(
+  vcf_record: &VCFRecord,
+  layout: &Layout,
+) -> Result<HashMap<String, String>, Utf8Error> {
+  let mut row = Row::default();
+  layout.view(vcf_record).write_row(&mut row)?;
+
+  Ok(
+    layout
+      .columns()
+      .iter()
+      .map(|column| format!(":{}", column))
+      .zip(row.into_values())
+      .collect(),
+  )
 }
 
 fn remove_non_alphabet(str: &str) -> String {
@@ -209,8 +277,8 @@ fn remove_non_alphabet(str: &str) -> String {
 }
 
 // SQLite
-fn format_ctable(schema: &HashMap<String, String>) -> String {
-  let ctable_prefix = "CREATE TABLE variant (";
+pub(crate) fn format_ctable(schema: &HashMap<String, String>, table_name: &str) -> String {
+  let ctable_prefix = format!("CREATE TABLE {} (", table_name);
   let ctable_suffix = ")";
   let mut ctable_content = String::new();
   for (key, value) in schema {
@@ -225,14 +293,17 @@ fn format_ctable(schema: &HashMap<String, String>) -> String {
   ));
 }
 
-pub fn create_table(db: &mut rusqlite::Connection, schema: &HashMap<String, String>) {
-  let ctable = format_ctable(schema);
+pub fn create_table(
+  db: &mut rusqlite::Connection,
+  schema: &HashMap<String, String>,
+) -> error::Result<()> {
+  let ctable = format_ctable(schema, TABLE_NAME);
   info!("Create Table: {}", ctable);
-  db.execute(&ctable[..], &[] as &[&dyn rusqlite::types::ToSql])
-    .unwrap();
+  db.execute(&ctable[..], &[] as &[&dyn rusqlite::types::ToSql])?;
+  Ok(())
 }
 
-fn format_insert_by_keys(keys: &Vec<String>) -> String {
+pub(crate) fn format_insert_by_keys(keys: &[String], table_name: &str) -> String {
   let joined_keys = keys
     .into_iter()
     .map(|key| key.clone())
@@ -241,13 +312,14 @@ fn format_insert_by_keys(keys: &Vec<String>) -> String {
 
   let values = keys
     .into_iter()
-    .map(|key| format!(":{}", key))
+    .enumerate()
+    .map(|(idx, _)| format!("?{}", idx + 1))
     .collect::<Vec<_>>()
     .join(",");
 
   let insert_query = format!(
     "INSERT INTO {} ({}) VALUES ({})",
-    "variant", joined_keys, values
+    table_name, joined_keys, values
   );
 
   return insert_query;
@@ -268,7 +340,7 @@ fn format_insert(row: &HashMap<String, String>) -> String {
     .collect::<Vec<_>>()
     .join(",");
 
-  let insert_query = format!("INSERT INTO {} ({}) VALUES ({})", "variant", keys, values);
+  let insert_query = format!("INSERT INTO {} ({}) VALUES ({})", TABLE_NAME, keys, values);
 
   return insert_query;
 }
@@ -278,7 +350,7 @@ pub fn insert_row(
   row: &HashMap<String, String>,
 ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
   let insert_query = format_insert(row);
-  let tx = db.transaction().unwrap();
+  let tx = db.transaction()?;
   let row_keys: Vec<String> = row.keys().into_iter().map(|item| item.clone()).collect();
   let row_values: Vec<String> = row.values().map(|item| item.clone()).collect();
 
@@ -287,57 +359,56 @@ pub fn insert_row(
   debug!("Row Values: {:?}", row_values);
 
   {
-    let mut stmt = tx.prepare(&insert_query).expect("tx.prepare() failed");
-    stmt.execute(&row_values).unwrap();
+    let mut stmt = tx.prepare(&insert_query)?;
+    stmt.execute(&row_values)?;
   }
 
-  tx.commit().unwrap();
+  tx.commit()?;
   Ok(row_keys)
 }
 
 pub fn insert_rows<'a, R: BufRead>(
   db: &mut rusqlite::Connection,
   reader: &mut VCFReader<R>,
-) -> Result<Vec<String>, vcf::VCFError> {
-  let tx = db.transaction().unwrap();
-  let mut vcf_record = reader.empty_record();
-
-  let info_keys = into_info_keys(&reader);
-
-  let keys = into_keys(&info_keys);
-  let named_keys = into_named_keys(&keys);
-  let named_keys_str: Vec<&str> = named_keys.iter().map(|s| &**s).collect();
-
-  let insert_query = format_insert_by_keys(&keys);
-  debug!("Insert: {}", insert_query);
-  debug!("Row Keys: {:?}", keys);
-
-  {
-    let mut stmt = tx.prepare(&insert_query).expect("tx.prepare() failed");
-
-    while reader.next_record(&mut vcf_record)? {
-      let m = into_row_map(&vcf_record, &info_keys);
+) -> error::Result<Summary> {
+  insert_rows_with_progress(db, reader, |_| true)
+}
 
-      let converted_values: Vec<(&str, &dyn rusqlite::ToSql)> = named_keys_str
-        .iter()
-        .map(|&k| match m.get(k) {
-          Some(_) => m.get(k).map(|v| (k, v as &dyn rusqlite::ToSql)).unwrap(),
-          None => (k, &"" as &dyn rusqlite::ToSql),
-        })
-        .collect::<Vec<_>>();
+/// Number of records between two calls of the progress callback.
+pub const PROGRESS_INTERVAL: u64 = 100_000;
 
-      stmt.execute_named(&converted_values[..]).unwrap();
-    }
+/// Same as `insert_rows`, `progress` is called with the number of records read every
+/// `PROGRESS_INTERVAL` records and at the end, it stops the insertion early by returning false.
+/// It is not called anymore once it returned false.
+pub fn insert_rows_with_progress<R: BufRead, F: FnMut(u64) -> bool>(
+  db: &mut rusqlite::Connection,
+  reader: &mut VCFReader<R>,
+  mut progress: F,
+) -> error::Result<Summary> {
+  let info_keys = into_info_keys(&reader)?;
+  let mut cancelled = false;
+  let mut summary = database::insert_rows(
+    db,
+    reader,
+    &info_keys,
+    TABLE_NAME,
+    usize::MAX,
+    &mut |records, _: &VCFRecord| {
+      cancelled = records % PROGRESS_INTERVAL == 0 && !progress(records);
+      !cancelled
+    },
+  )?;
+
+  summary.cancelled = cancelled;
+  if !cancelled {
+    progress(summary.records_read);
   }
-
-  tx.commit().unwrap();
-
-  Ok(vec![])
+  Ok(summary)
 }
 
-fn update_db_config(db: &mut rusqlite::Connection) {
+pub(crate) fn update_db_config(db: &mut rusqlite::Connection) -> rusqlite::Result<()> {
   // Improve Write Performance
-  db.pragma_update(None, "synchronous", &"OFF").unwrap();
+  db.pragma_update(None, "synchronous", &"OFF")?;
   info!("Synchronous Mode: OFF");
 
   // db.pragma_update(None, "journal_mode", &"MEMORY").unwrap();
@@ -348,23 +419,103 @@ fn update_db_config(db: &mut rusqlite::Connection) {
 
   // db.pragma_update(None, "cache_size", &10000).unwrap();
   // info!("Cache Size: 10000");
+
+  Ok(())
 }
 
-pub fn makedb(input: &str, output: &str) -> Result<Vec<String>, VCFError> {
-  // let mut conn = rusqlite::Connection::open_in_memory().unwrap();
-  let mut conn = rusqlite::Connection::open(output).unwrap();
+/// Convert a VCF file into the `variant` table of a SQLite database, see `VcfDatabase` for more
+/// options.
+pub fn makedb(input: &str, output: &str) -> error::Result<Summary> {
+  makedb_with_progress(input, output, |_| true)
+}
 
-  update_db_config(&mut conn);
+/// Same as `makedb`, `progress` is called with the number of records read every
+/// `PROGRESS_INTERVAL` records and at the end, it stops the conversion early by returning false.
+/// It is not called anymore once it returned false, see `Summary::cancelled`.
+pub fn makedb_with_progress<F: FnMut(u64) -> bool>(
+  input: &str,
+  output: &str,
+  progress: F,
+) -> error::Result<Summary> {
+  VcfDatabase::builder()
+    .input(input)
+    .output(output)
+    .build()?
+    .run_with_progress(progress)
+}
 
-  if util::is_vcf_file(input) {
-    let mut reader = get_reader(input).unwrap();
-    let schema = infer_schema(&reader);
-    create_table(&mut conn, &schema);
-    insert_rows(&mut conn, &mut reader)
-  } else {
-    let mut reader = get_reader_gz(input).unwrap();
-    let schema = infer_schema(&reader);
-    create_table(&mut conn, &schema);
-    insert_rows(&mut conn, &mut reader)
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  /// Records with the declared DP key and the undeclared XI (integers), XF (integers then a
+  /// float), XS (an integer then a string), XB (a flag) and XA (several integers) keys.
+  fn reader() -> VCFReader<Cursor<Vec<u8>>> {
+    let vcf = "##fileformat=VCFv4.2\n\
+               ##contig=<ID=chr1,length=1000000>\n\
+               ##INFO=<ID=DP,Number=1,Type=Integer,Description=\"Depth\">\n\
+               #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n\
+               chr1\t1\t.\tA\tG\t50\tPASS\tDP=10;XI=1;XF=1;XS=2\n\
+               chr1\t2\t.\tA\tG\t50\tPASS\tDP=12;XI=.;XF=1.5;XS=high;XB\n\
+               chr1\t3\t.\tA\tG,T\t50\tPASS\tDP=8;XI=3;XA=1,2\n";
+    VCFReader::new(Cursor::new(vcf.as_bytes().to_vec())).unwrap()
+  }
+
+  fn keys(limit: Option<u64>) -> Vec<(String, String)> {
+    discover_info_keys(&mut reader(), limit)
+      .unwrap()
+      .into_iter()
+      .collect()
+  }
+
+  fn key(key: &str, sql_type: &str) -> (String, String) {
+    (String::from(key), String::from(sql_type))
+  }
+
+  #[test]
+  fn observed_types() {
+    let observed = |values: &[&str]| {
+      observe(
+        &values
+          .iter()
+          .map(|value| value.as_bytes().to_vec())
+          .collect(),
+      )
+    };
+    assert_eq!(observed(&[]), Observed::Flag);
+    assert_eq!(observed(&["."]), Observed::Flag);
+    assert_eq!(observed(&["1"]), Observed::Integer);
+    assert_eq!(observed(&["1e3"]), Observed::Float);
+    assert_eq!(observed(&["high"]), Observed::String);
+    assert_eq!(observed(&["1", "2"]), Observed::String);
+  }
+
+  #[test]
+  fn discover_all() {
+    let expected = vec![
+      key("XA", "VARCHAR(32)"),
+      key("XB", "VARCHAR(32)"),
+      key("XF", "FLOAT"),
+      key("XI", "INTEGER"),
+      key("XS", "VARCHAR(32)"),
+    ];
+    assert_eq!(keys(None), expected);
+    // A limit beyond the last record reads all of them.
+    assert_eq!(keys(Some(10)), expected);
+  }
+
+  #[test]
+  fn discover_sample() {
+    // The types are not inferred from the first records, the next ones may hold other values.
+    assert_eq!(
+      keys(Some(1)),
+      vec![
+        key("XF", "VARCHAR(32)"),
+        key("XI", "VARCHAR(32)"),
+        key("XS", "VARCHAR(32)"),
+      ]
+    );
+    assert_eq!(keys(Some(3)).len(), 5);
   }
 }
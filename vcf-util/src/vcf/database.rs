@@ -0,0 +1,636 @@
+//! `Database` converts a VCF file into a table of a SQLite database, the library counterpart of
+//! the `makedb` subcommand.
+//!
+//! ```no_run
+//! use vcf_util::vcf::database::VcfDatabase;
+//!
+//! let summary = VcfDatabase::builder()
+//!   .input("sample.vcf.gz")
+//!   .output("samples.db")
+//!   .table_name("sample")
+//!   .build()?
+//!   .run()?;
+//! println!("{} rows in {:?}", summary.rows_written, summary.elapsed);
+//! # Ok::<(), vcf_util::vcf::error::Error>(())
+//! ```
+use log::*;
+use vcf::{VCFReader, VCFRecord};
+
+// Standard Library
+use std::fs::{self, File};
+use std::io::{BufRead, BufWriter, Cursor, Write};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+// Custom
+use super::convertor;
+use super::error::{Error, OnError, Result};
+use super::pipeline::{Input, Pipeline, Table};
+use super::progress::Progress;
+use super::record::{Layout, Row};
+use super::util;
+
+/// Number of lines parsed at once by default.
+pub const DEFAULT_BATCH_SIZE: usize = 10_000;
+
+/// Number of rows inserted per transaction by default.
+pub const DEFAULT_COMMIT_INTERVAL: usize = 100_000;
+
+/// How to find the INFO keys used by the records but not declared in the header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InfoDiscovery {
+  /// Only the declared keys have a column.
+  Off,
+  /// Read the first records, the keys found are text columns.
+  Sample(u64),
+  /// Read the whole file once more.
+  All,
+}
+
+impl FromStr for InfoDiscovery {
+  type Err = String;
+
+  fn from_str(discovery: &str) -> std::result::Result<Self, Self::Err> {
+    match discovery {
+      "off" => Ok(InfoDiscovery::Off),
+      "all" => Ok(InfoDiscovery::All),
+      _ => match discovery.parse::<u64>() {
+        Ok(n_records) if n_records > 0 => Ok(InfoDiscovery::Sample(n_records)),
+        _ => Err(format!("Not valid discovery mode: {:?}", discovery)),
+      },
+    }
+  }
+}
+
+/// Counts of a conversion.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Summary {
+  pub records_read: u64,
+  pub rows_written: u64,
+  /// Malformed records, see `VcfDatabaseBuilder::on_error`.
+  pub skipped: u64,
+  /// Whether the progress callback stopped the conversion early, the output is then partial.
+  pub cancelled: bool,
+  pub elapsed: Duration,
+}
+
+/// A conversion of a VCF file into a table, made by `VcfDatabase::builder()`.
+#[derive(Debug, Clone)]
+pub struct VcfDatabase {
+  input: String,
+  output: String,
+  batch_size: usize,
+  commit_interval: usize,
+  n_threads: usize,
+  table_name: String,
+  include_info: bool,
+  on_error: OnError,
+  reject_file: Option<String>,
+  discover_info: InfoDiscovery,
+  show_progress: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct VcfDatabaseBuilder {
+  input: Option<String>,
+  output: Option<String>,
+  batch_size: usize,
+  commit_interval: usize,
+  n_threads: usize,
+  table_name: String,
+  include_info: bool,
+  on_error: OnError,
+  reject_file: Option<String>,
+  discover_info: InfoDiscovery,
+  show_progress: bool,
+}
+
+impl Default for VcfDatabaseBuilder {
+  fn default() -> Self {
+    VcfDatabaseBuilder {
+      input: None,
+      output: None,
+      batch_size: DEFAULT_BATCH_SIZE,
+      commit_interval: DEFAULT_COMMIT_INTERVAL,
+      n_threads: 1,
+      table_name: String::from(convertor::TABLE_NAME),
+      include_info: true,
+      on_error: OnError::Fail,
+      reject_file: None,
+      discover_info: InfoDiscovery::Off,
+      show_progress: false,
+    }
+  }
+}
+
+impl VcfDatabaseBuilder {
+  /// VCF file to convert, gzipped unless the file name ends with .vcf or .gvcf. Required.
+  pub fn input<S: Into<String>>(mut self, input: S) -> Self {
+    self.input = Some(input.into());
+    self
+  }
+
+  /// SQLite database, created if it does not exist. Required.
+  pub fn output<S: Into<String>>(mut self, output: S) -> Self {
+    self.output = Some(output.into());
+    self
+  }
+
+  /// Number of lines handed to a parser thread at once, `DEFAULT_BATCH_SIZE` by default.
+  pub fn batch_size(mut self, batch_size: usize) -> Self {
+    self.batch_size = batch_size;
+    self
+  }
+
+  /// Number of rows inserted per transaction, `DEFAULT_COMMIT_INTERVAL` by default. The
+  /// transaction is committed at the end of a batch, so it may hold up to `batch_size` more rows.
+  pub fn commit_interval(mut self, commit_interval: usize) -> Self {
+    self.commit_interval = commit_interval;
+    self
+  }
+
+  /// Number of threads parsing the records, 1 by default. The file is read (and decompressed) by
+  /// one more thread and the rows are inserted by the thread calling `run`.
+  pub fn n_threads(mut self, n_threads: usize) -> Self {
+    self.n_threads = n_threads;
+    self
+  }
+
+  /// Table to create, `variant` by default. It must not exist in the database.
+  pub fn table_name<S: Into<String>>(mut self, table_name: S) -> Self {
+    self.table_name = table_name.into();
+    self
+  }
+
+  /// Whether to add a column for each INFO key declared in the header, true by default.
+  pub fn include_info(mut self, include_info: bool) -> Self {
+    self.include_info = include_info;
+    self
+  }
+
+  /// Whether to add a column for the INFO keys used by the records but not declared in the
+  /// header, `InfoDiscovery::Off` by default. Their type is inferred from the values with
+  /// `InfoDiscovery::All`, they are text columns with `InfoDiscovery::Sample` unless the file
+  /// has fewer records.
+  pub fn discover_info(mut self, discover_info: InfoDiscovery) -> Self {
+    self.discover_info = discover_info;
+    self
+  }
+
+  /// Whether to show the progress on stderr, see `progress::Progress`. False by default.
+  pub fn show_progress(mut self, show_progress: bool) -> Self {
+    self.show_progress = show_progress;
+    self
+  }
+
+  /// What to do with a malformed record, `OnError::Fail` by default.
+  pub fn on_error(mut self, on_error: OnError) -> Self {
+    self.on_error = on_error;
+    self
+  }
+
+  /// File of the skipped records: line number, reason and line, separated by tabs.
+  pub fn reject_file<S: Into<String>>(mut self, reject_file: S) -> Self {
+    self.reject_file = Some(reject_file.into());
+    self
+  }
+
+  pub fn build(self) -> Result<VcfDatabase> {
+    let input = self
+      .input
+      .ok_or_else(|| Error::InvalidArgument(String::from("input is required")))?;
+    let output = self
+      .output
+      .ok_or_else(|| Error::InvalidArgument(String::from("output is required")))?;
+
+    for (name, value) in [
+      ("batch_size", self.batch_size),
+      ("commit_interval", self.commit_interval),
+      ("n_threads", self.n_threads),
+    ]
+    .iter()
+    {
+      if *value == 0 {
+        return Err(Error::InvalidArgument(format!(
+          "{} must be greater than 0",
+          name
+        )));
+      }
+    }
+
+    if !is_identifier(&self.table_name) {
+      return Err(Error::InvalidArgument(format!(
+        "{:?} is not a valid table name",
+        self.table_name
+      )));
+    }
+
+    Ok(VcfDatabase {
+      input,
+      output,
+      batch_size: self.batch_size,
+      commit_interval: self.commit_interval,
+      n_threads: self.n_threads,
+      table_name: self.table_name,
+      include_info: self.include_info,
+      on_error: self.on_error,
+      reject_file: self.reject_file,
+      discover_info: self.discover_info,
+      show_progress: self.show_progress,
+    })
+  }
+}
+
+/// Letters, digits and underscores, not starting with a digit.
+fn is_identifier(name: &str) -> bool {
+  match name.chars().next() {
+    Some(first) if !first.is_ascii_digit() => {
+      name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+    _ => false,
+  }
+}
+
+impl VcfDatabase {
+  pub fn builder() -> VcfDatabaseBuilder {
+    VcfDatabaseBuilder::default()
+  }
+
+  pub fn run(&self) -> Result<Summary> {
+    self.run_with_progress(|_| true)
+  }
+
+  /// Same as `run`, `progress` is called with the number of records read every
+  /// `convertor::PROGRESS_INTERVAL` records and at the end, it stops the conversion early by
+  /// returning false. It is not called anymore once it returned false, and the summary is then
+  /// flagged as cancelled.
+  pub fn run_with_progress<F: FnMut(u64) -> bool>(&self, mut progress: F) -> Result<Summary> {
+    let start = Instant::now();
+    let (mut reader, counter) = convertor::open_input(&self.input)?;
+    let (header, header_lines) = util::read_header(&mut reader)?;
+    let input = Input {
+      reader,
+      header,
+      header_lines,
+    };
+    let reader = VCFReader::new(Cursor::new(input.header.clone()))
+      .map_err(|err| Error::Header(err.to_string()))?;
+    let mut rejects = Rejects::new(self.on_error, self.reject_file.as_deref())?;
+    let mut db = rusqlite::Connection::open(&self.output)?;
+    convertor::update_db_config(&mut db)?;
+
+    let (mut schema, mut info_keys) = if self.include_info {
+      (
+        convertor::infer_schema(&reader)?,
+        convertor::into_info_keys(&reader)?,
+      )
+    } else {
+      (convertor::fixed_schema(), vec![])
+    };
+
+    if self.include_info && self.discover_info != InfoDiscovery::Off {
+      let limit = match self.discover_info {
+        InfoDiscovery::Sample(n_records) => Some(n_records),
+        _ => None,
+      };
+      let undeclared =
+        convertor::discover_info_keys(&mut convertor::open_reader(&self.input)?, limit)?;
+      if !undeclared.is_empty() {
+        warn!(
+          "INFO keys not declared in the header of {}: {}",
+          self.input,
+          undeclared.keys().cloned().collect::<Vec<_>>().join(", ")
+        );
+      }
+
+      for (key, sql_type) in undeclared {
+        let column = convertor::info_column(&key);
+        if schema.insert(column.clone(), sql_type).is_some() {
+          return Err(Error::Schema(format!(
+            "Several INFO keys have the same column name {}",
+            column
+          )));
+        }
+        info_keys.push(key);
+      }
+    }
+
+    let ctable = convertor::format_ctable(&schema, &self.table_name);
+    info!("Create Table: {}", ctable);
+    db.execute(&ctable[..], &[] as &[&dyn rusqlite::types::ToSql])?;
+
+    let mut bar = if self.show_progress {
+      let total_bytes = fs::metadata(&self.input)
+        .ok()
+        .map(|metadata| metadata.len());
+      Some(Progress::new("makedb", total_bytes))
+    } else {
+      None
+    };
+
+    let pipeline = Pipeline {
+      batch_size: self.batch_size,
+      commit_interval: self.commit_interval,
+      n_threads: self.n_threads,
+    };
+    let table = Table::new(&self.table_name, &info_keys, &schema);
+    let mut cancelled = false;
+    let mut summary = pipeline.run(&mut db, input, table, &mut rejects, |records, contig| {
+      if let Some(bar) = &mut bar {
+        bar.tick(|| Some(counter.bytes()), || String::from(contig));
+      }
+
+      cancelled = records % convertor::PROGRESS_INTERVAL == 0 && !progress(records);
+      !cancelled
+    })?;
+    if let Some(bar) = &mut bar {
+      bar.finish();
+    }
+    summary.cancelled = cancelled;
+    if !cancelled {
+      progress(summary.records_read);
+    }
+    rejects.flush()?;
+    summary.elapsed = start.elapsed();
+    info!(
+      "{} records read, {} rows written into {}, {} skipped in {:?}",
+      summary.records_read, summary.rows_written, self.table_name, summary.skipped, summary.elapsed
+    );
+
+    Ok(summary)
+  }
+}
+
+/// Handling of the malformed records of a conversion.
+pub(crate) struct Rejects {
+  on_error: OnError,
+  writer: Option<BufWriter<File>>,
+}
+
+impl Rejects {
+  pub(crate) fn new(on_error: OnError, reject_file: Option<&str>) -> Result<Self> {
+    let writer = match reject_file {
+      Some(path) => {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(b"#line\treason\trecord\n")?;
+        Some(writer)
+      }
+      None => None,
+    };
+
+    Ok(Rejects { on_error, writer })
+  }
+
+  /// Skip the record at `line`, or an error if malformed records are not skipped. `content` is
+  /// the line without the line terminator.
+  pub(crate) fn reject(&mut self, line: u64, content: &[u8], reason: String) -> Result<()> {
+    match self.on_error {
+      OnError::Fail => return Err(Error::Parse { line, msg: reason }),
+      OnError::Warn => warn!("Skip the record at line {}: {}", line, reason),
+      OnError::Skip => {}
+    }
+
+    if let Some(writer) = &mut self.writer {
+      let reason = reason.replace(|c| c == '\t' || c == '\n', " ");
+      writer.write_all(format!("{}\t{}\t", line, reason).as_bytes())?;
+      writer.write_all(content)?;
+      writer.write_all(b"\n")?;
+    }
+
+    Ok(())
+  }
+
+  pub(crate) fn flush(&mut self) -> Result<()> {
+    if let Some(writer) = &mut self.writer {
+      writer.flush()?;
+    }
+
+    Ok(())
+  }
+}
+
+/// Insert the records of a reader into an existing table, committing every `batch_size` rows. A
+/// malformed record is an error, see `Pipeline` for skipping them.
+///
+/// `on_record` is called after each record with the number of records read, it stops the
+/// insertion early by returning false.
+pub(crate) fn insert_rows<R: BufRead, F: FnMut(u64, &VCFRecord) -> bool>(
+  db: &mut rusqlite::Connection,
+  reader: &mut VCFReader<R>,
+  info_keys: &Vec<String>,
+  table_name: &str,
+  batch_size: usize,
+  on_record: &mut F,
+) -> Result<Summary> {
+  let layout = Layout::new(info_keys);
+  let insert_query = convertor::format_insert_by_keys(layout.columns(), table_name);
+  debug!("Insert: {}", insert_query);
+  debug!("Row Keys: {:?}", layout.columns());
+
+  // Meta-information lines and the #CHROM line, for the line numbers.
+  let header_lines = reader.header().items().len() as u64 + 1;
+  let mut vcf_record = reader.empty_record();
+  let mut row = Row::default();
+  let mut summary = Summary::default();
+  let mut done = false;
+
+  while !done {
+    let tx = db.transaction()?;
+    {
+      let mut stmt = tx.prepare_cached(&insert_query)?;
+      let mut n_rows = 0;
+
+      while n_rows < batch_size {
+        let line = header_lines + summary.records_read + 1;
+        match reader.next_record(&mut vcf_record) {
+          Ok(false) => {
+            done = true;
+            break;
+          }
+          Ok(true) => layout
+            .view(&vcf_record)
+            .write_row(&mut row)
+            .map_err(|err| Error::Parse {
+              line,
+              msg: format!("Invalid INFO value: {}", err),
+            })?,
+          Err(err) => {
+            return Err(Error::Parse {
+              line,
+              msg: err.to_string(),
+            })
+          }
+        }
+        summary.records_read += 1;
+
+        stmt.execute(row.values())?;
+        summary.rows_written += 1;
+        n_rows += 1;
+
+        if !on_record(summary.records_read, &vcf_record) {
+          done = true;
+          break;
+        }
+      }
+    }
+    tx.commit()?;
+  }
+
+  Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn builder() -> VcfDatabaseBuilder {
+    VcfDatabase::builder()
+      .input("sample.vcf.gz")
+      .output("sample.db")
+  }
+
+  fn invalid_argument(result: Result<VcfDatabase>) -> String {
+    match result {
+      Err(Error::InvalidArgument(msg)) => msg,
+      Err(err) => panic!("Not an invalid argument: {}", err),
+      Ok(database) => panic!("Built with invalid arguments: {:?}", database),
+    }
+  }
+
+  #[test]
+  fn build() {
+    let database = builder().build().unwrap();
+    assert_eq!(database.batch_size, DEFAULT_BATCH_SIZE);
+    assert_eq!(database.commit_interval, DEFAULT_COMMIT_INTERVAL);
+    assert_eq!(database.n_threads, 1);
+    assert_eq!(database.table_name, convertor::TABLE_NAME);
+    assert_eq!(database.discover_info, InfoDiscovery::Off);
+
+    let database = builder()
+      .table_name("sample_1")
+      .n_threads(4)
+      .build()
+      .unwrap();
+    assert_eq!(database.table_name, "sample_1");
+    assert_eq!(database.n_threads, 4);
+  }
+
+  #[test]
+  fn build_invalid() {
+    assert_eq!(
+      invalid_argument(VcfDatabase::builder().output("sample.db").build()),
+      "input is required"
+    );
+    assert_eq!(
+      invalid_argument(VcfDatabase::builder().input("sample.vcf.gz").build()),
+      "output is required"
+    );
+    assert_eq!(
+      invalid_argument(builder().batch_size(0).build()),
+      "batch_size must be greater than 0"
+    );
+    assert_eq!(
+      invalid_argument(builder().commit_interval(0).build()),
+      "commit_interval must be greater than 0"
+    );
+    assert_eq!(
+      invalid_argument(builder().n_threads(0).build()),
+      "n_threads must be greater than 0"
+    );
+    assert_eq!(
+      invalid_argument(builder().table_name("variant; DROP TABLE variant").build()),
+      "\"variant; DROP TABLE variant\" is not a valid table name"
+    );
+  }
+
+  #[test]
+  fn identifiers() {
+    for name in &["variant", "_variant", "sample_1", "S1"] {
+      assert!(is_identifier(name), "{}", name);
+    }
+
+    for name in &["", "1sample", "my-table", "a b", "\"variant\"", "variänt"] {
+      assert!(!is_identifier(name), "{}", name);
+    }
+  }
+
+  #[test]
+  fn discovery_modes() {
+    assert_eq!("off".parse::<InfoDiscovery>(), Ok(InfoDiscovery::Off));
+    assert_eq!("all".parse::<InfoDiscovery>(), Ok(InfoDiscovery::All));
+    assert_eq!(
+      "1000".parse::<InfoDiscovery>(),
+      Ok(InfoDiscovery::Sample(1000))
+    );
+    assert!("0".parse::<InfoDiscovery>().is_err());
+    assert!("some".parse::<InfoDiscovery>().is_err());
+  }
+
+  fn reject_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("{}-{}.tsv", name, std::process::id()));
+    path.to_string_lossy().into_owned()
+  }
+
+  #[test]
+  fn rejects_format() {
+    let path = reject_path("database-rejects");
+    let mut rejects = Rejects::new(OnError::Skip, Some(&path)).unwrap();
+    rejects
+      .reject(
+        7,
+        b"chr1\tseven\t.\tA\tG\t50\tPASS\t.",
+        String::from("Invalid POS"),
+      )
+      .unwrap();
+    rejects
+      .reject(
+        12,
+        b"chr1\t12\t.\tA\tG\t50\tPASS\tDP=high",
+        String::from("Invalid INFO value:\tDP\nhigh"),
+      )
+      .unwrap();
+    rejects.flush().unwrap();
+    let rejected = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(
+      rejected,
+      "#line\treason\trecord\n\
+       7\tInvalid POS\tchr1\tseven\t.\tA\tG\t50\tPASS\t.\n\
+       12\tInvalid INFO value: DP high\tchr1\t12\t.\tA\tG\t50\tPASS\tDP=high\n"
+    );
+  }
+
+  #[test]
+  fn rejects_modes() {
+    let path = reject_path("database-rejects-fail");
+    let mut rejects = Rejects::new(OnError::Fail, Some(&path)).unwrap();
+    match rejects.reject(7, b"chr1", String::from("Invalid POS")) {
+      Err(Error::Parse { line, msg }) => {
+        assert_eq!(line, 7);
+        assert_eq!(msg, "Invalid POS");
+      }
+      _ => panic!("A malformed record is an error with OnError::Fail"),
+    }
+    rejects.flush().unwrap();
+    let rejected = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(rejected, "#line\treason\trecord\n");
+
+    let path = reject_path("database-rejects-warn");
+    let mut rejects = Rejects::new(OnError::Warn, Some(&path)).unwrap();
+    rejects
+      .reject(7, b"chr1", String::from("Invalid POS"))
+      .unwrap();
+    rejects.flush().unwrap();
+    let rejected = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(rejected, "#line\treason\trecord\n7\tInvalid POS\tchr1\n");
+
+    let mut rejects = Rejects::new(OnError::Skip, None).unwrap();
+    rejects
+      .reject(7, b"chr1", String::from("Invalid POS"))
+      .unwrap();
+    rejects.flush().unwrap();
+  }
+}
@@ -0,0 +1,64 @@
+//! `Error` is the error type of the vcf_util library.
+use thiserror::Error;
+
+// Standard Library
+use std::io;
+use std::str::FromStr;
+
+#[derive(Debug, Error)]
+pub enum Error {
+  /// A missing or invalid option, e.g. of the `VcfDatabase` builder.
+  #[error("Invalid argument: {0}")]
+  InvalidArgument(String),
+
+  #[error("IO error: {0}")]
+  Io(#[from] io::Error),
+
+  /// A malformed VCF header.
+  #[error("Invalid VCF header: {0}")]
+  Header(String),
+
+  /// A malformed VCF record, `line` is the 1-based line number in the (decompressed) file.
+  #[error("Invalid VCF record at line {line}: {msg}")]
+  Parse { line: u64, msg: String },
+
+  /// A VCF header which can not be mapped to a table, e.g. two INFO keys with the same column
+  /// name.
+  #[error("Invalid schema: {0}")]
+  Schema(String),
+
+  #[error("Database error: {0}")]
+  Database(#[from] rusqlite::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// What to do with a malformed record.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnError {
+  /// Stop with an error.
+  Fail,
+  /// Skip the record.
+  Skip,
+  /// Skip the record and log a warning.
+  Warn,
+}
+
+impl Default for OnError {
+  fn default() -> Self {
+    OnError::Fail
+  }
+}
+
+impl FromStr for OnError {
+  type Err = String;
+
+  fn from_str(on_error: &str) -> std::result::Result<Self, Self::Err> {
+    match on_error {
+      "fail" => Ok(OnError::Fail),
+      "skip" => Ok(OnError::Skip),
+      "warn" => Ok(OnError::Warn),
+      _ => Err(format!("Not valid error mode: {:?}", on_error)),
+    }
+  }
+}
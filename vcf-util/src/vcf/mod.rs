@@ -1,4 +1,9 @@
 //! `Convertor` is a suite of programs for interacting with VCF file, e.g. filtering with some conditions.
 
 pub mod convertor;
-pub mod util;
\ No newline at end of file
+pub mod database;
+pub mod error;
+pub(crate) mod pipeline;
+pub mod progress;
+pub mod record;
+pub mod util;
@@ -0,0 +1,736 @@
+//! `Pipeline` inserts the records of a VCF file into a table with several threads: a reader
+//! decompressing the file and splitting it into chunks of lines, a pool of parsers turning the
+//! chunks into batches of typed rows, and the calling thread binding the rows in order.
+use log::*;
+use rusqlite::types::{ToSqlOutput, ValueRef};
+use vcf::VCFReader;
+
+// Standard Library
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, BufReader};
+use std::ops::Range;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// Custom
+use super::convertor;
+use super::database::{Rejects, Summary};
+use super::error::{Error, Result};
+use super::record::{Layout, Row};
+use super::util::LineFeed;
+
+/// Lines read from the file, `lines` are the line numbers of the records in `data`.
+struct Chunk {
+  seq: u64,
+  lines: Vec<u64>,
+  data: Vec<u8>,
+}
+
+enum Item {
+  /// A row of `Batch::values`.
+  Row,
+  Rejected {
+    line: u64,
+    content: Vec<u8>,
+    reason: String,
+  },
+}
+
+/// A value of a row, its text is a range of `Batch::text`.
+#[derive(Debug, Clone, PartialEq)]
+enum Typed {
+  Integer(i64),
+  Real(f64),
+  Text(Range<usize>),
+}
+
+/// The parsed records of a chunk, in the order of the file.
+struct Batch {
+  seq: u64,
+  items: Vec<Item>,
+  /// Values of the rows, one after the other.
+  values: Vec<Typed>,
+  /// Text of the values, one after the other.
+  text: String,
+  /// Chromosome of the last record.
+  contig: String,
+}
+
+/// Columns of the table and their SQL type.
+pub(crate) struct Table {
+  name: String,
+  layout: Layout,
+  types: Vec<String>,
+}
+
+impl Table {
+  /// `schema` maps the column names to their SQL type, see `convertor::infer_schema`.
+  pub(crate) fn new(name: &str, info_keys: &[String], schema: &HashMap<String, String>) -> Self {
+    let layout = Layout::new(info_keys);
+    // CHROM is a name, stored as text by its INTEGER column unless it is a number.
+    let types = layout
+      .columns()
+      .iter()
+      .map(|column| match column.as_str() {
+        "chrom" => String::new(),
+        _ => schema.get(column).cloned().unwrap_or_default(),
+      })
+      .collect();
+
+    Table {
+      name: String::from(name),
+      layout,
+      types,
+    }
+  }
+
+  /// Append the values of `row` to `batch`, typed by column. An error if a value of an INTEGER or
+  /// FLOAT column is not a number, nothing is appended then.
+  fn push_values(&self, row: &Row, batch: &mut Batch) -> std::result::Result<(), String> {
+    let (start, text_start) = (batch.values.len(), batch.text.len());
+    let columns = self.layout.columns().iter().zip(&self.types);
+
+    for (value, (column, sql_type)) in row.values().iter().zip(columns) {
+      match typed_value(value, sql_type, &mut batch.text) {
+        Some(typed) => batch.values.push(typed),
+        None => {
+          batch.values.truncate(start);
+          batch.text.truncate(text_start);
+          return Err(format!(
+            "Invalid {} value for column {}: {:?}",
+            sql_type, column, value
+          ));
+        }
+      }
+    }
+
+    Ok(())
+  }
+}
+
+/// A file split after the header, see `util::read_header`.
+pub(crate) struct Input {
+  pub reader: Box<dyn BufRead + Send>,
+  pub header: Vec<u8>,
+  /// Number of lines of `header`.
+  pub header_lines: u64,
+}
+
+/// Value bound to a column of type `sql_type`, converted as the column affinity would, with its
+/// text appended to `text`. `None` if the value of an INTEGER or FLOAT column is not a number, a
+/// missing value (empty or `.`) is bound as is.
+fn typed_value(value: &str, sql_type: &str, text: &mut String) -> Option<Typed> {
+  let number = match sql_type {
+    _ if value.is_empty() || value == "." => None,
+    "INTEGER" => Some(
+      value
+        .parse::<i64>()
+        .map(Typed::Integer)
+        .or_else(|_| value.parse::<f64>().map(Typed::Real)),
+    ),
+    "FLOAT" => Some(value.parse::<f64>().map(Typed::Real)),
+    _ => None,
+  };
+
+  match number {
+    // Stored as NULL by SQLite.
+    Some(Ok(Typed::Real(number))) if !number.is_finite() => {}
+    Some(Ok(number)) => return Some(number),
+    Some(Err(_)) => return None,
+    None => {}
+  }
+
+  let start = text.len();
+  text.push_str(value);
+  Some(Typed::Text(start..text.len()))
+}
+
+/// Parameters of the insert statement for a row of `Batch::values`, borrowing their text.
+fn params<'a>(row: &'a [Typed], text: &'a str) -> impl Iterator<Item = ToSqlOutput<'a>> {
+  row.iter().map(move |value| {
+    ToSqlOutput::Borrowed(match value {
+      Typed::Integer(value) => ValueRef::Integer(*value),
+      Typed::Real(value) => ValueRef::Real(*value),
+      Typed::Text(range) => ValueRef::Text(text[range.clone()].as_bytes()),
+    })
+  })
+}
+
+fn read_chunks(
+  mut reader: Box<dyn BufRead + Send>,
+  mut line: u64,
+  batch_size: usize,
+  chunks: SyncSender<Chunk>,
+) -> io::Result<()> {
+  for seq in 0.. {
+    let mut chunk = Chunk {
+      seq,
+      lines: Vec::with_capacity(batch_size),
+      data: vec![],
+    };
+
+    while chunk.lines.len() < batch_size {
+      let start = chunk.data.len();
+      if reader.read_until(b'\n', &mut chunk.data)? == 0 {
+        break;
+      }
+      line += 1;
+
+      if chunk.data[start..].iter().all(|c| c.is_ascii_whitespace()) {
+        chunk.data.truncate(start);
+        continue;
+      }
+      if !chunk.data.ends_with(b"\n") {
+        chunk.data.push(b'\n');
+      }
+      chunk.lines.push(line);
+    }
+
+    // The parsers are gone if the conversion stopped early.
+    if chunk.lines.is_empty() || chunks.send(chunk).is_err() {
+      break;
+    }
+  }
+
+  Ok(())
+}
+
+/// The records of the chunks handed to a parser thread, fed line by line to a `VCFReader` which
+/// parsed the header once. The reader and its header are not `Send`, so each thread has its own.
+struct Parser {
+  feed: LineFeed,
+  reader: std::result::Result<VCFReader<BufReader<LineFeed>>, String>,
+}
+
+impl Parser {
+  fn new(header: &[u8]) -> Self {
+    let feed = LineFeed::default();
+    feed.set(header.to_vec());
+    let reader = VCFReader::new(BufReader::new(feed.clone()))
+      .map_err(|err| format!("Invalid VCF header: {}", err));
+
+    Parser { feed, reader }
+  }
+
+  fn parse(&mut self, chunk: Chunk, table: &Table) -> Batch {
+    let mut batch = Batch {
+      seq: chunk.seq,
+      items: Vec::with_capacity(chunk.lines.len()),
+      values: Vec::with_capacity(chunk.lines.len() * table.types.len()),
+      text: String::with_capacity(chunk.data.len()),
+      contig: String::new(),
+    };
+    let reader = match &mut self.reader {
+      Ok(reader) => reader,
+      Err(reason) => {
+        let contents = chunk.data.split(|&c| c == b'\n');
+        for (&line, content) in chunk.lines.iter().zip(contents) {
+          batch.items.push(Item::Rejected {
+            line,
+            content: content.to_vec(),
+            reason: reason.clone(),
+          });
+        }
+        return batch;
+      }
+    };
+    let mut record = reader.empty_record();
+    let mut row = Row::default();
+    let mut contig = vec![];
+    let mut data = self.feed.take();
+
+    let contents = chunk.data.split_inclusive(|&c| c == b'\n');
+    for (&line, content) in chunk.lines.iter().zip(contents) {
+      data.clear();
+      data.extend_from_slice(content);
+      self.feed.set(data);
+
+      let parsed = match reader.next_record(&mut record) {
+        Ok(true) => table
+          .layout
+          .view(&record)
+          .write_row(&mut row)
+          .map_err(|err| format!("Invalid INFO value: {}", err))
+          .and_then(|()| table.push_values(&row, &mut batch)),
+        Ok(false) => Err(String::from("Not a record")),
+        Err(err) => Err(err.to_string()),
+      };
+      data = self.feed.take();
+
+      match parsed {
+        Ok(()) => {
+          if record.chromosome != contig {
+            contig = record.chromosome.clone();
+          }
+
+          batch.items.push(Item::Row);
+        }
+        Err(reason) => {
+          let mut content = content;
+          while content.ends_with(b"\n") || content.ends_with(b"\r") {
+            content = &content[..content.len() - 1];
+          }
+          batch.items.push(Item::Rejected {
+            line,
+            content: content.to_vec(),
+            reason,
+          });
+        }
+      }
+    }
+
+    batch.contig = String::from_utf8_lossy(&contig).into_owned();
+    batch
+  }
+}
+
+/// Batches received from the parsers, yielded in the order of the file.
+struct InOrder {
+  batches: Receiver<Batch>,
+  pending: BTreeMap<u64, Batch>,
+  next: u64,
+}
+
+impl Iterator for InOrder {
+  type Item = Batch;
+
+  fn next(&mut self) -> Option<Batch> {
+    loop {
+      if let Some(batch) = self.pending.remove(&self.next) {
+        self.next += 1;
+        return Some(batch);
+      }
+
+      match self.batches.recv() {
+        Ok(batch) => {
+          self.pending.insert(batch.seq, batch);
+        }
+        Err(_) => return None,
+      }
+    }
+  }
+}
+
+fn thread_panicked(name: &str) -> Error {
+  Error::Io(io::Error::new(
+    io::ErrorKind::Other,
+    format!("The {} thread panicked", name),
+  ))
+}
+
+/// Options of a `Pipeline`, see `VcfDatabaseBuilder`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Pipeline {
+  /// Number of lines per chunk.
+  pub batch_size: usize,
+  /// Number of rows per transaction, committed at the end of a chunk.
+  pub commit_interval: usize,
+  /// Number of parser threads.
+  pub n_threads: usize,
+}
+
+impl Pipeline {
+  /// Insert the records of `input` into an existing table.
+  ///
+  /// `on_record` is called after each record with the number of records read and the chromosome
+  /// of the batch, it stops the insertion early by returning false.
+  pub(crate) fn run<F: FnMut(u64, &str) -> bool>(
+    &self,
+    db: &mut rusqlite::Connection,
+    input: Input,
+    table: Table,
+    rejects: &mut Rejects,
+    mut on_record: F,
+  ) -> Result<Summary> {
+    let insert_query = convertor::format_insert_by_keys(table.layout.columns(), &table.name);
+    debug!("Insert: {}", insert_query);
+    debug!("Row Keys: {:?}", table.layout.columns());
+
+    let Input {
+      reader,
+      header,
+      header_lines,
+    } = input;
+    let header = Arc::new(header);
+    let table = Arc::new(table);
+    let (chunk_tx, chunk_rx) = mpsc::sync_channel(self.n_threads * 2);
+    let (batch_tx, batch_rx) = mpsc::sync_channel(self.n_threads * 2);
+    let batch_size = self.batch_size;
+    let reader = thread::spawn(move || read_chunks(reader, header_lines, batch_size, chunk_tx));
+
+    let chunk_rx = Arc::new(Mutex::new(chunk_rx));
+    let parsers = (0..self.n_threads)
+      .map(|_| {
+        let chunk_rx = Arc::clone(&chunk_rx);
+        let batch_tx: SyncSender<Batch> = batch_tx.clone();
+        let header = Arc::clone(&header);
+        let table = Arc::clone(&table);
+
+        thread::spawn(move || {
+          let mut parser = Parser::new(&header);
+          drop(header);
+
+          loop {
+            let chunk = match chunk_rx.lock().ok().and_then(|chunks| chunks.recv().ok()) {
+              Some(chunk) => chunk,
+              None => break,
+            };
+
+            if batch_tx.send(parser.parse(chunk, &table)).is_err() {
+              break;
+            }
+          }
+        })
+      })
+      .collect::<Vec<_>>();
+    drop(chunk_rx);
+    drop(batch_tx);
+
+    let mut batches = InOrder {
+      batches: batch_rx,
+      pending: BTreeMap::new(),
+      next: 0,
+    };
+    let mut summary = Summary::default();
+    let mut done = false;
+
+    while !done {
+      let tx = db.transaction()?;
+      {
+        let mut stmt = tx.prepare_cached(&insert_query)?;
+        let mut n_rows = 0;
+
+        while n_rows < self.commit_interval && !done {
+          let batch = match batches.next() {
+            Some(batch) => batch,
+            None => {
+              done = true;
+              break;
+            }
+          };
+
+          let mut rows = batch.values.chunks(table.types.len());
+          for item in batch.items {
+            summary.records_read += 1;
+            match item {
+              Item::Row => {
+                stmt.execute(params(rows.next().unwrap_or_default(), &batch.text))?;
+                summary.rows_written += 1;
+                n_rows += 1;
+              }
+              Item::Rejected {
+                line,
+                content,
+                reason,
+              } => {
+                rejects.reject(line, &content, reason)?;
+                summary.skipped += 1;
+              }
+            }
+
+            if !on_record(summary.records_read, &batch.contig) {
+              done = true;
+              break;
+            }
+          }
+        }
+      }
+      tx.commit()?;
+    }
+
+    // The threads still running stop once the batches are not received anymore.
+    drop(batches);
+    for parser in parsers {
+      parser.join().map_err(|_| thread_panicked("parser"))?;
+    }
+
+    match reader.join() {
+      Ok(result) => result?,
+      Err(_) => return Err(thread_panicked("reader")),
+    }
+    Ok(summary)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::vcf::error::OnError;
+  use std::io::Cursor;
+  use std::time::Instant;
+
+  /// A header declaring `n_contigs` contigs and the DP and AF INFO keys.
+  fn header(n_contigs: usize) -> Vec<u8> {
+    let mut header = String::from("##fileformat=VCFv4.2\n");
+    for contig in 1..=n_contigs {
+      header.push_str(&format!("##contig=<ID=chr{},length=1000000>\n", contig));
+    }
+    header.push_str("##INFO=<ID=DP,Number=1,Type=Integer,Description=\"Depth\">\n");
+    header.push_str("##INFO=<ID=AF,Number=1,Type=Float,Description=\"Frequency\">\n");
+    header.push_str("#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n");
+    header.into_bytes()
+  }
+
+  fn record(pos: u64) -> String {
+    format!(
+      "chr1\t{}\t.\tA\tG\t50\tPASS\tDP={};AF=0.5\n",
+      pos,
+      pos % 100
+    )
+  }
+
+  fn table(header: &[u8]) -> Table {
+    let reader = VCFReader::new(Cursor::new(header.to_vec())).unwrap();
+    let info_keys = convertor::into_info_keys(&reader).unwrap();
+    Table::new(
+      "variant",
+      &info_keys,
+      &convertor::infer_schema(&reader).unwrap(),
+    )
+  }
+
+  /// Chunks of `batch_size` lines, numbered from the line after the header.
+  fn chunks(records: &str, header_lines: u64, batch_size: usize) -> Vec<Chunk> {
+    let (tx, rx) = mpsc::sync_channel(records.len() + 1);
+    let reader = Box::new(Cursor::new(records.as_bytes().to_vec()));
+    read_chunks(reader, header_lines, batch_size, tx).unwrap();
+    rx.into_iter().collect()
+  }
+
+  #[test]
+  fn typed_values() {
+    let typed = |value: &str, sql_type: &str| typed_value(value, sql_type, &mut String::new());
+    assert_eq!(typed("12", "INTEGER"), Some(Typed::Integer(12)));
+    assert_eq!(typed("1.5", "INTEGER"), Some(Typed::Real(1.5)));
+    assert_eq!(typed("1e3", "FLOAT"), Some(Typed::Real(1000.0)));
+    assert_eq!(typed("12x", "INTEGER"), None);
+    assert_eq!(typed("high", "FLOAT"), None);
+
+    let mut text = String::new();
+    let values = [
+      ("chr1", "VARCHAR(32)"),
+      (".", "INTEGER"),
+      ("", "FLOAT"),
+      ("nan", "FLOAT"),
+    ];
+    let values = values
+      .iter()
+      .map(|(value, sql_type)| typed_value(value, sql_type, &mut text))
+      .collect::<Vec<_>>();
+    let expected = vec![0..4, 4..5, 5..5, 5..8];
+    assert_eq!(
+      values,
+      expected
+        .into_iter()
+        .map(|range| Some(Typed::Text(range)))
+        .collect::<Vec<_>>()
+    );
+    assert_eq!(text, "chr1.nan");
+
+    let row = [Typed::Integer(1), Typed::Text(4..5), Typed::Real(0.5)];
+    let params = params(&row, &text).collect::<Vec<_>>();
+    assert_eq!(
+      params,
+      vec![
+        ToSqlOutput::Borrowed(ValueRef::Integer(1)),
+        ToSqlOutput::Borrowed(ValueRef::Text(b".")),
+        ToSqlOutput::Borrowed(ValueRef::Real(0.5)),
+      ]
+    );
+  }
+
+  #[test]
+  fn invalid_number_rejected() {
+    let header = header(1);
+    let table = table(&header);
+    let records = format!(
+      "{}{}",
+      record(1),
+      "chr1\t2\t.\tA\tG\t50\tPASS\tDP=high;AF=0.5\n"
+    );
+    let mut parser = Parser::new(&header);
+    let batch = parser.parse(chunks(&records, 5, 10).remove(0), &table);
+
+    assert!(matches!(batch.items[0], Item::Row));
+    match &batch.items[1] {
+      Item::Rejected { line, reason, .. } => {
+        assert_eq!(*line, 7);
+        assert_eq!(reason, "Invalid INTEGER value for column info_dp: \"high\"");
+      }
+      Item::Row => panic!("DP=high is not an INTEGER"),
+    }
+    assert_eq!(batch.values.len(), table.types.len());
+  }
+
+  #[test]
+  fn several_values() {
+    // AC holds a value per ALT allele.
+    let ac = "##INFO=<ID=AC,Number=A,Type=Integer,Description=\"Count\">\n#CHROM";
+    let header = String::from_utf8(header(1)).unwrap().replace("#CHROM", ac);
+    let header = header.into_bytes();
+    let table = table(&header);
+    let records = "chr1\t1\t.\tA\tG,T\t50\tPASS\tDP=1;AF=0.5;AC=1,2\n\
+                   chr1\t2\t.\tA\tG,T\t50\tPASS\tDP=2;AF=0.1,0.2;AC=3,4\n";
+    let mut parser = Parser::new(&header);
+    let batch = parser.parse(chunks(records, 6, 10).remove(0), &table);
+
+    // The values are joined, the column of AC is text.
+    assert!(matches!(batch.items[0], Item::Row));
+    let ac = table.layout.index("info_ac").unwrap();
+    match &batch.values[ac] {
+      Typed::Text(range) => assert_eq!(&batch.text[range.clone()], "1,2"),
+      value => panic!("AC=1,2 is not bound as text: {:?}", value),
+    }
+
+    // AF holds a single value.
+    match &batch.items[1] {
+      Item::Rejected { line, reason, .. } => {
+        assert_eq!(*line, 8);
+        assert_eq!(
+          reason,
+          "Invalid FLOAT value for column info_af: \"0.1,0.2\""
+        );
+      }
+      Item::Row => panic!("AF=0.1,0.2 is not a FLOAT"),
+    }
+    assert_eq!(batch.values.len(), table.types.len());
+  }
+
+  /// Insert `records` into an in-memory database with `n_threads` parsers and chunks of
+  /// `batch_size` lines.
+  fn convert<F: FnMut(u64, &str) -> bool>(
+    records: &str,
+    n_threads: usize,
+    batch_size: usize,
+    rejects: &mut Rejects,
+    on_record: F,
+  ) -> (rusqlite::Connection, Result<Summary>) {
+    let header = header(1);
+    let mut db = rusqlite::Connection::open_in_memory().unwrap();
+    let reader = VCFReader::new(Cursor::new(header.clone())).unwrap();
+    let ctable = convertor::format_ctable(&convertor::infer_schema(&reader).unwrap(), "variant");
+    db.execute(&ctable[..], &[] as &[&dyn rusqlite::types::ToSql])
+      .unwrap();
+
+    let pipeline = Pipeline {
+      batch_size,
+      commit_interval: 50,
+      n_threads,
+    };
+    let input = Input {
+      reader: Box::new(Cursor::new(records.as_bytes().to_vec())),
+      header: header.clone(),
+      header_lines: 5,
+    };
+    let result = pipeline.run(&mut db, input, table(&header), rejects, on_record);
+    (db, result)
+  }
+
+  fn positions(db: &rusqlite::Connection) -> Vec<i64> {
+    let mut stmt = db
+      .prepare("SELECT pos FROM variant ORDER BY rowid")
+      .unwrap();
+    let rows = stmt.query_map(&[] as &[&dyn rusqlite::types::ToSql], |row| row.get(0));
+    rows.unwrap().map(|pos| pos.unwrap()).collect()
+  }
+
+  #[test]
+  fn in_order() {
+    let records = (1..=1000).map(record).collect::<String>();
+    let mut rejects = Rejects::new(OnError::Fail, None).unwrap();
+    let (db, result) = convert(&records, 4, 7, &mut rejects, |_, _| true);
+
+    let summary = result.unwrap();
+    assert_eq!(summary.records_read, 1000);
+    assert_eq!(summary.rows_written, 1000);
+    assert_eq!(positions(&db), (1..=1000).collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn rejects_in_chunk() {
+    // Lines 6 to 25, with a blank line 8 and malformed records at lines 12 and 17.
+    let mut records = String::new();
+    for pos in 1..=20 {
+      match pos {
+        3 => records.push('\n'),
+        7 => records.push_str("chr1\tseven\t.\tA\tG\t50\tPASS\tDP=7\n"),
+        12 => records.push_str("chr1\t12\t.\tA\tG\t50\tPASS\tDP=high\n"),
+        _ => records.push_str(&record(pos)),
+      }
+    }
+
+    let mut rejects = Rejects::new(OnError::Fail, None).unwrap();
+    match convert(&records, 3, 4, &mut rejects, |_, _| true).1 {
+      Err(Error::Parse { line, .. }) => assert_eq!(line, 12),
+      _ => panic!("The record at line 12 is malformed"),
+    }
+
+    let path = std::env::temp_dir().join(format!("pipeline-rejects-{}.tsv", std::process::id()));
+    let mut rejects = Rejects::new(OnError::Skip, path.to_str()).unwrap();
+    let (db, result) = convert(&records, 3, 4, &mut rejects, |_, _| true);
+    rejects.flush().unwrap();
+    let rejected = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let summary = result.unwrap();
+    assert_eq!(summary.records_read, 19);
+    assert_eq!(summary.skipped, 2);
+    let lines = rejected
+      .lines()
+      .skip(1)
+      .map(|line| line.split('\t').next().unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(lines, vec!["12", "17"]);
+    assert!(rejected.contains("\tchr1\t12\t.\tA\tG\t50\tPASS\tDP=high\n"));
+
+    let expected = (1..=20).filter(|pos| ![3, 7, 12].contains(pos));
+    assert_eq!(positions(&db), expected.collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn early_cancel() {
+    let records = (1..=10_000).map(record).collect::<String>();
+    let mut rejects = Rejects::new(OnError::Fail, None).unwrap();
+    // The threads are joined before returning, it would not return if they were left blocked.
+    let (db, result) = convert(&records, 4, 10, &mut rejects, |records, _| records < 25);
+
+    let summary = result.unwrap();
+    assert_eq!(summary.records_read, 25);
+    assert_eq!(summary.rows_written, 25);
+    assert_eq!(positions(&db), (1..=25).collect::<Vec<_>>());
+  }
+
+  /// Time of parsing the same chunks with one `Parser`, as a parser thread does, and with a parser
+  /// per chunk, i.e. the header parsed again for each chunk. The header has the contigs of a
+  /// reference genome with its alternate loci. Run it with
+  /// `cargo test --release -p vcf-util parser_timings -- --ignored --nocapture`.
+  #[test]
+  #[ignore]
+  fn parser_timings() {
+    let header = header(3366);
+    let header_lines = 3366 + 4;
+    let table = table(&header);
+    let records = (1..=200_000).map(record).collect::<String>();
+
+    for &batch_size in &[100, 1_000, 10_000] {
+      let start = Instant::now();
+      let mut parser = Parser::new(&header);
+      for chunk in chunks(&records, header_lines, batch_size) {
+        parser.parse(chunk, &table);
+      }
+      let shared = start.elapsed();
+
+      let start = Instant::now();
+      for chunk in chunks(&records, header_lines, batch_size) {
+        Parser::new(&header).parse(chunk, &table);
+      }
+      let per_chunk = start.elapsed();
+
+      println!(
+        "batch_size {}: header parsed once {:?}, per chunk {:?}",
+        batch_size, shared, per_chunk
+      );
+    }
+  }
+}
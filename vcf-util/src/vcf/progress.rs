@@ -0,0 +1,2 @@
+//! `Progress` reports the progress of a conversion on stderr, e.g. of makedb, see `omics_progress`.
+pub use omics_progress::{Progress, LOG_INTERVAL, TICK_INTERVAL};
@@ -0,0 +1,303 @@
+//! `Record` gives the values of the VCF records by column of the `makedb` table without copying
+//! them: a `Layout` resolves the columns once from the INFO keys, a `RecordView` borrows the
+//! values of a record and a `Row` holds them as strings, reusing its buffers from record to record.
+//!
+//! ```no_run
+//! use vcf_util::vcf::convertor;
+//! use vcf_util::vcf::record::{Layout, Row};
+//!
+//! let mut reader = convertor::open_reader("sample.vcf.gz")?;
+//! let layout = Layout::new(&convertor::into_info_keys(&reader)?);
+//! let mut record = reader.empty_record();
+//! let mut row = Row::default();
+//! while reader.next_record(&mut record).unwrap_or(false) {
+//!   layout.view(&record).write_row(&mut row).unwrap();
+//!   println!("{}", row.values().join("\t"));
+//! }
+//! # Ok::<(), vcf_util::vcf::error::Error>(())
+//! ```
+use vcf::VCFRecord;
+
+// Standard Library
+use std::collections::HashMap;
+use std::str::{self, Utf8Error};
+
+// Custom
+use super::convertor;
+
+/// Columns of the fixed fields, before the INFO columns.
+pub const FIXED_COLUMNS: [&str; 7] = ["chrom", "pos", "id", "ref", "alt", "qual", "filter"];
+
+/// A value of a record, borrowed from the record.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field<'a> {
+  /// CHROM and REF.
+  Bytes(&'a [u8]),
+  /// ID, ALT and FILTER.
+  List(&'a [Vec<u8>]),
+  /// The values of an INFO key, empty if the key is missing.
+  Info(&'a [Vec<u8>]),
+  /// POS.
+  Integer(u64),
+  /// QUAL, `None` if missing.
+  Float(Option<f64>),
+}
+
+impl<'a> Field<'a> {
+  /// Append the value as stored in the table: the items of a list are concatenated, the values of
+  /// an INFO key are joined with commas and a missing value is empty. An error if an INFO value is
+  /// not valid UTF-8.
+  pub fn write_to(&self, buf: &mut String) -> Result<(), Utf8Error> {
+    match *self {
+      Field::Bytes(value) => buf.extend(value.iter().map(|&c| c as char)),
+      Field::List(items) => buf.extend(items.iter().flatten().map(|&c| c as char)),
+      Field::Info(items) => {
+        for (idx, item) in items.iter().enumerate() {
+          if idx > 0 {
+            buf.push(',');
+          }
+          buf.push_str(str::from_utf8(item)?);
+        }
+      }
+      Field::Integer(value) => buf.push_str(&value.to_string()),
+      Field::Float(Some(value)) => buf.push_str(&value.to_string()),
+      Field::Float(None) => {}
+    }
+
+    Ok(())
+  }
+}
+
+/// Columns of a table: the fixed columns, then a column per INFO key.
+#[derive(Debug, Clone)]
+pub struct Layout {
+  columns: Vec<String>,
+  info_keys: Vec<Vec<u8>>,
+  /// Column index of the INFO keys.
+  info_columns: HashMap<Vec<u8>, usize>,
+}
+
+impl Layout {
+  /// `info_keys` are INFO keys as in the VCF file, see `convertor::into_info_keys`.
+  pub fn new(info_keys: &[String]) -> Self {
+    let mut columns = FIXED_COLUMNS
+      .iter()
+      .map(|column| String::from(*column))
+      .collect::<Vec<_>>();
+    let mut info_columns = HashMap::new();
+
+    for key in info_keys {
+      info_columns.insert(key.as_bytes().to_vec(), columns.len());
+      columns.push(convertor::info_column(key));
+    }
+
+    Layout {
+      columns,
+      info_keys: info_keys
+        .iter()
+        .map(|key| key.as_bytes().to_vec())
+        .collect(),
+      info_columns,
+    }
+  }
+
+  pub fn columns(&self) -> &[String] {
+    &self.columns
+  }
+
+  /// Index of a column by name, e.g. `info_af`.
+  pub fn index(&self, column: &str) -> Option<usize> {
+    self.columns.iter().position(|name| name == column)
+  }
+
+  pub fn view<'a>(&'a self, record: &'a VCFRecord) -> RecordView<'a> {
+    RecordView {
+      layout: self,
+      record,
+    }
+  }
+}
+
+/// A record seen through a `Layout`.
+#[derive(Debug, Clone, Copy)]
+pub struct RecordView<'a> {
+  layout: &'a Layout,
+  record: &'a VCFRecord,
+}
+
+impl<'a> RecordView<'a> {
+  /// Value of a column, `None` if the index is out of the layout.
+  pub fn get(&self, column: usize) -> Option<Field<'a>> {
+    let record = self.record;
+    let field = match column {
+      0 => Field::Bytes(&record.chromosome),
+      1 => Field::Integer(record.position),
+      2 => Field::List(&record.id),
+      3 => Field::Bytes(&record.reference),
+      4 => Field::List(&record.alternative),
+      5 => Field::Float(record.qual),
+      6 => Field::List(&record.filter),
+      _ => {
+        let key = self.layout.info_keys.get(column - FIXED_COLUMNS.len())?;
+        Field::Info(record.info(key).map_or(&[][..], |values| &values[..]))
+      }
+    };
+
+    Some(field)
+  }
+
+  /// Values of all the columns, in order.
+  pub fn fields(&self) -> impl Iterator<Item = Field<'a>> + '_ {
+    (0..self.layout.columns.len()).filter_map(move |column| self.get(column))
+  }
+
+  /// Write the values of all the columns into `row`, reusing its strings. An error if an INFO
+  /// value is not valid UTF-8.
+  pub fn write_row(&self, row: &mut Row) -> Result<(), Utf8Error> {
+    row
+      .values
+      .resize_with(self.layout.columns.len(), String::new);
+    for value in &mut row.values {
+      value.clear();
+    }
+
+    for (column, value) in row.values.iter_mut().enumerate().take(FIXED_COLUMNS.len()) {
+      if let Some(field) = self.get(column) {
+        field.write_to(value)?;
+      }
+    }
+
+    // In reverse, the first value of a key given several times is kept, as by `VCFRecord::info`.
+    for (key, values) in self.record.info.iter().rev() {
+      if let Some(&column) = self.layout.info_columns.get(key) {
+        let value = &mut row.values[column];
+        value.clear();
+        Field::Info(values).write_to(value)?;
+      }
+    }
+
+    Ok(())
+  }
+}
+
+/// The values of a record as strings, see `RecordView::write_row`.
+#[derive(Debug, Clone, Default)]
+pub struct Row {
+  values: Vec<String>,
+}
+
+impl Row {
+  /// Values in the order of `Layout::columns`.
+  pub fn values(&self) -> &[String] {
+    &self.values
+  }
+
+  pub fn into_values(self) -> Vec<String> {
+    self.values
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+  use vcf::VCFReader;
+
+  /// The records of a file declaring the DP, AF and AC INFO keys.
+  fn records(lines: &str) -> Vec<VCFRecord> {
+    let vcf = format!(
+      "##fileformat=VCFv4.2\n\
+       ##INFO=<ID=DP,Number=1,Type=Integer,Description=\"Depth\">\n\
+       ##INFO=<ID=AF,Number=A,Type=Float,Description=\"Frequency\">\n\
+       ##INFO=<ID=AC,Number=A,Type=Integer,Description=\"Count\">\n\
+       #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n{}",
+      lines
+    );
+    let mut reader = VCFReader::new(Cursor::new(vcf.into_bytes())).unwrap();
+    let mut records = vec![];
+    loop {
+      let mut record = reader.empty_record();
+      if !reader.next_record(&mut record).unwrap() {
+        return records;
+      }
+      records.push(record);
+    }
+  }
+
+  fn layout() -> Layout {
+    Layout::new(&[String::from("DP"), String::from("AF")])
+  }
+
+  #[test]
+  fn columns() {
+    let layout = layout();
+    assert_eq!(
+      layout.columns(),
+      &["chrom", "pos", "id", "ref", "alt", "qual", "filter", "info_dp", "info_af"]
+    );
+    assert_eq!(layout.index("info_af"), Some(8));
+    assert_eq!(layout.index("info_ac"), None);
+  }
+
+  #[test]
+  fn fields() {
+    let records = records("chr1\t12\trs1\tA\tG\t.\tPASS\tDP=10;AC=3\n");
+    let layout = layout();
+    let view = layout.view(&records[0]);
+
+    assert_eq!(view.get(0), Some(Field::Bytes(b"chr1")));
+    assert_eq!(view.get(1), Some(Field::Integer(12)));
+    assert_eq!(view.get(5), Some(Field::Float(None)));
+    assert_eq!(view.get(8), Some(Field::Info(&[])));
+    assert_eq!(view.get(9), None);
+    assert_eq!(view.fields().count(), 9);
+  }
+
+  #[test]
+  fn write_row() {
+    let records = records(
+      "chr1\t12\trs1\tA\tG\t50\tPASS\tDP=10;AF=0.1,0.2;AC=3\n\
+       chr2\t5\trs2\tC\tT\t.\tq10\tAF=0.5\n",
+    );
+    let layout = layout();
+    let mut row = Row::default();
+
+    layout.view(&records[0]).write_row(&mut row).unwrap();
+    assert_eq!(
+      row.values(),
+      &["chr1", "12", "rs1", "A", "G", "50", "PASS", "10", "0.1,0.2"]
+    );
+
+    // The row is reused, the values of the previous record are cleared.
+    layout.view(&records[1]).write_row(&mut row).unwrap();
+    assert_eq!(
+      row.values(),
+      &["chr2", "5", "rs2", "C", "T", "", "q10", "", "0.5"]
+    );
+  }
+
+  #[test]
+  fn write_row_duplicate_key() {
+    let records = records("chr1\t12\t.\tA\tG\t50\tPASS\tDP=10;AF=0.5;DP=20\n");
+    let layout = layout();
+    let mut row = Row::default();
+
+    // The first value is kept, as by `VCFRecord::info`.
+    layout.view(&records[0]).write_row(&mut row).unwrap();
+    assert_eq!(row.values()[7], "10");
+    assert_eq!(
+      layout.view(&records[0]).get(7),
+      Some(Field::Info(&[b"10".to_vec()]))
+    );
+  }
+
+  #[test]
+  fn write_row_invalid_utf8() {
+    let mut record = records("chr1\t12\t.\tA\tG\t50\tPASS\tDP=10\n").remove(0);
+    record.info[0].1 = vec![vec![0xff]];
+    let layout = layout();
+    let mut row = Row::default();
+
+    assert!(layout.view(&record).write_row(&mut row).is_err());
+  }
+}
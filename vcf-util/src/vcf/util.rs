@@ -1,5 +1,15 @@
 use regex::Regex;
 
+// Standard Library
+use std::cell::RefCell;
+use std::io::{self, BufRead, Cursor, Read};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Custom
+use super::error::{Error, Result};
+
 pub fn is_vcf_file(filepath: &str) -> bool {
   // Import at the crate root - preqc-pack.rs
   lazy_static! {
@@ -16,4 +26,92 @@ pub fn is_vcf_gz_file(filepath: &str) -> bool {
   }
 
   RE.is_match(filepath)
-}
\ No newline at end of file
+}
+
+/// Read the meta-information lines and the #CHROM line of a VCF file, with the number of lines
+/// read.
+pub fn read_header<R: BufRead + ?Sized>(reader: &mut R) -> Result<(Vec<u8>, u64)> {
+  let mut header = vec![];
+  let mut n_lines = 0;
+
+  loop {
+    let start = header.len();
+    if reader.read_until(b'\n', &mut header)? == 0 {
+      return Err(Error::Header(String::from("No #CHROM line")));
+    }
+    n_lines += 1;
+
+    if header[start..].starts_with(b"#CHROM") {
+      return Ok((header, n_lines));
+    } else if !header[start..].starts_with(b"#") {
+      return Err(Error::Header(format!(
+        "Line {} is not a header line",
+        n_lines
+      )));
+    }
+  }
+}
+
+/// A reader of the data set by its owner, e.g. the lines of a file read one by one and parsed by
+/// a `VCFReader` reading a `LineFeed`.
+#[derive(Debug, Clone, Default)]
+pub struct LineFeed(Rc<RefCell<Cursor<Vec<u8>>>>);
+
+impl LineFeed {
+  /// Data to read next, in place of the data left.
+  pub fn set(&self, data: Vec<u8>) {
+    *self.0.borrow_mut() = Cursor::new(data);
+  }
+
+  /// Take the data, read or not, e.g. to reuse the buffer.
+  pub fn take(&self) -> Vec<u8> {
+    self.0.replace(Cursor::default()).into_inner()
+  }
+
+  /// Copy of the data, read or not.
+  pub fn data(&self) -> Vec<u8> {
+    self.0.borrow().get_ref().clone()
+  }
+}
+
+impl Read for LineFeed {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    self.0.borrow_mut().read(buf)
+  }
+}
+
+/// Number of bytes read through a `CountingReader`.
+#[derive(Debug, Clone, Default)]
+pub struct ByteCounter(Arc<AtomicU64>);
+
+impl ByteCounter {
+  pub fn bytes(&self) -> u64 {
+    self.0.load(Ordering::Relaxed)
+  }
+}
+
+/// A reader which counts the bytes read, e.g. from a compressed file under a decoder.
+pub struct CountingReader<R> {
+  inner: R,
+  counter: ByteCounter,
+}
+
+impl<R: Read> CountingReader<R> {
+  pub fn new(inner: R) -> (Self, ByteCounter) {
+    let counter = ByteCounter::default();
+    let reader = CountingReader {
+      inner,
+      counter: counter.clone(),
+    };
+
+    (reader, counter)
+  }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let n = self.inner.read(buf)?;
+    self.counter.0.fetch_add(n as u64, Ordering::Relaxed);
+    Ok(n)
+  }
+}
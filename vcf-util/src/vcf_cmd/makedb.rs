@@ -4,11 +4,13 @@ use log::*;
 use structopt::StructOpt;
 
 // Standard
+use std::io;
 use std::path::Path;
 
 // Custom
 extern crate vcf_util;
-use vcf_util::vcf::convertor;
+use vcf_util::vcf::database::{InfoDiscovery, Summary, VcfDatabase};
+use vcf_util::vcf::error::{Error, OnError};
 use vcf_util::vcf::util;
 
 /// Convert VCF file to a SQL Database File
@@ -27,6 +29,42 @@ pub struct Arguments {
     default_value = "vcf.db"
   )]
   output: String,
+
+  /// Add a column for the INFO keys used by the records but not declared in the header: off, all
+  /// (read the file twice) or the number of records to read to find them, as text columns.
+  #[structopt(name = "discover_info", long = "discover-info", default_value = "off")]
+  discover_info: InfoDiscovery,
+
+  /// What to do with a malformed record: stop (fail), skip it (skip) or skip it with a warning
+  /// (warn).
+  #[structopt(name="on_error", long="on-error", possible_values=&["fail", "skip", "warn"], default_value="fail")]
+  on_error: OnError,
+
+  /// File of the skipped records, with their line number and the reason.
+  #[structopt(name = "reject", long = "reject")]
+  reject: Option<String>,
+
+  /// Number of lines parsed at once by a thread.
+  #[structopt(name = "batch_size", long = "batch-size", default_value = "10000")]
+  batch_size: usize,
+
+  /// Number of rows inserted per transaction.
+  #[structopt(
+    name = "commit_interval",
+    long = "commit-interval",
+    default_value = "100000"
+  )]
+  commit_interval: usize,
+
+  /// Number of threads parsing the records, the file is read by one more thread and the rows are
+  /// inserted by the main thread.
+  #[structopt(
+    name = "n_threads",
+    short = "n",
+    long = "n_threads",
+    default_value = "1"
+  )]
+  n_threads: usize,
 }
 
 pub fn run(args: &Arguments) {
@@ -43,9 +81,110 @@ pub fn run(args: &Arguments) {
       std::process::exit(exitcode::DATAERR)
     }
 
-    convertor::makedb(&args.input, &args.output).unwrap();
+    match makedb(args) {
+      Ok(summary) if summary.skipped > 0 => warn!(
+        "{} - {} of {} records skipped{}",
+        module_path!(),
+        summary.skipped,
+        summary.records_read,
+        args
+          .reject
+          .as_ref()
+          .map_or(String::new(), |reject| format!(", see {}", reject))
+      ),
+      Ok(_) => {}
+      Err(err) => {
+        error!("{} - {}", module_path!(), err);
+        std::process::exit(exit_code(&err))
+      }
+    }
   } else {
     error!("{} - Not Found: {:?}", module_path!(), args.input);
     std::process::exit(exitcode::NOINPUT)
   }
 }
+
+fn makedb(args: &Arguments) -> Result<Summary, Error> {
+  let mut builder = VcfDatabase::builder()
+    .input(&args.input)
+    .output(&args.output)
+    .discover_info(args.discover_info)
+    .batch_size(args.batch_size)
+    .commit_interval(args.commit_interval)
+    .n_threads(args.n_threads)
+    .show_progress(true)
+    .on_error(args.on_error);
+  if let Some(reject) = &args.reject {
+    builder = builder.reject_file(reject);
+  }
+
+  builder.build()?.run()
+}
+
+fn exit_code(err: &Error) -> exitcode::ExitCode {
+  match err {
+    Error::InvalidArgument(_) => exitcode::USAGE,
+    Error::Io(err) if err.kind() == io::ErrorKind::NotFound => exitcode::NOINPUT,
+    Error::Io(_) => exitcode::IOERR,
+    Error::Header(_) | Error::Parse { .. } | Error::Schema(_) => exitcode::DATAERR,
+    Error::Database(_) => exitcode::CANTCREAT,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn exit_codes() {
+    let not_found = io::Error::new(io::ErrorKind::NotFound, "sample.vcf.gz");
+    let denied = io::Error::new(io::ErrorKind::PermissionDenied, "sample.db");
+    let errors = vec![
+      (
+        Error::InvalidArgument(String::from("n_threads")),
+        exitcode::USAGE,
+      ),
+      (Error::Io(not_found), exitcode::NOINPUT),
+      (Error::Io(denied), exitcode::IOERR),
+      (
+        Error::Header(String::from("no #CHROM line")),
+        exitcode::DATAERR,
+      ),
+      (
+        Error::Parse {
+          line: 12,
+          msg: String::from("Invalid INFO value"),
+        },
+        exitcode::DATAERR,
+      ),
+      (Error::Schema(String::from("info_dp")), exitcode::DATAERR),
+      (
+        Error::Database(rusqlite::Error::QueryReturnedNoRows),
+        exitcode::CANTCREAT,
+      ),
+    ];
+
+    for (err, code) in errors {
+      assert_eq!(exit_code(&err), code, "{}", err);
+    }
+  }
+
+  #[test]
+  fn arguments() {
+    let args = Arguments::from_iter(vec![
+      "makedb",
+      "sample.vcf.gz",
+      "--discover-info",
+      "1000",
+      "--on-error",
+      "skip",
+    ]);
+    assert_eq!(args.output, "vcf.db");
+    assert_eq!(args.discover_info, InfoDiscovery::Sample(1000));
+    assert_eq!(args.on_error, OnError::Skip);
+
+    assert!(
+      Arguments::from_iter_safe(vec!["makedb", "sample.vcf.gz", "--discover-info", "0"]).is_err()
+    );
+  }
+}